@@ -1,29 +1,32 @@
 use aicl_oidc::{
+    axum::handlers,
     errors::JsonErrorHandler,
-    oidc::keycloak::{KeyCloakToken, TOKEN_KEY},
-    vault::ApiToken,
-    AiclIdentifier, AiclIdentity, AppErrorHandler, OptionalIdentity,
+    oidc::{
+        keycloak::{KeyCloakToken, TOKEN_KEY},
+        session::SessionConfig,
+    },
+    AiclIdentifier, AiclIdentity, AiclRouterExt, AppErrorHandler, OptionalIdentity,
+};
+use axum::{
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
 };
-use axum::{response::IntoResponse, routing::get, Json, Router};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
-use reqwest::StatusCode;
 use serde_json::{json, Value};
-use sqlx::postgres::PgPoolOptions;
+use sqlx::{postgres::PgPoolOptions, PgPool};
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
-use tower_sessions::{
-    cookie::{time::Duration, SameSite},
-    Expiry, MemoryStore, Session, SessionManagerLayer,
-};
+use tower_sessions::Session;
 
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-pub async fn run(identifier: AiclIdentifier, error_handler: AppErrorHandler) {
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false)
-        .with_same_site(SameSite::Lax)
-        .with_expiry(Expiry::OnInactivity(Duration::hours(1)));
+pub async fn run(identifier: AiclIdentifier, error_handler: AppErrorHandler, pool: PgPool) {
+    // Plain HTTP on localhost, so the cookie can't be `Secure`
+    let session_layer = SessionConfig::insecure()
+        .postgres_layer(pool)
+        .await
+        .expect("Failed to set up the session store");
 
     let app = Router::new()
         .merge(
@@ -31,20 +34,18 @@ pub async fn run(identifier: AiclIdentifier, error_handler: AppErrorHandler) {
                 .route("/foo", get(authenticated))
                 .route("/debug/token", get(debug_token_handler))
                 .route_service("/logout", identifier.logout_service())
-                .route("/token", get(create_token))
+                .route("/token", post(handlers::create_token))
                 .layer(identifier.login_layer())
                 .route("/bar", get(maybe_authenticated))
-                .layer(identifier.authenticate_layer())
-                .layer(session_layer)
-                .layer(identifier.identifier_layer())
-                .layer(error_handler.layer())
+                .merge(identifier.auth_router())
+                .with_aicl_auth(&identifier, &error_handler, session_layer)
                 .layer(TraceLayer::new_for_http()),
         )
         .nest(
             "/api",
             Router::new()
                 .route("/protected", get(token_authenticated))
-                .layer(identifier.api_token_layer()),
+                .with_aicl_api_token_auth(&identifier, &error_handler),
         );
 
     let listener = TcpListener::bind("0.0.0.0:4040").await.unwrap();
@@ -65,46 +66,11 @@ async fn maybe_authenticated(OptionalIdentity(maybe_user): OptionalIdentity) ->
             format!("Hello {}! You are already logged in.", identity.username)
         }
         None => {
-            format!("Hello anon!")
+            "Hello anon!".to_string()
         }
     }
 }
 
-// Endpoint for creating API tokens
-async fn create_token(
-    identity: AiclIdentity,
-    session: Session,
-    identifier: AiclIdentifier,
-) -> Result<Json<ApiToken>, (StatusCode, String)> {
-    // Get the vault service from the identifier
-
-    // Get the OIDC token from the session
-    let oidc_token = session
-        .get::<KeyCloakToken>(TOKEN_KEY)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Session error: {}", e),
-            )
-        })?
-        .ok_or((StatusCode::UNAUTHORIZED, "No OIDC token found".to_string()))?;
-    tracing::info!("Creating API token for user {}", identity.username);
-    // Create a token
-    let token = identifier
-        .vault
-        .create_api_token_with_oidc(&identity, &oidc_token)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to create token: {}", e),
-            )
-        })?;
-    tracing::info!("Created API token for user {}", identity.username);
-    Ok(Json(token))
-}
-
 // Endpoint that requires token authentication
 async fn token_authenticated(identity: AiclIdentity) -> impl IntoResponse {
     format!("API access granted for {}!", identity.username)
@@ -152,7 +118,7 @@ pub async fn debug_token_handler(identity: AiclIdentity, session: Session) -> im
                     "payload": payload,
                     "raw": {
                         "id_token": id_token_str,
-                        "access_token": token.access_token.secret()
+                        "access_token": token.access_token.as_ref().map(|t| t.secret())
                     }
                 }
             }))
@@ -212,9 +178,9 @@ async fn main() {
 
     // Log application startup
     tracing::info!("Starting OIDC application");
-    let identifier = AiclIdentifier::from_env(pool)
+    let identifier = AiclIdentifier::from_env(pool.clone())
         .await
         .expect("Failed to initialize AiclIdentifier");
     let error_handler = AppErrorHandler::new(JsonErrorHandler::default());
-    run(identifier, error_handler).await;
+    run(identifier, error_handler, pool).await;
 }
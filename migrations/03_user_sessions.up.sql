@@ -0,0 +1,13 @@
+-- Registry of authenticated sessions, so users and admins can see and revoke them
+CREATE TABLE user_sessions (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL,                   -- Keycloak user id, not a reference so sessions survive a resync
+    mechanism VARCHAR(50) NOT NULL CHECK (mechanism IN ('oidc', 'api_token')),
+    ip_address TEXT,                         -- As seen when the session was last used
+    user_agent TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_user_sessions_user_id ON user_sessions(user_id) WHERE revoked_at IS NULL;
@@ -0,0 +1,13 @@
+-- Registry of the API tokens issued through Vault. Only the accessor is stored, never the token.
+CREATE TABLE api_tokens (
+    accessor TEXT PRIMARY KEY,               -- Vault token accessor, can revoke but not use the token
+    user_id UUID NOT NULL,                   -- Keycloak user id, not a reference so tokens survive a resync
+    name VARCHAR(255) NOT NULL,
+    policies TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_api_tokens_user_id ON api_tokens(user_id) WHERE revoked_at IS NULL;
@@ -0,0 +1,5 @@
+-- Rollback: API token scopes
+ALTER TABLE api_tokens
+    DROP COLUMN IF EXISTS bound_cidrs,
+    DROP COLUMN IF EXISTS num_uses,
+    DROP COLUMN IF EXISTS profile;
@@ -0,0 +1,5 @@
+-- Restrictions of scoped API tokens, so users can tell them apart
+ALTER TABLE api_tokens
+    ADD COLUMN profile VARCHAR(255),        -- Token profile the scope came from, if any
+    ADD COLUMN num_uses BIGINT,             -- Use limit at creation, NULL if unlimited
+    ADD COLUMN bound_cidrs TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,5 @@
+-- Rollback: encrypted columns
+COMMENT ON COLUMN system_configs.value IS NULL;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS attributes_ciphertext;
@@ -0,0 +1,7 @@
+-- Transit ciphertext of user attributes, which may hold PII. When set, the plaintext
+-- attributes columns of the user are left empty.
+ALTER TABLE users
+    ADD COLUMN attributes_ciphertext TEXT;
+
+-- Sensitive system config values are stored as transit ciphertext in `value`
+COMMENT ON COLUMN system_configs.value IS 'Transit ciphertext when is_sensitive is set';
@@ -0,0 +1,5 @@
+-- Rollback: database credential leases
+DROP INDEX IF EXISTS idx_database_leases_team;
+DROP INDEX IF EXISTS idx_database_leases_session_id;
+DROP INDEX IF EXISTS idx_database_leases_user_id;
+DROP TABLE IF EXISTS database_leases;
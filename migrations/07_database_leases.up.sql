@@ -0,0 +1,16 @@
+-- Leases of the dynamic Postgres credentials Vault issued to team members. The password is never stored.
+CREATE TABLE database_leases (
+    lease_id TEXT PRIMARY KEY,               -- Vault lease id, revokes the credentials
+    user_id UUID NOT NULL,                   -- Keycloak user id, not a reference so leases survive a resync
+    session_id UUID,                         -- Session the credentials were issued to, revoked on its logout
+    team VARCHAR(255) NOT NULL,              -- Team name, as in the Vault role
+    role VARCHAR(255) NOT NULL,              -- Vault database role, team-{name}-member or team-{name}-captain
+    username TEXT NOT NULL,                  -- Generated Postgres user
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_database_leases_user_id ON database_leases(user_id) WHERE revoked_at IS NULL;
+CREATE INDEX idx_database_leases_session_id ON database_leases(session_id) WHERE revoked_at IS NULL;
+CREATE INDEX idx_database_leases_team ON database_leases(team) WHERE revoked_at IS NULL;
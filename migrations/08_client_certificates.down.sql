@@ -0,0 +1,3 @@
+-- Rollback: client certificates
+DROP INDEX IF EXISTS idx_client_certificates_team;
+DROP TABLE IF EXISTS client_certificates;
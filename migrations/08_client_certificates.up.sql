@@ -0,0 +1,13 @@
+-- Client certificates issued to team agents from the Vault PKI. Private keys are never stored.
+CREATE TABLE client_certificates (
+    serial_number TEXT PRIMARY KEY,          -- Vault formatted serial, e.g. 3c:0f:9a
+    user_id UUID NOT NULL,                   -- Keycloak user id the certificate authenticates as
+    team VARCHAR(255) NOT NULL,              -- Team name at issuance, checked again on every request
+    issued_by UUID NOT NULL,                 -- Captain who issued it
+    name VARCHAR(255),                       -- Label of the agent using it
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_client_certificates_team ON client_certificates(team) WHERE revoked_at IS NULL;
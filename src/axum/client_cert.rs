@@ -0,0 +1,189 @@
+use axum::{
+    http::{HeaderName, Request},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use std::task::{Context, Poll};
+
+use crate::{errors::AppError, vault::pki::pem_to_der, AiclIdentifier};
+
+use super::error::{required_extension, AppErrorHandler};
+
+/// A client certificate verified by the TLS connection, in DER
+///
+/// Servers terminating TLS themselves, e.g. with rustls, insert it into the request
+/// extensions from the connection's peer certificates.
+#[derive(Debug, Clone)]
+pub struct PeerCertificate(pub Vec<u8>);
+
+/// Authenticates requests with client certificates issued from the Vault PKI
+///
+/// The certificate comes from a [`PeerCertificate`] extension, or from a header set by the
+/// TLS terminator when one is configured. Requests without a certificate pass through.
+/// Like [`super::middleware::ApiTokenAuthLayer`], it goes inside the identifier layer.
+#[derive(Clone, Default)]
+pub struct ClientCertAuthLayer {
+    header: Option<HeaderName>,
+}
+
+impl ClientCertAuthLayer {
+    /// Also read the certificate from a header as PEM, URL-encoded or not, e.g. nginx's
+    /// `$ssl_client_escaped_cert`
+    ///
+    /// Only use this behind a proxy that verifies client certificates and removes the
+    /// header from incoming requests, anyone can set it otherwise.
+    pub fn with_header(mut self, header: HeaderName) -> Self {
+        self.header = Some(header);
+        self
+    }
+}
+
+impl<S> tower::Layer<S> for ClientCertAuthLayer {
+    type Service = ClientCertAuthMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientCertAuthMiddleware {
+            inner,
+            header: self.header.clone(),
+        }
+    }
+}
+
+// The middleware service that authenticates client certificates
+#[derive(Clone)]
+pub struct ClientCertAuthMiddleware<S> {
+    inner: S,
+    header: Option<HeaderName>,
+}
+
+/// The request's client certificate in DER, `Err` if the header doesn't hold one
+fn extract_certificate<B>(
+    req: &Request<B>,
+    header: Option<&HeaderName>,
+) -> Result<Option<Vec<u8>>, AppError> {
+    if let Some(PeerCertificate(der)) = req.extensions().get::<PeerCertificate>() {
+        return Ok(Some(der.clone()));
+    }
+
+    let Some(value) = header.and_then(|header| req.headers().get(header)) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| AppError::bad_request("Malformed client certificate header"))?;
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let pem = percent_decode(value)
+        .ok_or_else(|| AppError::bad_request("Malformed client certificate header"))?;
+    pem_to_der(&pem)
+        .map(Some)
+        .ok_or_else(|| AppError::bad_request("Malformed client certificate header"))
+}
+
+// Proxies escape the PEM's newlines, `+` is kept as is since base64 uses it
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl<S, B> tower::Service<Request<B>> for ClientCertAuthMiddleware<S>
+where
+    S: tower::Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let certificate = match extract_certificate(&req, self.header.as_ref()) {
+            Ok(Some(der)) => Ok(der),
+            // No certificate, the route handler decides whether authentication is required
+            Ok(None) => return Box::pin(inner.call(req)),
+            Err(e) => Err(e),
+        };
+
+        let extensions = req.extensions();
+        let (error_handler, identifier) = match (
+            required_extension::<AppErrorHandler>(extensions),
+            required_extension::<AiclIdentifier>(extensions),
+        ) {
+            (Ok(error_handler), Ok(identifier)) => (error_handler, identifier),
+            (Err(response), _) | (_, Err(response)) => return Box::pin(async { Ok(response) }),
+        };
+
+        Box::pin(async move {
+            let der = match certificate {
+                Ok(der) => der,
+                Err(e) => return Ok(error_handler.handle_error(e)),
+            };
+
+            match identifier.certificate_identity(&der).await {
+                Ok(identity) => {
+                    let (mut parts, body) = req.into_parts();
+                    parts.extensions.insert(identity);
+                    inner.call(Request::from_parts(parts, body)).await
+                }
+                Err(e) => Ok(error_handler.handle_error(e)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_header_is_decoded() {
+        let der = [0x30, 0x05, 0x30, 0x03, 0x02, 0x01, 0x2a];
+        let escaped =
+            "-----BEGIN%20CERTIFICATE-----%0AMAUwAwIBKg==%0A-----END%20CERTIFICATE-----%0A";
+        let header = HeaderName::from_static("x-client-cert");
+        let request = |value: &str| {
+            Request::builder()
+                .header("x-client-cert", value)
+                .body(())
+                .unwrap()
+        };
+
+        let certificate = extract_certificate(&request(escaped), Some(&header)).unwrap();
+        assert_eq!(certificate.unwrap(), der);
+
+        // The header is only read when configured
+        assert!(extract_certificate(&request(escaped), None)
+            .unwrap()
+            .is_none());
+        assert!(extract_certificate(&request("%zz"), Some(&header)).is_err());
+
+        // The connection's certificate wins over the header
+        let mut with_peer = request("garbage");
+        with_peer
+            .extensions_mut()
+            .insert(PeerCertificate(der.to_vec()));
+        assert_eq!(
+            extract_certificate(&with_peer, Some(&header))
+                .unwrap()
+                .unwrap(),
+            der
+        );
+    }
+}
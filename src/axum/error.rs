@@ -8,23 +8,48 @@ use axum::{
 use reqwest::StatusCode;
 use tower::{Layer, Service};
 
-use crate::errors::{self, AppError};
+use crate::errors::{self, AppError, ErrorContext};
 
 /// A wrapper around an error handler that can be used in request extensions
+///
+/// When taken from request extensions it carries the [`ErrorContext`] of that request.
 #[derive(Clone)]
 pub struct AppErrorHandler {
     handler: Arc<dyn errors::ErrorHandler>,
+    context: ErrorContext,
 }
 
 impl AppErrorHandler {
     pub fn new<H: errors::ErrorHandler>(handler: H) -> Self {
         Self {
             handler: Arc::new(handler),
+            context: ErrorContext::default(),
         }
     }
 
     pub fn handle_error<E: Into<AppError>>(&self, error: E) -> Response {
-        self.handler.handle_error(error.into())
+        self.handler
+            .handle_error_with_context(error.into(), &self.context)
+    }
+
+    /// A copy of this handler bound to the given request context
+    pub fn with_context(&self, context: ErrorContext) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            context,
+        }
+    }
+
+    /// A copy of this handler for a route that takes bearer tokens, which challenges
+    /// clients on every `401`
+    pub(crate) fn for_bearer_route(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            context: ErrorContext {
+                bearer_auth: true,
+                ..self.context.clone()
+            },
+        }
     }
 
     pub fn layer(&self) -> ErrorHandlerExtensionLayer {
@@ -74,7 +99,10 @@ where
 
     fn call(&mut self, mut req: Request<B>) -> Self::Future {
         // Add error handler to request extensions
-        req.extensions_mut().insert(self.handler.clone());
+        let handler = self
+            .handler
+            .with_context(ErrorContext::from_headers(req.headers()));
+        req.extensions_mut().insert(handler);
         self.inner.call(req)
     }
 }
@@ -90,6 +118,33 @@ pub fn handle_error(extensions: &Extensions, error: AppError) -> Response {
     }
 }
 
+/// Clone an extension a middleware depends on
+///
+/// A missing extension means the layers were installed in the wrong order, so instead of
+/// panicking this logs it and produces a `500` through the error handler, if there is one.
+// Middlewares return the `Response` as is, boxing it would only move the allocation
+#[allow(clippy::result_large_err)]
+pub(crate) fn required_extension<T>(extensions: &Extensions) -> Result<T, Response>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    extensions.get::<T>().cloned().ok_or_else(|| {
+        let name = std::any::type_name::<T>()
+            .rsplit("::")
+            .next()
+            .unwrap_or_default();
+        tracing::error!(
+            "{} not found in request extensions, the layers are installed in the wrong order. \
+             Use `AiclRouterExt::with_aicl_auth` to install them",
+            name
+        );
+        handle_error(
+            extensions,
+            AppError::internal_error(format!("{} not found in request extensions", name)),
+        )
+    })
+}
+
 impl<S> FromRequestParts<S> for AppErrorHandler
 where
     S: Send + Sync,
@@ -102,7 +157,7 @@ where
             .get::<AppErrorHandler>()
             .ok_or((
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "AiclIdentity not found".to_string(),
+                "AppErrorHandler not found".to_string(),
             ))
             .cloned()
     }
@@ -1,7 +1,17 @@
-use axum::{extract::FromRequestParts, http::request::Parts};
+use std::{ops::Deref, sync::Arc};
+
+use axum::{extract::FromRequestParts, http::request::Parts, response::Response};
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
 use reqwest::StatusCode;
+use tower_sessions::Session;
+use vaultrs::client::VaultClient;
 
-use crate::{AiclIdentifier, AiclIdentity};
+use crate::{
+    axum::error::{handle_error, required_extension},
+    errors::AppError,
+    oidc::keycloak::{KeyCloakToken, TOKEN_KEY},
+    AiclIdentifier, AiclIdentity,
+};
 
 impl<S> FromRequestParts<S> for AiclIdentity
 where
@@ -55,3 +65,79 @@ where
             .cloned()
     }
 }
+
+/// A Vault client authenticated as the current user
+///
+/// Bearer requests use the presented API token once it passed verification and the network
+/// check of the API token middleware. Session requests log in with the user's ID token under
+/// their role's Vault role. Logins are reused until shortly before their lease ends, so a
+/// handler can extract this freely.
+#[derive(Clone)]
+pub struct UserVaultClient(pub Arc<VaultClient>);
+
+impl Deref for UserVaultClient {
+    type Target = VaultClient;
+
+    fn deref(&self) -> &VaultClient {
+        &self.0
+    }
+}
+
+impl<S> FromRequestParts<S> for UserVaultClient
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let identifier = required_extension::<AiclIdentifier>(&parts.extensions)?;
+
+        if let Some(bearer) = parts.headers.typed_get::<Authorization<Bearer>>() {
+            let token = bearer.token();
+            let verified = identifier
+                .vault
+                .verify_token(token)
+                .await
+                .map_err(|e| handle_error(&parts.extensions, e.into()))?;
+            // Same check as the API token middleware, the token may be bound to networks
+            if !verified.allows_ip(identifier.trusted_proxies.client_ip(parts)) {
+                return Err(handle_error(
+                    &parts.extensions,
+                    AppError::forbidden("API token is not allowed from this network"),
+                ));
+            }
+            return identifier
+                .vault
+                .token_vault_client(token, &verified)
+                .await
+                .map(UserVaultClient)
+                .map_err(|e| handle_error(&parts.extensions, e.into()));
+        }
+
+        let identity = parts
+            .extensions
+            .get::<AiclIdentity>()
+            .cloned()
+            .ok_or_else(|| {
+                handle_error(&parts.extensions, AppError::unauthorized("Not logged in"))
+            })?;
+        let session = required_extension::<Session>(&parts.extensions)?;
+        let oidc_token = session
+            .get::<KeyCloakToken>(TOKEN_KEY)
+            .await
+            .map_err(|e| handle_error(&parts.extensions, AppError::session_error(e)))?
+            .ok_or_else(|| {
+                handle_error(
+                    &parts.extensions,
+                    AppError::unauthorized("No OIDC token found"),
+                )
+            })?;
+
+        identifier
+            .vault
+            .user_vault_client(&identity, &oidc_token)
+            .await
+            .map(UserVaultClient)
+            .map_err(|e| handle_error(&parts.extensions, e.into()))
+    }
+}
@@ -1,59 +1,727 @@
+use std::{sync::Arc, time::Duration};
 
-/// Handler for the login route
-pub async fn login_handler(
-    session: Session,
-    provider: Arc<KeycloakOidcProvider>,
-) -> impl IntoResponse {
-    // Start the authentication flow
-    match provider.start_auth(&session, None).await {
-        Ok(auth_uri) => Redirect::to(auth_uri.to_string().as_str()).into_response(),
-        Err(e) => {
-            tracing::error!("Failed to start authentication: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        api_tokens::ApiTokenRecord,
+        client_certificates::ClientCertificateRecord,
+        database_leases::DatabaseLease,
+        sessions::{UserSession, SESSION_REGISTRY_KEY},
+    },
+    errors::AppError,
+    oidc::keycloak::{KeyCloakToken, TOKEN_KEY},
+    vault::{
+        database::DatabaseCredentials,
+        pki::IssuedCertificate,
+        wrapping::{wrap_ttl, UnwrappedValue},
+        ApiToken, TokenRenewal, TokenScope,
+    },
+    AiclIdentifier, AiclIdentity, AppErrorHandler, OptionalIdentity,
+};
+
+/// Session key holding the page to return to once the login callback completes
+pub const LOGIN_REDIRECT_KEY: &str = "aicl-oidc-login-redirect";
+
+/// Configuration for the router returned by [`AiclIdentifier::auth_router`]
+#[derive(Debug, Clone)]
+pub struct AuthRouterConfig {
+    /// Path the routes are mounted under, e.g. `/auth` gives `/auth/login`
+    pub base_path: String,
+    /// Where to send the user after login when no `return_to` was given
+    pub default_return_to: String,
+    /// Mount `GET /me`
+    pub enable_me: bool,
+    /// Mount `GET|POST|DELETE /tokens`, `POST /tokens/renew` and `DELETE /tokens/{accessor}`
+    pub enable_tokens: bool,
+    /// Mount `GET|DELETE /sessions` and `DELETE /sessions/{id}`
+    pub enable_sessions: bool,
+    /// Mount `GET|POST /database/credentials` and `DELETE /database/credentials/{lease_id}`,
+    /// off by default as it needs Vault's database secrets engine
+    pub enable_database_credentials: bool,
+    /// Mount `GET|POST /certificates` and `DELETE /certificates/{serial}`, off by default
+    /// as it needs Vault's PKI secrets engine
+    pub enable_client_certificates: bool,
+    /// Mount `POST /unwrap`, off by default
+    pub enable_unwrap: bool,
+}
+
+impl Default for AuthRouterConfig {
+    fn default() -> Self {
+        Self {
+            base_path: "/auth".to_string(),
+            default_return_to: "/".to_string(),
+            enable_me: true,
+            enable_tokens: true,
+            enable_sessions: true,
+            enable_database_credentials: false,
+            enable_client_certificates: false,
+            enable_unwrap: false,
         }
     }
 }
 
-/// Handler for the login callback
-pub async fn login_callback_handler(
-    session: Session,
-    provider: Arc<KeycloakOidcProvider>,
+impl AuthRouterConfig {
+    fn path(&self, route: &str) -> String {
+        format!("{}{}", self.base_path.trim_end_matches('/'), route)
+    }
+}
+
+/// Build the auth router.
+///
+/// The routes expect the layers installed by [`crate::AiclRouterExt::with_aicl_auth`]
+/// around them.
+pub(crate) fn auth_router<S>(config: AuthRouterConfig) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let mut router = Router::new()
+        .route(&config.path("/login"), get(login))
+        .route(&config.path("/callback"), get(callback))
+        // POST only, so a link or image on another site can't log the user out
+        .route(&config.path("/logout"), post(logout));
+
+    if config.enable_me {
+        router = router.route(&config.path("/me"), get(me));
+    }
+    if config.enable_tokens {
+        router = router
+            .route(
+                &config.path("/tokens"),
+                get(list_tokens)
+                    .post(create_token)
+                    .delete(revoke_all_tokens),
+            )
+            .route(&config.path("/tokens/renew"), post(renew_token))
+            .route(&config.path("/tokens/{accessor}"), delete(revoke_token));
+    }
+    if config.enable_sessions {
+        router = router
+            .route(
+                &config.path("/sessions"),
+                get(list_sessions).delete(revoke_all_sessions),
+            )
+            .route(&config.path("/sessions/{id}"), delete(revoke_session));
+    }
+    if config.enable_database_credentials {
+        router = router
+            .route(
+                &config.path("/database/credentials"),
+                get(list_database_credentials).post(issue_database_credentials),
+            )
+            .route(
+                &config.path("/database/credentials/{*lease_id}"),
+                delete(revoke_database_credentials),
+            );
+    }
+
+    if config.enable_client_certificates {
+        router = router
+            .route(
+                &config.path("/certificates"),
+                get(list_client_certificates).post(issue_client_certificate),
+            )
+            .route(
+                &config.path("/certificates/{serial}"),
+                delete(revoke_client_certificate),
+            );
+    }
+
+    if config.enable_unwrap {
+        router = router.route(&config.path("/unwrap"), post(unwrap));
+    }
+
+    router.with_state(Arc::new(config))
+}
+
+/// Only accept local absolute paths as redirect targets, so login isn't an open redirect
+fn sanitize_return_to(return_to: Option<&str>) -> Uri {
+    return_to
+        .filter(|path| path.starts_with('/') && !path.starts_with("//") && !path.contains('\\'))
+        .and_then(|path| path.parse::<Uri>().ok())
+        .filter(|uri| uri.scheme().is_none() && uri.authority().is_none())
+        .unwrap_or_else(|| Uri::from_static("/"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    return_to: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
     code: String,
     state: String,
-) -> impl IntoResponse {
-    // Exchange the code for tokens
-    match provider.handle_callback(&code, &state, &session, None).await {
-        Ok(_) => {
-            // Check if there's a redirect URL in the session
-            match session.get::<String>("login_redirect").await {
-                Ok(Some(redirect)) => {
-                    // Remove the redirect from the session
-                    let _ = session.remove::<String>("login_redirect").await;
-                    Redirect::to(&redirect).into_response()
-                }
-                _ => {
-                    // Default redirect to home
-                    Redirect::to("/").into_response()
-                }
-            }
-        }
+}
+
+/// Handler for the login route, redirects to the identity provider
+async fn login(
+    State(config): State<Arc<AuthRouterConfig>>,
+    Query(params): Query<LoginParams>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Response {
+    let return_to = sanitize_return_to(
+        params
+            .return_to
+            .as_deref()
+            .or(Some(config.default_return_to.as_str())),
+    );
+    if let Err(e) = session
+        .insert(LOGIN_REDIRECT_KEY, return_to.to_string())
+        .await
+    {
+        return error_handler.handle_error(AppError::session_error(e));
+    }
+
+    let callback: Uri = match config.path("/callback").parse() {
+        Ok(callback) => callback,
+        Err(e) => return error_handler.handle_error(AppError::internal_error(e)),
+    };
+    match identifier.oidc.start_auth(&session, &callback).await {
+        Ok(auth_uri) => Redirect::to(&auth_uri.to_string()).into_response(),
         Err(e) => {
-            tracing::error!("Authentication callback failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            tracing::error!("Failed to start authentication: {}", e);
+            error_handler.handle_error(e)
         }
     }
 }
 
+/// Handler for the login callback, finishes the code exchange
+async fn callback(
+    State(config): State<Arc<AuthRouterConfig>>,
+    Query(params): Query<CallbackParams>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Response {
+    let callback: Uri = match config.path("/callback").parse() {
+        Ok(callback) => callback,
+        Err(e) => return error_handler.handle_error(AppError::internal_error(e)),
+    };
+    if let Err(e) = identifier
+        .oidc
+        .handle_callback(&params.code, &params.state, &session, &callback)
+        .await
+    {
+        tracing::error!("Authentication callback failed: {}", e);
+        return error_handler.handle_error(e);
+    }
+
+    let return_to = session
+        .remove::<String>(LOGIN_REDIRECT_KEY)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| config.default_return_to.clone());
+    Redirect::to(&return_to).into_response()
+}
+
 /// Handler for the logout route
-pub async fn logout_handler(
+async fn logout(
     session: Session,
-    provider: Arc<KeycloakOidcProvider>,
-) -> impl IntoResponse {
-    match provider.logout(&session).await {
-        Ok(logout_uri) => Redirect::to(logout_uri.to_string().as_str()).into_response(),
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Response {
+    match identifier.logout(&session).await {
+        Ok(logout_uri) => Redirect::to(&logout_uri.to_string()).into_response(),
         Err(e) => {
             tracing::error!("Logout failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            error_handler.handle_error(e)
         }
     }
 }
+
+/// Returns the identity of the logged in user
+async fn me(
+    OptionalIdentity(identity): OptionalIdentity,
+    error_handler: AppErrorHandler,
+) -> Result<Json<AiclIdentity>, Response> {
+    identity
+        .map(Json)
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateTokenParams {
+    name: Option<String>,
+    /// Token profile to restrict the token to
+    profile: Option<String>,
+    policies: Option<Vec<String>>,
+    /// Maximum lifetime in seconds
+    ttl: Option<u64>,
+    num_uses: Option<u64>,
+    /// Client networks, e.g. `["10.0.0.0/8"]`
+    bound_cidrs: Option<Vec<String>>,
+    /// Return a single-use wrapping token valid for this many seconds instead of the token
+    wrap_ttl: Option<u64>,
+}
+
+impl CreateTokenParams {
+    fn scope(&self) -> TokenScope {
+        TokenScope {
+            policies: self.policies.clone(),
+            ttl: self.ttl,
+            num_uses: self.num_uses,
+            bound_cidrs: self.bound_cidrs.clone(),
+        }
+    }
+}
+
+/// A newly created API token. The token is only ever shown in this response.
+#[derive(Debug, Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    pub name: String,
+    pub profile: Option<String>,
+}
+
+/// Creates a Vault API token for the logged in user from the OIDC token in the session
+///
+/// The JSON body can narrow the token down, e.g.
+/// `{"profile": "ci-readonly", "ttl": 3600, "num_uses": 50}`, send `{}` for a default token.
+/// Asking for more than the user's role allows is refused with a `403`. With
+/// `"wrap_ttl": 300` the token is returned wrapped, for handing it to someone else.
+pub async fn create_token(
+    OptionalIdentity(identity): OptionalIdentity,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+    Json(params): Json<CreateTokenParams>,
+) -> Result<Response, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    let oidc_token = session
+        .get::<KeyCloakToken>(TOKEN_KEY)
+        .await
+        .map_err(|e| error_handler.handle_error(AppError::session_error(e)))?
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("No OIDC token found")))?;
+
+    // Checked before the token exists, so a bad TTL doesn't leave one behind
+    let wrap_ttl = params
+        .wrap_ttl
+        .map(|seconds| wrap_ttl(Some(Duration::from_secs(seconds))))
+        .transpose()
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    tracing::info!("Creating API token for user {}", identity.username);
+    let (token, record) = identifier
+        .create_api_token(
+            &identity,
+            &oidc_token,
+            params.name.as_deref(),
+            params.profile.as_deref(),
+            params.scope(),
+        )
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    let created = CreatedToken {
+        token,
+        name: record.name,
+        profile: record.profile,
+    };
+    let Some(wrap_ttl) = wrap_ttl else {
+        return Ok(Json(created).into_response());
+    };
+    match identifier.vault.wrap(&created, Some(wrap_ttl)).await {
+        Ok(wrapped) => Ok(Json(wrapped).into_response()),
+        Err(e) => {
+            // The token was never handed out, don't leave it behind
+            if let Err(revoke_error) = identifier
+                .revoke_api_token(identity.id, &created.token.accessor)
+                .await
+            {
+                tracing::error!("Failed to revoke unwrapped API token: {}", revoke_error);
+            }
+            Err(error_handler.handle_error(e))
+        }
+    }
+}
+
+/// Lists the logged in user's API tokens
+async fn list_tokens(
+    OptionalIdentity(identity): OptionalIdentity,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<Vec<ApiTokenRecord>>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    identifier
+        .tokens
+        .list_user_tokens(identity.id)
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(AppError::internal_error(e)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenewTokenParams {
+    /// Requested lease extension in seconds, defaults to the token's period
+    increment: Option<u64>,
+}
+
+/// Renews the API token given as bearer token, so its holder can keep it alive over HTTP
+async fn renew_token(
+    Query(params): Query<RenewTokenParams>,
+    headers: HeaderMap,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<TokenRenewal>, Response> {
+    let error_handler = error_handler.for_bearer_route();
+    let bearer = headers
+        .typed_get::<Authorization<Bearer>>()
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("No API token given")))?;
+
+    identifier
+        .renew_api_token(
+            bearer.token(),
+            params.increment.map(std::time::Duration::from_secs),
+        )
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+/// Revokes one of the logged in user's API tokens by accessor
+async fn revoke_token(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path(accessor): Path<String>,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    match identifier.revoke_api_token(identity.id, &accessor).await {
+        Ok(true) => Ok(Json(serde_json::json!({
+            "message": "Token revoked successfully"
+        }))),
+        Ok(false) => Err(error_handler.handle_error(AppError::not_found("Token not found"))),
+        Err(e) => Err(error_handler.handle_error(e)),
+    }
+}
+
+/// Revokes all of the logged in user's API tokens
+async fn revoke_all_tokens(
+    OptionalIdentity(identity): OptionalIdentity,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    let revoked = identifier
+        .revoke_all_api_tokens(identity.id)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    Ok(Json(serde_json::json!({
+        "message": "Tokens revoked successfully",
+        "revoked": revoked,
+    })))
+}
+
+/// An active session of the logged in user
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: UserSession,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+/// Lists the logged in user's active sessions
+async fn list_sessions(
+    OptionalIdentity(identity): OptionalIdentity,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<Vec<SessionInfo>>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+    let current: Option<Uuid> = session
+        .get(SESSION_REGISTRY_KEY)
+        .await
+        .map_err(|e| error_handler.handle_error(AppError::session_error(e)))?;
+
+    let sessions = identifier
+        .sessions
+        .list_user_sessions(identity.id)
+        .await
+        .map_err(|e| error_handler.handle_error(AppError::internal_error(e)))?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: Some(session.id) == current,
+                session,
+            })
+            .collect(),
+    ))
+}
+
+/// Signs one of the logged in user's sessions out
+async fn revoke_session(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path(id): Path<Uuid>,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    match identifier.sessions.revoke_session(identity.id, id).await {
+        Ok(true) => Ok(Json(serde_json::json!({
+            "message": "Session revoked successfully"
+        }))),
+        Ok(false) => Err(error_handler.handle_error(AppError::not_found("Session not found"))),
+        Err(e) => Err(error_handler.handle_error(AppError::internal_error(e))),
+    }
+}
+
+/// Signs the logged in user out everywhere, including this session
+async fn revoke_all_sessions(
+    OptionalIdentity(identity): OptionalIdentity,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    let revoked = identifier
+        .sessions
+        .revoke_all_sessions(identity.id)
+        .await
+        .map_err(|e| error_handler.handle_error(AppError::internal_error(e)))?;
+
+    Ok(Json(serde_json::json!({
+        "message": "Sessions revoked successfully",
+        "revoked": revoked,
+    })))
+}
+
+/// Newly issued database credentials. The password is only ever shown in this response.
+#[derive(Debug, Serialize)]
+pub struct IssuedDatabaseCredentials {
+    #[serde(flatten)]
+    pub credentials: DatabaseCredentials,
+    pub team: String,
+    pub role: String,
+}
+
+/// Issues short-lived Postgres credentials for the logged in user's team
+///
+/// The credentials are revoked when the session logs out.
+async fn issue_database_credentials(
+    OptionalIdentity(identity): OptionalIdentity,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<IssuedDatabaseCredentials>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    let oidc_token = session
+        .get::<KeyCloakToken>(TOKEN_KEY)
+        .await
+        .map_err(|e| error_handler.handle_error(AppError::session_error(e)))?
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("No OIDC token found")))?;
+    let session_id: Option<Uuid> = session
+        .get(SESSION_REGISTRY_KEY)
+        .await
+        .map_err(|e| error_handler.handle_error(AppError::session_error(e)))?;
+
+    let (credentials, lease) = identifier
+        .issue_database_credentials(&identity, &oidc_token, session_id)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    Ok(Json(IssuedDatabaseCredentials {
+        credentials,
+        team: lease.team,
+        role: lease.role,
+    }))
+}
+
+/// Lists the logged in user's active database credentials, without their passwords
+async fn list_database_credentials(
+    OptionalIdentity(identity): OptionalIdentity,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<Vec<DatabaseLease>>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    identifier
+        .leases
+        .list_user_leases(identity.id)
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(AppError::internal_error(e)))
+}
+
+/// Revokes one of the logged in user's database credentials by lease id
+async fn revoke_database_credentials(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path(lease_id): Path<String>,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    match identifier
+        .revoke_database_credentials(identity.id, &lease_id)
+        .await
+    {
+        Ok(true) => Ok(Json(serde_json::json!({
+            "message": "Credentials revoked successfully"
+        }))),
+        Ok(false) => Err(error_handler.handle_error(AppError::not_found("Lease not found"))),
+        Err(e) => Err(error_handler.handle_error(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueCertificateParams {
+    /// Team member the certificate authenticates as, the captain by default
+    user_id: Option<Uuid>,
+    /// Label of the agent using the certificate
+    name: Option<String>,
+    /// Lifetime in seconds
+    ttl: Option<u64>,
+}
+
+/// A newly issued client certificate. The private key is only ever shown in this response.
+#[derive(Debug, Serialize)]
+pub struct IssuedClientCertificate {
+    #[serde(flatten)]
+    pub certificate: IssuedCertificate,
+    pub user_id: Uuid,
+    pub team: String,
+    pub name: Option<String>,
+}
+
+/// Issues a client certificate for an agent of the logged in captain's team
+///
+/// The query picks the agent's user and lifetime, e.g. `?user_id=...&name=grader&ttl=3600`.
+async fn issue_client_certificate(
+    OptionalIdentity(identity): OptionalIdentity,
+    Query(params): Query<IssueCertificateParams>,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<IssuedClientCertificate>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    let (certificate, record) = identifier
+        .issue_client_certificate(
+            &identity,
+            params.user_id,
+            params.name.as_deref(),
+            params.ttl.map(Duration::from_secs),
+        )
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    Ok(Json(IssuedClientCertificate {
+        certificate,
+        user_id: record.user_id,
+        team: record.team,
+        name: record.name,
+    }))
+}
+
+/// Lists the active client certificates of the logged in user's team
+async fn list_client_certificates(
+    OptionalIdentity(identity): OptionalIdentity,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<Vec<ClientCertificateRecord>>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+    let team = identity
+        .team
+        .ok_or_else(|| error_handler.handle_error(AppError::forbidden("Not in a team")))?;
+
+    identifier
+        .certificates
+        .list_team_certificates(&team.name)
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(AppError::internal_error(e)))
+}
+
+/// Revokes one of the logged in captain's team certificates by serial number
+async fn revoke_client_certificate(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path(serial): Path<String>,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let identity = identity
+        .ok_or_else(|| error_handler.handle_error(AppError::unauthorized("Not logged in")))?;
+
+    match identifier
+        .revoke_client_certificate(&identity, &serial)
+        .await
+    {
+        Ok(true) => Ok(Json(serde_json::json!({
+            "message": "Certificate revoked successfully"
+        }))),
+        Ok(false) => Err(error_handler.handle_error(AppError::not_found("Certificate not found"))),
+        Err(e) => Err(error_handler.handle_error(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnwrapRequest {
+    token: String,
+}
+
+/// Returns the value behind a wrapping token, once
+///
+/// The token is sent in the body, so it doesn't end up in access logs. Tokens that were
+/// used before are refused with a `410` and logged for auditing.
+async fn unwrap(
+    OptionalIdentity(identity): OptionalIdentity,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+    Json(request): Json<UnwrapRequest>,
+) -> Result<Json<UnwrappedValue>, Response> {
+    identifier
+        .unwrap(&request.token, identity.as_ref())
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_return_to() {
+        assert_eq!(sanitize_return_to(Some("/foo?bar=1")), "/foo?bar=1");
+        assert_eq!(sanitize_return_to(None), "/");
+        assert_eq!(sanitize_return_to(Some("https://evil.example.com/")), "/");
+        assert_eq!(sanitize_return_to(Some("//evil.example.com/")), "/");
+        assert_eq!(sanitize_return_to(Some("/\\evil.example.com")), "/");
+    }
+}
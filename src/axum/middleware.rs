@@ -1,18 +1,37 @@
 use axum::{
-    extract::Query,
-    http::{Request, Uri},
+    extract::{ConnectInfo, OriginalUri, Query},
+    http::{
+        header::{ACCEPT, USER_AGENT},
+        request::Parts,
+        Request, Uri,
+    },
     response::{IntoResponse, Redirect, Response},
 };
 use futures_util::{future::BoxFuture, FutureExt};
 use headers::{authorization::Bearer, Authorization, HeaderMapExt};
 use serde::Deserialize;
-use std::task::{Context, Poll};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+};
 use tower::{Layer, Service};
 use tower_sessions::Session;
+use uuid::Uuid;
+
+use crate::{
+    database::sessions::{SessionClient, SESSION_REGISTRY_KEY},
+    errors::{AppError, ErrorFormat},
+    vault::{cidr_contains, parse_cidr},
+    AiclIdentifier, AiclIdentity,
+};
 
-use crate::{AiclIdentifier, AiclIdentity};
+use super::error::{required_extension, AppErrorHandler};
 
-use super::error::AppErrorHandler;
+/// Short-circuit a middleware with an already built response
+fn ready<E: 'static>(response: Response) -> BoxFuture<'static, Result<Response, E>> {
+    async move { Ok(response) }.boxed()
+}
 
 /// A layer that adds the identifier to request extensions
 #[derive(Clone)]
@@ -87,27 +106,26 @@ where
     }
 
     fn call(&mut self, request: Request<B>) -> Self::Future {
-        let error_handler = request
-            .extensions()
-            .get::<AppErrorHandler>()
-            .expect("Error handler not found")
-            .clone();
-        let identifier = request
-            .extensions()
-            .get::<AiclIdentifier>()
-            .expect("Identifier not found")
-            .clone();
+        let extensions = request.extensions();
+        let (error_handler, identifier, session) = match (
+            required_extension::<AppErrorHandler>(extensions),
+            required_extension::<AiclIdentifier>(extensions),
+            required_extension::<Session>(extensions),
+        ) {
+            (Ok(error_handler), Ok(identifier), Ok(session)) => {
+                (error_handler, identifier, session)
+            }
+            (Err(response), _, _) | (_, Err(response), _) | (_, _, Err(response)) => {
+                return ready(response)
+            }
+        };
         let inner = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, inner);
 
-        // Get the session from the request extensions
-        let session = match request.extensions().get::<Session>() {
-            Some(session) => session.clone(),
-            None => panic!("Session not found in request extensions, layer this correctly"),
-        };
-
         Box::pin(async move {
             let (mut parts, body) = request.into_parts();
+            // Set by the API token layer, such requests don't belong to a session
+            let token_identity = parts.extensions.get::<AiclIdentity>().is_some();
             match identifier
                 .oidc
                 .authenticate(&mut parts, &session, &identifier.idp)
@@ -119,12 +137,146 @@ where
                     return Ok(error_handler.handle_error(error));
                 }
             }
+            if !token_identity {
+                if let Err(error) = track_session(&identifier, &mut parts, &session).await {
+                    tracing::error!(%error, "Session registry check failed");
+                    return Ok(error_handler.handle_error(error));
+                }
+            }
             let request = Request::from_parts(parts, body);
             inner.call(request).await
         })
     }
 }
 
+/// Proxies whose `X-Forwarded-For` header is believed
+///
+/// Without trusted proxies the peer address is the client. Behind them, the client is the
+/// last `X-Forwarded-For` hop that isn't a trusted proxy itself: the hops before it are
+/// whatever the client sent.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<String>,
+}
+
+impl TrustedProxies {
+    /// Proxies at these addresses or networks, e.g. `10.0.0.0/8`
+    pub fn new(cidrs: Vec<String>) -> Self {
+        for cidr in cidrs.iter().filter(|cidr| parse_cidr(cidr).is_none()) {
+            tracing::warn!("Ignoring invalid trusted proxy {}", cidr);
+        }
+        Self { cidrs }
+    }
+
+    /// The comma separated networks in `TRUSTED_PROXIES`, none when it's unset
+    pub fn from_env() -> Self {
+        let cidrs = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+        Self::new(
+            cidrs
+                .split(',')
+                .map(str::trim)
+                .filter(|cidr| !cidr.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr_contains(cidr, ip))
+    }
+
+    /// The address of the client, `None` when the server doesn't provide `ConnectInfo`
+    pub fn client_ip(&self, parts: &Parts) -> Option<IpAddr> {
+        let ConnectInfo(peer) = parts.extensions.get::<ConnectInfo<SocketAddr>>()?;
+        let mut client = peer.ip();
+        let hops = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            if !self.trusts(client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => client = hop,
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Where the request comes from, for the session registry
+fn session_client(identifier: &AiclIdentifier, parts: &Parts) -> SessionClient {
+    SessionClient {
+        ip_address: identifier
+            .trusted_proxies
+            .client_ip(parts)
+            .map(|ip| ip.to_string()),
+        user_agent: parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+    }
+}
+
+/// Register the authenticated session, or check it wasn't revoked
+///
+/// The registry id is handed out at login, so concurrent first requests register the
+/// session once. A revoked session is flushed and the identity removed, so the request
+/// continues as anonymous and the login layer sends the user back to the identity provider.
+async fn track_session(
+    identifier: &AiclIdentifier,
+    parts: &mut Parts,
+    session: &Session,
+) -> Result<(), AppError> {
+    let Some(identity) = parts.extensions.get::<AiclIdentity>().cloned() else {
+        return Ok(());
+    };
+    let client = session_client(identifier, parts);
+    let registry_id: Option<Uuid> = session
+        .get(SESSION_REGISTRY_KEY)
+        .await
+        .map_err(AppError::session_error)?;
+    // Sessions logged in before the id was handed out at login get one now
+    let registry_id = match registry_id {
+        Some(registry_id) => registry_id,
+        None => {
+            let registry_id = Uuid::new_v4();
+            session
+                .insert(SESSION_REGISTRY_KEY, registry_id)
+                .await
+                .map_err(AppError::session_error)?;
+            registry_id
+        }
+    };
+
+    let mut active = identifier
+        .sessions
+        .touch(registry_id, &client)
+        .await
+        .map_err(AppError::internal_error)?;
+    if !active {
+        // Not registered yet, or revoked
+        active = identifier
+            .sessions
+            .register(registry_id, identity.id, identity.role, &client)
+            .await
+            .map_err(AppError::internal_error)?;
+    }
+    if !active {
+        tracing::info!(user_id = %identity.id, %registry_id, "Session was revoked");
+        session.flush().await.map_err(AppError::session_error)?;
+        parts.extensions.remove::<AiclIdentity>();
+    }
+
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct AuthenticateLayer {}
 
@@ -136,31 +288,150 @@ impl<S: Clone> Layer<S> for AuthenticateLayer {
     }
 }
 
+/// Rules used to tell API requests apart from browser navigations
+///
+/// Unauthenticated API requests get a `401` with a JSON body instead of a redirect to the
+/// identity provider, since `fetch`/XHR callers can't follow the login flow.
+#[derive(Debug, Clone)]
+pub struct ApiRequestRules {
+    /// Treat requests that prefer a JSON response (per `Accept`) as API requests
+    pub match_accept_json: bool,
+    /// Treat requests carrying `X-Requested-With: XMLHttpRequest` as API requests
+    pub match_x_requested_with: bool,
+    /// Treat requests whose `Sec-Fetch-Mode` is not `navigate` as API requests
+    pub match_sec_fetch_mode: bool,
+    /// Treat requests whose path starts with one of these prefixes as API requests
+    pub path_prefixes: Vec<String>,
+}
+
+impl Default for ApiRequestRules {
+    fn default() -> Self {
+        Self {
+            match_accept_json: true,
+            match_x_requested_with: true,
+            match_sec_fetch_mode: true,
+            path_prefixes: vec!["/api/".to_string()],
+        }
+    }
+}
+
+impl ApiRequestRules {
+    pub fn is_api_request<B>(&self, req: &Request<B>) -> bool {
+        let headers = req.headers();
+
+        if self.match_x_requested_with
+            && headers
+                .get("x-requested-with")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.eq_ignore_ascii_case("XMLHttpRequest"))
+        {
+            return true;
+        }
+
+        if self.match_sec_fetch_mode
+            && headers
+                .get("sec-fetch-mode")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| !v.eq_ignore_ascii_case("navigate"))
+        {
+            return true;
+        }
+
+        if self.match_accept_json
+            && ErrorFormat::negotiate(headers.get(ACCEPT), ErrorFormat::Html) == ErrorFormat::Json
+        {
+            return true;
+        }
+
+        let path = original_uri(req).path().to_string();
+        self.path_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// The URI as seen by the outermost router, before any `nest` stripped a prefix
+fn original_uri<B>(req: &Request<B>) -> Uri {
+    req.extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| req.uri().clone())
+}
+
 /// Layer that applies the login enforcer middleware
 #[derive(Clone)]
-pub struct LoginEnforcerLayer {}
+pub struct LoginEnforcerLayer {
+    api_rules: Arc<ApiRequestRules>,
+    login_path: Arc<str>,
+}
+
+impl Default for LoginEnforcerLayer {
+    fn default() -> Self {
+        Self {
+            api_rules: Arc::new(ApiRequestRules::default()),
+            login_path: Arc::from("/auth/login"),
+        }
+    }
+}
+
+impl LoginEnforcerLayer {
+    /// Replace the rules used to detect API requests
+    pub fn with_api_rules(mut self, api_rules: ApiRequestRules) -> Self {
+        self.api_rules = Arc::new(api_rules);
+        self
+    }
+
+    /// Path of the login endpoint advertised to API clients as `login_url` in the `401` error
+    pub fn with_login_path(mut self, login_path: impl Into<String>) -> Self {
+        self.login_path = Arc::from(login_path.into());
+        self
+    }
+}
 
 impl<S> tower::Layer<S> for LoginEnforcerLayer {
     type Service = LoginEnforcerMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        LoginEnforcerMiddleware { inner }
+        LoginEnforcerMiddleware {
+            inner,
+            api_rules: self.api_rules.clone(),
+            login_path: self.login_path.clone(),
+        }
     }
 }
 
 /// The middleware service that enforces login
 pub struct LoginEnforcerMiddleware<S> {
     inner: S,
+    api_rules: Arc<ApiRequestRules>,
+    login_path: Arc<str>,
 }
 
 impl<S: Clone> Clone for LoginEnforcerMiddleware<S> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            api_rules: self.api_rules.clone(),
+            login_path: self.login_path.clone(),
         }
     }
 }
 
+/// Login endpoint for unauthenticated API requests, returning to `return_to` afterwards
+fn login_url(login_path: &str, return_to: &Uri) -> String {
+    let return_to = return_to
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    format!(
+        "{}?{}",
+        login_path,
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("return_to", return_to)
+            .finish()
+    )
+}
+
 #[derive(Debug, Deserialize)]
 struct OidcQuery {
     code: String,
@@ -189,35 +460,43 @@ where
     }
 
     fn call(&mut self, req: Request<B>) -> Self::Future {
-        let error_handler = req
-            .extensions()
-            .get::<AppErrorHandler>()
-            .expect("Error handler not found")
-            .clone();
-        let identifier = req
-            .extensions()
-            .get::<AiclIdentifier>()
-            .expect("Identifier not found")
-            .clone();
+        let extensions = req.extensions();
+        let (error_handler, identifier) = match (
+            required_extension::<AppErrorHandler>(extensions),
+            required_extension::<AiclIdentifier>(extensions),
+        ) {
+            (Ok(error_handler), Ok(identifier)) => (error_handler, identifier),
+            (Err(response), _) | (_, Err(response)) => return ready(response),
+        };
         // Clone the inner service
         let mut inner = self.inner.clone();
 
         // Check if the user is identified, and return if they are.
-        if let Some(_) = req.extensions().get::<AiclIdentity>() {
+        if req.extensions().get::<AiclIdentity>().is_some() {
             return async move { inner.call(req).await }.boxed();
         }
 
+        // API clients can't follow the login redirect, tell them where to log in instead
+        if self.api_rules.is_api_request(&req) {
+            let return_to = strip_oidc_params(&original_uri(&req));
+            let response = error_handler.handle_error(AppError::login_required(login_url(
+                &self.login_path,
+                &return_to,
+            )));
+            return async move { Ok(response) }.boxed();
+        }
+
         // Get the session from the request extensions
-        let session = match req.extensions().get::<Session>() {
-            Some(session) => session.clone(),
-            None => panic!("Session not found in request extensions, layer this correctly"),
+        let session = match required_extension::<Session>(req.extensions()) {
+            Ok(session) => session,
+            Err(response) => return ready(response),
         };
 
         // Extract the URI for potential redirection
         let uri = req.uri().clone();
         let redirect = strip_oidc_params(&uri);
 
-        if let Some(Query(query)) = Query::<OidcQuery>::try_from_uri(&uri).ok() {
+        if let Ok(Query(query)) = Query::<OidcQuery>::try_from_uri(&uri) {
             return Box::pin(async move {
                 match identifier
                     .oidc
@@ -296,7 +575,7 @@ fn strip_oidc_params(uri: &Uri) -> Uri {
     let mut parts = uri.to_string();
     if let Some(query_start) = parts.find('?') {
         parts.truncate(query_start);
-        parts.push_str("?");
+        parts.push('?');
         parts.push_str(&new_query);
     }
 
@@ -354,19 +633,18 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request<B>) -> Self::Future {
-        // Get the error handler from request extensions
-        let error_handler = req
-            .extensions()
-            .get::<AppErrorHandler>()
-            .expect("Error handler not found")
-            .clone();
-
-        let identifier = req
-            .extensions()
-            .get::<AiclIdentifier>()
-            .expect("Identifier not found")
-            .clone();
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        // Get the error handler and identifier from request extensions
+        let extensions = req.extensions();
+        let (error_handler, identifier) = match (
+            required_extension::<AppErrorHandler>(extensions),
+            required_extension::<AiclIdentifier>(extensions),
+        ) {
+            (Ok(error_handler), Ok(identifier)) => (error_handler.for_bearer_route(), identifier),
+            (Err(response), _) | (_, Err(response)) => return ready(response),
+        };
+        // Handlers behind this layer answer a missing token with a bearer challenge too
+        req.extensions_mut().insert(error_handler.clone());
 
         // Get token service and setup clones for async block
         let mut inner = self.inner.clone();
@@ -386,9 +664,21 @@ where
 
             // Verify the token and get the user ID
             match identifier.vault.verify_token(&token).await {
-                Ok(user_id) => {
+                Ok(verified) => {
+                    let (parts, body) = req.into_parts();
+                    if !verified.allows_ip(identifier.trusted_proxies.client_ip(&parts)) {
+                        return Ok(error_handler.handle_error(AppError::forbidden(
+                            "API token is not allowed from this network",
+                        )));
+                    }
+                    let req = Request::from_parts(parts, body);
+
+                    if let Err(e) = identifier.tokens.mark_used(&verified.accessor).await {
+                        tracing::warn!("Failed to record API token use: {:#}", e);
+                    }
+
                     // Get the user identity from IdpAdmin
-                    match identifier.idp.get_domain_user(user_id).await {
+                    match identifier.idp.get_domain_user(verified.user_id).await {
                         Ok(identity) => {
                             // Add identity to request extensions
                             let (mut parts, body) = req.into_parts();
@@ -409,3 +699,122 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_api_request_detection() {
+        let rules = ApiRequestRules::default();
+
+        let navigation = request(
+            "/dashboard",
+            &[
+                ("accept", "text/html,application/xhtml+xml,*/*;q=0.8"),
+                ("sec-fetch-mode", "navigate"),
+            ],
+        );
+        assert!(!rules.is_api_request(&navigation));
+
+        assert!(rules.is_api_request(&request("/dashboard", &[("accept", "application/json")])));
+        assert!(rules.is_api_request(&request(
+            "/dashboard",
+            &[("x-requested-with", "XMLHttpRequest")]
+        )));
+        assert!(rules.is_api_request(&request("/dashboard", &[("sec-fetch-mode", "cors")])));
+        assert!(rules.is_api_request(&request("/api/teams", &[])));
+    }
+
+    #[test]
+    fn test_client_ip_behind_trusted_proxies() {
+        let parts = |peer: &str, forwarded_for: &str| {
+            let mut request = request("/", &[("x-forwarded-for", forwarded_for)]);
+            let peer: SocketAddr = format!("{}:443", peer).parse().unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            request.into_parts().0
+        };
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        let proxies = TrustedProxies::new(vec!["10.0.0.0/8".to_string()]);
+
+        // The header of anyone else is ignored
+        let direct = parts("203.0.113.7", "198.51.100.1");
+        assert_eq!(proxies.client_ip(&direct), ip("203.0.113.7"));
+        assert_eq!(
+            TrustedProxies::default().client_ip(&direct),
+            ip("203.0.113.7")
+        );
+
+        // Behind proxies, the hops the client made up are skipped
+        let proxied = parts("10.0.0.2", "198.51.100.1, 203.0.113.7, 10.0.0.1");
+        assert_eq!(proxies.client_ip(&proxied), ip("203.0.113.7"));
+        assert_eq!(
+            TrustedProxies::default().client_ip(&proxied),
+            ip("10.0.0.2")
+        );
+
+        // Without the peer address there is no telling
+        let unknown = request("/", &[("x-forwarded-for", "203.0.113.7")])
+            .into_parts()
+            .0;
+        assert_eq!(proxies.client_ip(&unknown), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_extensions_return_500() {
+        use tower::ServiceExt;
+
+        let error_handler = AppErrorHandler::new(crate::errors::JsonErrorHandler::default());
+        // The identifier and session layers are missing
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "ok" }))
+            .layer(AuthenticateLayer {})
+            .layer(error_handler.layer());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_login_required_error() {
+        let return_to: Uri = "/dashboard?tab=1".parse().unwrap();
+        let login_url = login_url("/auth/login", &return_to);
+        assert_eq!(login_url, "/auth/login?return_to=%2Fdashboard%3Ftab%3D1");
+
+        let body = |response: Response| async move {
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        // The error handler renders the login URL in its own format
+        let json = AppErrorHandler::new(crate::errors::JsonErrorHandler::default());
+        let response = json.handle_error(AppError::login_required(login_url.clone()));
+        assert_eq!(body(response).await["error"]["login_url"], login_url);
+
+        let problem = AppErrorHandler::new(crate::errors::ProblemJsonErrorHandler::default());
+        let response = problem.handle_error(AppError::login_required(login_url.clone()));
+        let problem = body(response).await;
+        assert_eq!(problem["code"], "auth.login_required");
+        assert_eq!(problem["login_url"], login_url);
+    }
+}
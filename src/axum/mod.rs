@@ -1,3 +1,7 @@
+pub mod client_cert;
 pub mod error;
 pub mod extractors;
+pub mod handlers;
 pub mod middleware;
+pub mod router;
+pub mod secrets;
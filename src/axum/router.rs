@@ -0,0 +1,82 @@
+use std::convert::Infallible;
+
+use axum::{extract::Request, response::IntoResponse, routing::Route, Router};
+use tower::{Layer, Service};
+
+use crate::{AiclIdentifier, AppErrorHandler};
+
+/// Installs the AICL layers on a [`Router`] in the order the middlewares expect
+///
+/// Axum layers only wrap the routes that exist when `.layer()` is called, so call these
+/// after adding the routes they should protect. Routes merged afterwards are not wrapped.
+///
+/// ```ignore
+/// let app = Router::new()
+///     .route("/foo", get(authenticated))
+///     .layer(identifier.login_layer())
+///     .route("/bar", get(maybe_authenticated))
+///     .merge(identifier.auth_router())
+///     .with_aicl_auth(&identifier, &error_handler, session_layer);
+/// ```
+pub trait AiclRouterExt {
+    /// Wrap the routes with, from outermost to innermost, the error handler, the identifier,
+    /// the session and the OIDC authentication layers
+    ///
+    /// `session_layer` is a tower-sessions `SessionManagerLayer`, e.g. from
+    /// [`crate::oidc::session::SessionConfig`], or a
+    /// [`crate::oidc::cookie_session::CookieSessionLayer`].
+    fn with_aicl_auth<L>(
+        self,
+        identifier: &AiclIdentifier,
+        error_handler: &AppErrorHandler,
+        session_layer: L,
+    ) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static;
+
+    /// Wrap the routes with, from outermost to innermost, the error handler, the identifier
+    /// and the API token authentication layers. No session is needed.
+    fn with_aicl_api_token_auth(
+        self,
+        identifier: &AiclIdentifier,
+        error_handler: &AppErrorHandler,
+    ) -> Self;
+}
+
+impl<S> AiclRouterExt for Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    fn with_aicl_auth<L>(
+        self,
+        identifier: &AiclIdentifier,
+        error_handler: &AppErrorHandler,
+        session_layer: L,
+    ) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layer(identifier.authenticate_layer())
+            .layer(session_layer)
+            .layer(identifier.identifier_layer())
+            .layer(error_handler.layer())
+    }
+
+    fn with_aicl_api_token_auth(
+        self,
+        identifier: &AiclIdentifier,
+        error_handler: &AppErrorHandler,
+    ) -> Self {
+        self.layer(identifier.api_token_layer())
+            .layer(identifier.identifier_layer())
+            .layer(error_handler.layer())
+    }
+}
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use crate::{
+    errors::AppError,
+    oidc::keycloak::{KeyCloakToken, TOKEN_KEY},
+    vault::secrets::{KvSecrets, SecretVersion},
+    AiclIdentifier, AiclIdentity, AppErrorHandler, OptionalIdentity, Role,
+};
+
+/// Whose secrets a request is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretScope {
+    Team,
+    Institution,
+}
+
+impl SecretScope {
+    /// Only captains change team secrets and only advisors change institution secrets
+    fn can_write(&self, role: Role) -> bool {
+        matches!(
+            (self, role),
+            (Self::Team, Role::Captain) | (Self::Institution, Role::Advisor)
+        )
+    }
+}
+
+/// Build the secrets router, mounted under `base_path`
+///
+/// The routes expect the layers installed by [`crate::AiclRouterExt::with_aicl_auth`]
+/// around them, the user's Vault token comes from the OIDC token in the session.
+pub(crate) fn secrets_router<S>(base_path: &str) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let base_path = base_path.trim_end_matches('/');
+    Router::new()
+        .route(&format!("{}/{{scope}}", base_path), get(list_secrets))
+        .route(
+            &format!("{}/{{scope}}/data/{{*name}}", base_path),
+            get(read_secret).put(write_secret).delete(delete_secret),
+        )
+        .route(
+            &format!("{}/{{scope}}/versions/{{*name}}", base_path),
+            get(secret_versions),
+        )
+        .route(
+            &format!("{}/{{scope}}/rollback/{{*name}}", base_path),
+            post(rollback_secret),
+        )
+}
+
+/// Open the secrets of the scope as the logged in user
+async fn open_secrets(
+    scope: SecretScope,
+    identity: Option<AiclIdentity>,
+    session: &Session,
+    identifier: &AiclIdentifier,
+    write: bool,
+) -> Result<KvSecrets, AppError> {
+    let identity = identity.ok_or_else(|| AppError::unauthorized("Not logged in"))?;
+    if write && !scope.can_write(identity.role) {
+        return Err(AppError::forbidden(match scope {
+            SecretScope::Team => "Only team captains can change team secrets",
+            SecretScope::Institution => "Only advisors can change institution secrets",
+        }));
+    }
+
+    let oidc_token = session
+        .get::<KeyCloakToken>(TOKEN_KEY)
+        .await
+        .map_err(AppError::session_error)?
+        .ok_or_else(|| AppError::unauthorized("No OIDC token found"))?;
+
+    Ok(match scope {
+        SecretScope::Team => identifier
+            .vault
+            .team_secrets(&identity, &oidc_token)
+            .await?
+            .into_inner(),
+        SecretScope::Institution => identifier
+            .vault
+            .institution_secrets(&identity, &oidc_token)
+            .await?
+            .into_inner(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    folder: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VersionParams {
+    version: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadParams {
+    version: Option<u64>,
+    /// Return a single-use wrapping token valid for this many seconds instead of the secret
+    wrap_ttl: Option<u64>,
+}
+
+/// Lists the secrets in a folder, the root by default
+async fn list_secrets(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path(scope): Path<SecretScope>,
+    Query(params): Query<ListParams>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<Vec<String>>, Response> {
+    let secrets = open_secrets(scope, identity, &session, &identifier, false)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    secrets
+        .list(params.folder.as_deref())
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+/// Reads the latest or the requested version of a secret
+///
+/// With `?wrap_ttl=300` the secret is returned wrapped, for handing it to someone else.
+async fn read_secret(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path((scope, name)): Path<(SecretScope, String)>,
+    Query(params): Query<ReadParams>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Response, Response> {
+    let secrets = open_secrets(scope, identity, &session, &identifier, false)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    if let Some(wrap_ttl) = params.wrap_ttl {
+        return secrets
+            .read_wrapped(&name, params.version, Some(Duration::from_secs(wrap_ttl)))
+            .await
+            .map(|wrapped| Json(wrapped).into_response())
+            .map_err(|e| error_handler.handle_error(e));
+    }
+    let secret: Result<serde_json::Value, _> = match params.version {
+        Some(version) => secrets.read_version(&name, version).await,
+        None => secrets.read(&name).await,
+    };
+    secret
+        .map(|secret| Json(secret).into_response())
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+/// Writes a new version of a secret
+async fn write_secret(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path((scope, name)): Path<(SecretScope, String)>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+    Json(data): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Result<Json<SecretVersion>, Response> {
+    let secrets = open_secrets(scope, identity, &session, &identifier, true)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    secrets
+        .write(&name, &data)
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+/// Soft deletes the latest version of a secret
+async fn delete_secret(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path((scope, name)): Path<(SecretScope, String)>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<serde_json::Value>, Response> {
+    let secrets = open_secrets(scope, identity, &session, &identifier, true)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    secrets
+        .delete(&name)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+    Ok(Json(serde_json::json!({
+        "message": "Secret deleted successfully"
+    })))
+}
+
+/// Lists the versions of a secret
+async fn secret_versions(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path((scope, name)): Path<(SecretScope, String)>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<Vec<SecretVersion>>, Response> {
+    let secrets = open_secrets(scope, identity, &session, &identifier, false)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    secrets
+        .versions(&name)
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+/// Restores an older version of a secret as the latest one
+async fn rollback_secret(
+    OptionalIdentity(identity): OptionalIdentity,
+    Path((scope, name)): Path<(SecretScope, String)>,
+    Query(params): Query<VersionParams>,
+    session: Session,
+    identifier: AiclIdentifier,
+    error_handler: AppErrorHandler,
+) -> Result<Json<SecretVersion>, Response> {
+    let version = params.version.ok_or_else(|| {
+        error_handler.handle_error(AppError::bad_request("The version to restore is required"))
+    })?;
+    let secrets = open_secrets(scope, identity, &session, &identifier, true)
+        .await
+        .map_err(|e| error_handler.handle_error(e))?;
+
+    secrets
+        .rollback(&name, version)
+        .await
+        .map(Json)
+        .map_err(|e| error_handler.handle_error(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_captains_and_advisors_write() {
+        assert!(SecretScope::Team.can_write(Role::Captain));
+        assert!(!SecretScope::Team.can_write(Role::Student));
+        assert!(!SecretScope::Team.can_write(Role::Advisor));
+        assert!(SecretScope::Institution.can_write(Role::Advisor));
+        assert!(!SecretScope::Institution.can_write(Role::Spectator));
+    }
+}
@@ -0,0 +1,322 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::vault::{ApiToken, TokenScope};
+
+/// How long a token's last use is not written again
+const LAST_USED_RESOLUTION: Duration = Duration::from_secs(60);
+
+/// An API token in the registry. The token itself is never stored, only its accessor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenRecord {
+    pub accessor: String,
+    pub user_id: Uuid,
+    pub name: String,
+    pub policies: Vec<String>,
+    /// Token profile the token was created from
+    pub profile: Option<String>,
+    pub num_uses: Option<i64>,
+    pub bound_cidrs: Vec<String>,
+    pub created_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// Registry of the API tokens issued to users
+///
+/// Vault remains the source of truth for whether a token is valid, the registry keeps what
+/// users need to tell their tokens apart and revoke them by accessor.
+pub struct ApiTokenRegistry {
+    db: PgPool,
+    recently_used: Cache<String, ()>,
+}
+
+impl ApiTokenRegistry {
+    pub fn new(db: PgPool) -> Self {
+        Self {
+            db,
+            recently_used: Cache::builder()
+                .time_to_live(LAST_USED_RESOLUTION)
+                .max_capacity(10_000)
+                .build(),
+        }
+    }
+
+    /// Record a newly issued token
+    #[instrument(skip(self, token, scope), level = "debug")]
+    pub async fn record(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token: &ApiToken,
+        profile: Option<&str>,
+        scope: &TokenScope,
+    ) -> anyhow::Result<ApiTokenRecord> {
+        let expires_at = OffsetDateTime::from_unix_timestamp(token.expires_at as i64)
+            .context("Invalid token expiry")?;
+        let num_uses = scope.num_uses.map(|n| n as i64);
+        let bound_cidrs = scope.bound_cidrs.clone().unwrap_or_default();
+
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO api_tokens
+                (accessor, user_id, name, policies, profile, num_uses, bound_cidrs, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING created_at
+            "#,
+            token.accessor,
+            user_id,
+            name,
+            &token.policies,
+            profile,
+            num_uses,
+            &bound_cidrs,
+            expires_at
+        )
+        .fetch_one(&self.db)
+        .await
+        .context("Failed to record API token")?;
+
+        Ok(ApiTokenRecord {
+            accessor: token.accessor.clone(),
+            user_id,
+            name: name.to_string(),
+            policies: token.policies.clone(),
+            profile: profile.map(str::to_string),
+            num_uses,
+            bound_cidrs,
+            created_at: row.created_at,
+            expires_at,
+            last_used_at: None,
+        })
+    }
+
+    /// Get one of the user's active tokens
+    pub async fn get_user_token(
+        &self,
+        user_id: Uuid,
+        accessor: &str,
+    ) -> anyhow::Result<Option<ApiTokenRecord>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT accessor, user_id, name, policies, profile, num_uses, bound_cidrs,
+                   created_at, expires_at, last_used_at
+            FROM api_tokens
+            WHERE accessor = $1 AND user_id = $2
+              AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            "#,
+            accessor,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await
+        .context("Failed to fetch API token")?;
+
+        Ok(row.map(|row| ApiTokenRecord {
+            accessor: row.accessor,
+            user_id: row.user_id,
+            name: row.name,
+            policies: row.policies,
+            profile: row.profile,
+            num_uses: row.num_uses,
+            bound_cidrs: row.bound_cidrs,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        }))
+    }
+
+    /// List the user's active tokens, newest first
+    pub async fn list_user_tokens(&self, user_id: Uuid) -> anyhow::Result<Vec<ApiTokenRecord>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT accessor, user_id, name, policies, profile, num_uses, bound_cidrs,
+                   created_at, expires_at, last_used_at
+            FROM api_tokens
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to fetch API tokens")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ApiTokenRecord {
+                accessor: row.accessor,
+                user_id: row.user_id,
+                name: row.name,
+                policies: row.policies,
+                profile: row.profile,
+                num_uses: row.num_uses,
+                bound_cidrs: row.bound_cidrs,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            })
+            .collect())
+    }
+
+    /// Record that the token was used. Writes at most once a minute per token.
+    pub async fn mark_used(&self, accessor: &str) -> anyhow::Result<()> {
+        if self.recently_used.contains_key(accessor) {
+            return Ok(());
+        }
+        self.recently_used.insert(accessor.to_string(), ()).await;
+
+        sqlx::query!(
+            r#"
+            UPDATE api_tokens
+            SET last_used_at = CURRENT_TIMESTAMP
+            WHERE accessor = $1
+            "#,
+            accessor
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to update API token")?;
+
+        Ok(())
+    }
+
+    /// Record a token's new expiry after a renewal
+    pub async fn update_expiry(&self, accessor: &str, expires_at: u64) -> anyhow::Result<()> {
+        let expires_at = OffsetDateTime::from_unix_timestamp(expires_at as i64)
+            .context("Invalid token expiry")?;
+
+        sqlx::query!(
+            r#"
+            UPDATE api_tokens
+            SET expires_at = $2
+            WHERE accessor = $1 AND revoked_at IS NULL
+            "#,
+            accessor,
+            expires_at
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to update API token expiry")?;
+
+        Ok(())
+    }
+
+    /// Mark a token as revoked. Returns `false` if the user has no such active token.
+    #[instrument(skip(self), level = "info")]
+    pub async fn mark_revoked(&self, user_id: Uuid, accessor: &str) -> anyhow::Result<bool> {
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE api_tokens
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE accessor = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            accessor,
+            user_id
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to revoke API token")?
+        .rows_affected();
+
+        Ok(revoked > 0)
+    }
+
+    /// Mark all of the user's tokens as revoked, returning their accessors
+    #[instrument(skip(self), level = "info")]
+    pub async fn mark_all_revoked(&self, user_id: Uuid) -> anyhow::Result<Vec<String>> {
+        let accessors = sqlx::query_scalar!(
+            r#"
+            UPDATE api_tokens
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL
+            RETURNING accessor
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to revoke API tokens")?;
+
+        info!(revoked = accessors.len(), "Revoked all API tokens");
+        Ok(accessors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn token(accessor: &str) -> ApiToken {
+        ApiToken {
+            client_token: "hvs.secret".to_string(),
+            accessor: accessor.to_string(),
+            expires_at: (OffsetDateTime::now_utc().unix_timestamp() + 3600) as u64,
+            renewable: false,
+            policies: vec!["default".to_string()],
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_api_token_registry(pool: PgPool) -> anyhow::Result<()> {
+        let registry = ApiTokenRegistry::new(pool);
+        let user_id = Uuid::new_v4();
+        let scope = TokenScope {
+            num_uses: Some(10),
+            bound_cidrs: Some(vec!["10.0.0.0/8".to_string()]),
+            ..TokenScope::default()
+        };
+
+        let record = registry
+            .record(user_id, "ci", &token("first"), Some("ci"), &scope)
+            .await?;
+        assert_eq!(record.name, "ci");
+        assert_eq!(record.num_uses, Some(10));
+        registry
+            .record(
+                user_id,
+                "laptop",
+                &token("second"),
+                None,
+                &TokenScope::default(),
+            )
+            .await?;
+        registry
+            .record(
+                Uuid::new_v4(),
+                "other",
+                &token("third"),
+                None,
+                &TokenScope::default(),
+            )
+            .await?;
+
+        registry.mark_used("first").await?;
+        let renewed_until = record.expires_at.unix_timestamp() as u64 + 3600;
+        registry.update_expiry("first", renewed_until).await?;
+        let first = registry.get_user_token(user_id, "first").await?.unwrap();
+        assert!(first.last_used_at.is_some());
+        assert_eq!(first.expires_at.unix_timestamp() as u64, renewed_until);
+        assert_eq!(registry.list_user_tokens(user_id).await?.len(), 2);
+
+        // Other users can't revoke the token
+        assert!(!registry.mark_revoked(Uuid::new_v4(), "first").await?);
+        assert!(registry.mark_revoked(user_id, "first").await?);
+        assert!(registry.get_user_token(user_id, "first").await?.is_none());
+
+        assert_eq!(registry.mark_all_revoked(user_id).await?, vec!["second"]);
+        assert!(registry.list_user_tokens(user_id).await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::vault::pki::IssuedCertificate;
+
+/// A client certificate in the registry. The private key is never stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientCertificateRecord {
+    pub serial_number: String,
+    pub user_id: Uuid,
+    pub team: String,
+    pub issued_by: Uuid,
+    pub name: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+}
+
+/// Registry of the client certificates issued to team agents
+///
+/// Certificates are only accepted while they are in the registry, so revoking one here
+/// takes effect on every replica within `CERTIFICATE_CACHE_TTL`, before Vault's CRL is
+/// picked up.
+pub struct ClientCertificateRegistry {
+    db: PgPool,
+}
+
+impl ClientCertificateRegistry {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Record a newly issued certificate
+    #[instrument(skip(self, certificate), level = "debug")]
+    pub async fn record(
+        &self,
+        user_id: Uuid,
+        team: &str,
+        issued_by: Uuid,
+        name: Option<&str>,
+        certificate: &IssuedCertificate,
+    ) -> anyhow::Result<ClientCertificateRecord> {
+        let expires_at = OffsetDateTime::from_unix_timestamp(certificate.expires_at as i64)
+            .context("Invalid certificate expiry")?;
+
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO client_certificates
+                (serial_number, user_id, team, issued_by, name, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING created_at
+            "#,
+            certificate.serial_number,
+            user_id,
+            team,
+            issued_by,
+            name,
+            expires_at
+        )
+        .fetch_one(&self.db)
+        .await
+        .context("Failed to record client certificate")?;
+
+        Ok(ClientCertificateRecord {
+            serial_number: certificate.serial_number.clone(),
+            user_id,
+            team: team.to_string(),
+            issued_by,
+            name: name.map(str::to_string),
+            created_at: row.created_at,
+            expires_at,
+        })
+    }
+
+    /// An active certificate by serial number
+    pub async fn get_active(
+        &self,
+        serial_number: &str,
+    ) -> anyhow::Result<Option<ClientCertificateRecord>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT serial_number, user_id, team, issued_by, name, created_at, expires_at
+            FROM client_certificates
+            WHERE serial_number = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            "#,
+            serial_number
+        )
+        .fetch_optional(&self.db)
+        .await
+        .context("Failed to fetch client certificate")?;
+
+        Ok(row.map(|row| ClientCertificateRecord {
+            serial_number: row.serial_number,
+            user_id: row.user_id,
+            team: row.team,
+            issued_by: row.issued_by,
+            name: row.name,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+        }))
+    }
+
+    /// List the team's active certificates, newest first
+    pub async fn list_team_certificates(
+        &self,
+        team: &str,
+    ) -> anyhow::Result<Vec<ClientCertificateRecord>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT serial_number, user_id, team, issued_by, name, created_at, expires_at
+            FROM client_certificates
+            WHERE team = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            ORDER BY created_at DESC
+            "#,
+            team
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to fetch client certificates")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ClientCertificateRecord {
+                serial_number: row.serial_number,
+                user_id: row.user_id,
+                team: row.team,
+                issued_by: row.issued_by,
+                name: row.name,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+            })
+            .collect())
+    }
+
+    /// Mark one of the team's certificates as revoked. Returns `false` if the team has no
+    /// such active certificate.
+    #[instrument(skip(self), level = "info")]
+    pub async fn mark_revoked(&self, team: &str, serial_number: &str) -> anyhow::Result<bool> {
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE client_certificates
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE serial_number = $1 AND team = $2 AND revoked_at IS NULL
+            "#,
+            serial_number,
+            team
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to revoke client certificate")?
+        .rows_affected();
+
+        Ok(revoked > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn certificate(serial_number: &str) -> IssuedCertificate {
+        IssuedCertificate {
+            serial_number: serial_number.to_string(),
+            certificate: String::new(),
+            private_key: String::new(),
+            ca_chain: vec![],
+            expires_at: (OffsetDateTime::now_utc().unix_timestamp() + 3600) as u64,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_client_certificate_registry(pool: PgPool) -> anyhow::Result<()> {
+        let registry = ClientCertificateRegistry::new(pool);
+        let captain = Uuid::new_v4();
+        let agent = Uuid::new_v4();
+
+        registry
+            .record(
+                agent,
+                "Team1",
+                captain,
+                Some("grader"),
+                &certificate("0a:01"),
+            )
+            .await?;
+        registry
+            .record(captain, "Team1", captain, None, &certificate("0a:02"))
+            .await?;
+        registry
+            .record(
+                Uuid::new_v4(),
+                "Team2",
+                Uuid::new_v4(),
+                None,
+                &certificate("0a:03"),
+            )
+            .await?;
+
+        let listed = registry.list_team_certificates("Team1").await?;
+        assert_eq!(listed.len(), 2);
+        let record = registry.get_active("0a:01").await?.unwrap();
+        assert_eq!(record.user_id, agent);
+        assert_eq!(record.name.as_deref(), Some("grader"));
+
+        // Other teams can't revoke the certificate
+        assert!(!registry.mark_revoked("Team2", "0a:01").await?);
+        assert!(registry.mark_revoked("Team1", "0a:01").await?);
+        assert!(registry.get_active("0a:01").await?.is_none());
+        assert_eq!(registry.list_team_certificates("Team1").await?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::vault::transit::{key_version, Encrypted, KeyContext};
+
+use super::IdpSyncService;
+
+/// A system configuration entry with its value decrypted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemConfig {
+    pub key: String,
+    pub value: Option<String>,
+    pub description: Option<String>,
+    pub is_sensitive: bool,
+    pub updated_at: Option<OffsetDateTime>,
+}
+
+impl IdpSyncService {
+    /// Get a configuration entry, decrypting sensitive values
+    pub async fn get_config(&self, key: &str) -> anyhow::Result<Option<SystemConfig>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT key, value, description, is_sensitive, updated_at
+            FROM system_configs
+            WHERE key = $1
+            "#,
+            key
+        )
+        .fetch_optional(&self.db)
+        .await
+        .context("Failed to fetch system config")?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let is_sensitive = row.is_sensitive.unwrap_or(false);
+        let value = match row.value {
+            // Plaintext stored before sensitive configs were encrypted, until the rewrap
+            // job encrypts it
+            Some(value) if is_sensitive && key_version(&value).is_none() => {
+                tracing::warn!(key, "Sensitive system config is not encrypted yet");
+                Some(value)
+            }
+            Some(ciphertext) if is_sensitive => {
+                let crypto = self
+                    .crypto
+                    .as_ref()
+                    .context("Sensitive configs need a VaultCrypto to be read")?;
+                let value: String = Encrypted::from_ciphertext(ciphertext)
+                    .open(crypto, KeyContext::Global)
+                    .await
+                    .context("Failed to decrypt system config")?;
+                Some(value)
+            }
+            value => value,
+        };
+
+        Ok(Some(SystemConfig {
+            key: row.key,
+            value,
+            description: row.description,
+            is_sensitive,
+            updated_at: row.updated_at,
+        }))
+    }
+
+    /// Create or update a configuration entry. Sensitive values are stored encrypted.
+    #[instrument(skip(self, value), level = "info")]
+    pub async fn set_config(
+        &self,
+        key: &str,
+        value: &str,
+        description: Option<&str>,
+        is_sensitive: bool,
+        updated_by: Option<Uuid>,
+    ) -> anyhow::Result<()> {
+        let stored = if is_sensitive {
+            let crypto = self
+                .crypto
+                .as_ref()
+                .context("Sensitive configs need a VaultCrypto to be stored")?;
+            Encrypted::seal(crypto, KeyContext::Global, &value.to_string())
+                .await
+                .context("Failed to encrypt system config")?
+                .into_ciphertext()
+        } else {
+            value.to_string()
+        };
+
+        sqlx::query!(
+            r#"
+            INSERT INTO system_configs
+                (key, value, description, is_sensitive, created_by, updated_by)
+            VALUES ($1, $2, $3, $4, $5, $5)
+            ON CONFLICT (key) DO UPDATE
+            SET value = EXCLUDED.value,
+                description = COALESCE(EXCLUDED.description, system_configs.description),
+                is_sensitive = EXCLUDED.is_sensitive,
+                updated_by = EXCLUDED.updated_by
+            "#,
+            key,
+            stored,
+            description,
+            is_sensitive,
+            updated_by
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to store system config")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::AiclIdentifier;
+
+    #[sqlx::test]
+    async fn test_sensitive_configs_are_encrypted(pool: PgPool) -> anyhow::Result<()> {
+        let identifier = AiclIdentifier::from_env(pool.clone()).await?;
+        let sync_service = identifier.db.clone();
+
+        sync_service
+            .set_config("llm.api_key", "sk-secret", Some("LLM API key"), true, None)
+            .await?;
+        sync_service
+            .set_config("llm.model", "small", None, false, None)
+            .await?;
+
+        let stored =
+            sqlx::query_scalar!("SELECT value FROM system_configs WHERE key = 'llm.api_key'")
+                .fetch_one(&pool)
+                .await?
+                .unwrap();
+        assert!(
+            stored.starts_with("vault:v"),
+            "Sensitive values should be stored encrypted"
+        );
+
+        let api_key = sync_service.get_config("llm.api_key").await?.unwrap();
+        assert_eq!(api_key.value.as_deref(), Some("sk-secret"));
+        assert_eq!(api_key.description.as_deref(), Some("LLM API key"));
+        let model = sync_service.get_config("llm.model").await?.unwrap();
+        assert_eq!(model.value.as_deref(), Some("small"));
+        assert!(sync_service.get_config("missing").await?.is_none());
+
+        // Nothing to rewrap before the key is rotated
+        assert_eq!(sync_service.rewrap_encrypted().await?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::vault::database::DatabaseCredentials;
+
+/// A lease of dynamic Postgres credentials in the registry. The password is never stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseLease {
+    pub lease_id: String,
+    pub user_id: Uuid,
+    /// Session the credentials were issued to, if any
+    pub session_id: Option<Uuid>,
+    pub team: String,
+    pub role: String,
+    pub username: String,
+    pub created_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+}
+
+/// Registry of the database credentials issued to team members
+///
+/// Vault expires the credentials on its own, the registry lets them end earlier: with the
+/// session on logout, or for the whole team when it is removed.
+pub struct DatabaseLeaseRegistry {
+    db: PgPool,
+}
+
+impl DatabaseLeaseRegistry {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Record newly issued credentials
+    #[instrument(skip(self, credentials), level = "debug")]
+    pub async fn record(
+        &self,
+        user_id: Uuid,
+        session_id: Option<Uuid>,
+        team: &str,
+        role: &str,
+        credentials: &DatabaseCredentials,
+    ) -> anyhow::Result<DatabaseLease> {
+        let expires_at = OffsetDateTime::from_unix_timestamp(credentials.expires_at as i64)
+            .context("Invalid lease expiry")?;
+
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO database_leases
+                (lease_id, user_id, session_id, team, role, username, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING created_at
+            "#,
+            credentials.lease_id,
+            user_id,
+            session_id,
+            team,
+            role,
+            credentials.username,
+            expires_at
+        )
+        .fetch_one(&self.db)
+        .await
+        .context("Failed to record database lease")?;
+
+        Ok(DatabaseLease {
+            lease_id: credentials.lease_id.clone(),
+            user_id,
+            session_id,
+            team: team.to_string(),
+            role: role.to_string(),
+            username: credentials.username.clone(),
+            created_at: row.created_at,
+            expires_at,
+        })
+    }
+
+    /// List the user's active leases, newest first
+    pub async fn list_user_leases(&self, user_id: Uuid) -> anyhow::Result<Vec<DatabaseLease>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT lease_id, user_id, session_id, team, role, username, created_at, expires_at
+            FROM database_leases
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to fetch database leases")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DatabaseLease {
+                lease_id: row.lease_id,
+                user_id: row.user_id,
+                session_id: row.session_id,
+                team: row.team,
+                role: row.role,
+                username: row.username,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+            })
+            .collect())
+    }
+
+    /// Mark one of the user's leases as revoked. Returns `false` if they have no such active lease.
+    #[instrument(skip(self), level = "info")]
+    pub async fn mark_revoked(&self, user_id: Uuid, lease_id: &str) -> anyhow::Result<bool> {
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE database_leases
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE lease_id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            lease_id,
+            user_id
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to revoke database lease")?
+        .rows_affected();
+
+        Ok(revoked > 0)
+    }
+
+    /// Mark the leases issued to a session as revoked, returning their ids
+    #[instrument(skip(self), level = "info")]
+    pub async fn mark_session_revoked(&self, session_id: Uuid) -> anyhow::Result<Vec<String>> {
+        let lease_ids = sqlx::query_scalar!(
+            r#"
+            UPDATE database_leases
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE session_id = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            RETURNING lease_id
+            "#,
+            session_id
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to revoke session database leases")?;
+
+        Ok(lease_ids)
+    }
+
+    /// Mark all leases of a team as revoked, returning their ids
+    #[instrument(skip(self), level = "info")]
+    pub async fn mark_team_revoked(&self, team: &str) -> anyhow::Result<Vec<String>> {
+        let lease_ids = sqlx::query_scalar!(
+            r#"
+            UPDATE database_leases
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE team = $1 AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            RETURNING lease_id
+            "#,
+            team
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to revoke team database leases")?;
+
+        info!(revoked = lease_ids.len(), "Revoked team database leases");
+        Ok(lease_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn credentials(lease_id: &str) -> DatabaseCredentials {
+        DatabaseCredentials {
+            username: format!("v-{}", lease_id),
+            password: "secret".to_string(),
+            lease_id: format!("database/creds/team-Team1-member/{}", lease_id),
+            expires_at: (OffsetDateTime::now_utc().unix_timestamp() + 3600) as u64,
+            renewable: true,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_database_lease_registry(pool: PgPool) -> anyhow::Result<()> {
+        let registry = DatabaseLeaseRegistry::new(pool);
+        let user_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+        let role = "team-Team1-member";
+
+        let first = registry
+            .record(
+                user_id,
+                Some(session_id),
+                "Team1",
+                role,
+                &credentials("first"),
+            )
+            .await?;
+        registry
+            .record(user_id, None, "Team1", role, &credentials("second"))
+            .await?;
+        registry
+            .record(
+                Uuid::new_v4(),
+                None,
+                "Team2",
+                "team-Team2-member",
+                &credentials("third"),
+            )
+            .await?;
+        assert_eq!(registry.list_user_leases(user_id).await?.len(), 2);
+
+        // Logging out ends the session's credentials only
+        assert_eq!(
+            registry.mark_session_revoked(session_id).await?,
+            vec![first.lease_id.clone()]
+        );
+        assert!(!registry.mark_revoked(user_id, &first.lease_id).await?);
+        assert_eq!(registry.list_user_leases(user_id).await?.len(), 1);
+
+        assert_eq!(registry.mark_team_revoked("Team1").await?.len(), 1);
+        assert!(registry.list_user_leases(user_id).await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tracing::{info, instrument, warn};
+
+use crate::vault::transit::{key_version, Encrypted, KeyContext};
+
+use super::{users::user_key_context, IdpSyncService};
+
+impl IdpSyncService {
+    /// Re-encrypt stored ciphertexts with the latest transit key version
+    ///
+    /// Run this after rotating the key, before raising its minimum decryption version.
+    /// Rows changed while the job runs keep their newer ciphertext. Sensitive configs still
+    /// stored in plaintext, from before they were encrypted, are encrypted. Values that fail
+    /// are logged and skipped, so one bad row doesn't hold back the others. Returns the
+    /// number of rewritten values.
+    #[instrument(skip(self), level = "info")]
+    pub async fn rewrap_encrypted(&self) -> anyhow::Result<usize> {
+        let crypto = self
+            .crypto
+            .as_ref()
+            .context("Rewrapping needs a VaultCrypto")?;
+        let mut count = 0;
+
+        let users = sqlx::query!(
+            r#"
+            SELECT id, institution_id, attributes_ciphertext as "ciphertext!"
+            FROM users
+            WHERE attributes_ciphertext IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to fetch encrypted user attributes")?;
+
+        for user in users {
+            let rewrapped = match crypto
+                .rewrap(user_key_context(user.institution_id), &user.ciphertext)
+                .await
+            {
+                Ok(rewrapped) => rewrapped,
+                Err(e) => {
+                    warn!(user_id = %user.id, "Failed to rewrap user attributes: {}", e);
+                    continue;
+                }
+            };
+            if key_version(&rewrapped) == key_version(&user.ciphertext) {
+                continue;
+            }
+
+            count += sqlx::query!(
+                r#"
+                UPDATE users
+                SET attributes_ciphertext = $1
+                WHERE id = $2 AND attributes_ciphertext = $3
+                "#,
+                rewrapped,
+                user.id,
+                user.ciphertext
+            )
+            .execute(&self.db)
+            .await
+            .context("Failed to update user attributes")?
+            .rows_affected() as usize;
+        }
+
+        let configs = sqlx::query!(
+            r#"
+            SELECT key, value as "value!"
+            FROM system_configs
+            WHERE is_sensitive AND value IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to fetch sensitive system configs")?;
+
+        // All ciphertexts are rewrapped in one request
+        let ciphertexts: Vec<String> = configs
+            .iter()
+            .filter(|config| key_version(&config.value).is_some())
+            .map(|config| config.value.clone())
+            .collect();
+        let mut rewrapped_ciphertexts = crypto
+            .rewrap_batch(KeyContext::Global, &ciphertexts)
+            .await
+            .context("Failed to rewrap system configs")?
+            .into_iter();
+
+        for config in configs {
+            let rewrapped = match key_version(&config.value) {
+                Some(_) => rewrapped_ciphertexts
+                    .next()
+                    .context("Missing rewrapped system config")?,
+                // Stored before sensitive configs were encrypted
+                None => Encrypted::seal(crypto, KeyContext::Global, &config.value)
+                    .await
+                    .map(Encrypted::into_ciphertext),
+            };
+            let rewrapped = match rewrapped {
+                Ok(rewrapped) => rewrapped,
+                Err(e) => {
+                    warn!(key = %config.key, "Failed to rewrap system config: {}", e);
+                    continue;
+                }
+            };
+            if key_version(&rewrapped) == key_version(&config.value) {
+                continue;
+            }
+
+            count += sqlx::query!(
+                r#"
+                UPDATE system_configs
+                SET value = $1
+                WHERE key = $2 AND value = $3
+                "#,
+                rewrapped,
+                config.key,
+                config.value
+            )
+            .execute(&self.db)
+            .await
+            .context("Failed to update system config")?
+            .rows_affected() as usize;
+        }
+
+        info!(count, "Rewrapped encrypted values");
+        Ok(count)
+    }
+
+    /// Rewrap periodically, so rotations are applied to stored data without a manual step
+    pub fn spawn_rewrap(self: &Arc<Self>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let sync = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                if let Err(e) = sync.rewrap_encrypted().await {
+                    warn!("Failed to rewrap encrypted values: {:#}", e);
+                }
+            }
+        })
+    }
+}
@@ -8,11 +8,17 @@ use tracing::info;
 use users::CompleteUser;
 use uuid::Uuid;
 
-use crate::idp::admin::IdpAdmin;
+use crate::{idp::admin::IdpAdmin, vault::transit::VaultCrypto};
 
 pub mod users;
 pub mod teams;
 pub mod institutions;
+pub mod sessions;
+pub mod api_tokens;
+pub mod database_leases;
+pub mod client_certificates;
+pub mod configs;
+pub mod encryption;
 
 /// Service for synchronizing IdP data with the database
 pub struct IdpSyncService {
@@ -21,6 +27,8 @@ pub struct IdpSyncService {
     teams: Cache<Uuid, Arc<CompleteTeam>>,
     users: Cache<Uuid, Arc<CompleteUser>>,
     institutions: Cache<Uuid, Arc<CompleteInstitution>>,
+    // Encrypts user attributes and sensitive configs, stored in plaintext without it
+    crypto: Option<Arc<VaultCrypto>>,
 }
 
 impl IdpSyncService {
@@ -36,7 +44,13 @@ impl IdpSyncService {
         let institutions = CacheBuilder::new(500)
                 .time_to_idle(ttl)
                 .build();
-        Self { db, idp_admin, teams, users, institutions }
+        Self { db, idp_admin, teams, users, institutions, crypto: None }
+    }
+
+    /// Encrypt user attributes and sensitive configs with Vault's transit engine
+    pub fn with_crypto(mut self, crypto: Arc<VaultCrypto>) -> Self {
+        self.crypto = Some(crypto);
+        self
     }
 
     pub async fn sync_all(&self) -> anyhow::Result<()> {        
@@ -0,0 +1,383 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::Role;
+
+/// Session key holding the id of the session in the registry
+pub const SESSION_REGISTRY_KEY: &str = "aicl-oidc-session-registry-id";
+
+/// How often a session's last use is written. Revocation is still checked on every request.
+pub const LAST_SEEN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How the user authenticated
+///
+/// Only OIDC logins have sessions, API tokens are checked on every request instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMechanism {
+    Oidc,
+}
+
+impl AuthMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Oidc => "oidc",
+        }
+    }
+}
+
+/// Where a request comes from, recorded against the session
+#[derive(Debug, Clone, Default)]
+pub struct SessionClient {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// An active session in the registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub mechanism: AuthMechanism,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub last_seen_at: OffsetDateTime,
+}
+
+/// Registry of authenticated sessions, shared by all replicas through Postgres
+///
+/// Every authenticated request checks its session against the registry, so a revoked
+/// session is signed out on its next request whichever replica handles it.
+#[derive(Clone)]
+pub struct SessionRegistry {
+    db: PgPool,
+    limits: HashMap<Role, usize>,
+}
+
+impl SessionRegistry {
+    pub fn new(db: PgPool) -> Self {
+        Self { db, limits: HashMap::new() }
+    }
+
+    /// Cap the number of concurrent sessions for users with this role.
+    /// Going over the cap revokes the least recently used sessions.
+    pub fn with_limit(mut self, role: Role, max_sessions: usize) -> Self {
+        self.limits.insert(role, max_sessions);
+        self
+    }
+
+    /// Cap the sessions of the roles listed in `SESSION_LIMITS`, e.g. `student=2,captain=3`
+    pub fn with_limits_from_env(mut self) -> anyhow::Result<Self> {
+        let limits = std::env::var("SESSION_LIMITS").unwrap_or_default();
+        self.limits.extend(parse_limits(&limits)?);
+        Ok(self)
+    }
+
+    /// Record a session under the id it got at login and enforce the role's session cap
+    ///
+    /// Registering an id again changes nothing, so concurrent first requests of a session
+    /// count once against the cap. Returns `false` if the session was revoked since.
+    #[instrument(skip(self, client), level = "debug")]
+    pub async fn register(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        role: Role,
+        client: &SessionClient,
+    ) -> anyhow::Result<bool> {
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO user_sessions (id, user_id, mechanism, ip_address, user_agent)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (id) DO NOTHING
+            "#,
+            id,
+            user_id,
+            AuthMechanism::Oidc.as_str(),
+            client.ip_address,
+            client.user_agent
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to register session")?
+        .rows_affected()
+            > 0;
+
+        if !inserted {
+            let active = sqlx::query_scalar!(
+                r#"SELECT revoked_at IS NULL AS "active!" FROM user_sessions WHERE id = $1"#,
+                id
+            )
+            .fetch_optional(&self.db)
+            .await
+            .context("Failed to fetch session")?;
+            return Ok(active.unwrap_or(false));
+        }
+
+        if let Some(limit) = self.limits.get(&role) {
+            // Keep the new session and the most recently used ones
+            let revoked = sqlx::query!(
+                r#"
+                UPDATE user_sessions
+                SET revoked_at = CURRENT_TIMESTAMP
+                WHERE id IN (
+                    SELECT id FROM user_sessions
+                    WHERE user_id = $1 AND revoked_at IS NULL
+                    ORDER BY id = $2 DESC, last_seen_at DESC
+                    OFFSET $3
+                )
+                "#,
+                user_id,
+                id,
+                *limit as i64
+            )
+            .execute(&self.db)
+            .await
+            .context("Failed to enforce the session limit")?
+            .rows_affected();
+
+            if revoked > 0 {
+                info!(%user_id, revoked, limit, "Revoked sessions over the limit");
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Mark the session as used. Returns `false` if it was revoked.
+    ///
+    /// The last use is only written once per [`LAST_SEEN_INTERVAL`], so busy sessions don't
+    /// update their row on every request.
+    pub async fn touch(&self, id: Uuid, client: &SessionClient) -> anyhow::Result<bool> {
+        let active = sqlx::query!(
+            r#"
+            WITH active AS (
+                SELECT id, last_seen_at FROM user_sessions
+                WHERE id = $1 AND revoked_at IS NULL
+            ), touched AS (
+                UPDATE user_sessions
+                SET last_seen_at = CURRENT_TIMESTAMP,
+                    ip_address = COALESCE($2, ip_address),
+                    user_agent = COALESCE($3, user_agent)
+                FROM active
+                WHERE user_sessions.id = active.id
+                  AND active.last_seen_at < CURRENT_TIMESTAMP - make_interval(secs => $4)
+            )
+            SELECT id FROM active
+            "#,
+            id,
+            client.ip_address,
+            client.user_agent,
+            LAST_SEEN_INTERVAL.as_secs_f64()
+        )
+        .fetch_optional(&self.db)
+        .await
+        .context("Failed to update session")?;
+
+        Ok(active.is_some())
+    }
+
+    /// List the user's active sessions, most recently used first
+    pub async fn list_user_sessions(&self, user_id: Uuid) -> anyhow::Result<Vec<UserSession>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, user_id, ip_address, user_agent, created_at, last_seen_at
+            FROM user_sessions
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY last_seen_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+        .context("Failed to fetch user sessions")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| UserSession {
+                id: row.id,
+                user_id: row.user_id,
+                mechanism: AuthMechanism::Oidc,
+                ip_address: row.ip_address,
+                user_agent: row.user_agent,
+                created_at: row.created_at,
+                last_seen_at: row.last_seen_at,
+            })
+            .collect())
+    }
+
+    /// Revoke one of the user's sessions. Returns `false` if the user has no such active session.
+    #[instrument(skip(self), level = "info")]
+    pub async fn revoke_session(&self, user_id: Uuid, id: Uuid) -> anyhow::Result<bool> {
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE user_sessions
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            id,
+            user_id
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to revoke session")?
+        .rows_affected();
+
+        Ok(revoked > 0)
+    }
+
+    /// Revoke all of the user's sessions, signing them out everywhere
+    #[instrument(skip(self), level = "info")]
+    pub async fn revoke_all_sessions(&self, user_id: Uuid) -> anyhow::Result<u64> {
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE user_sessions
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+            user_id
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to revoke sessions")?
+        .rows_affected();
+
+        info!(revoked, "Revoked all sessions");
+        Ok(revoked)
+    }
+
+    /// Close a session on logout
+    pub async fn end_session(&self, id: Uuid) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE user_sessions
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND revoked_at IS NULL
+            "#,
+            id
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to end session")?;
+
+        Ok(())
+    }
+}
+
+/// Parse `role=max_sessions` pairs separated by commas
+fn parse_limits(limits: &str) -> anyhow::Result<HashMap<Role, usize>> {
+    let roles = [Role::Admin, Role::Advisor, Role::Captain, Role::Student, Role::Spectator];
+    limits
+        .split(',')
+        .map(str::trim)
+        .filter(|limit| !limit.is_empty())
+        .map(|limit| {
+            let (name, max_sessions) = limit
+                .split_once('=')
+                .with_context(|| format!("Invalid session limit {}, expected role=count", limit))?;
+            let role = roles
+                .into_iter()
+                .find(|role| role.as_str() == name.trim())
+                .with_context(|| format!("Unknown role {} in session limit", name))?;
+            let max_sessions = max_sessions
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid session count in {}", limit))?;
+            Ok((role, max_sessions))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_session_limit_and_revocation(pool: PgPool) -> anyhow::Result<()> {
+        let registry = SessionRegistry::new(pool).with_limit(Role::Student, 2);
+        let user_id = Uuid::new_v4();
+        let client = SessionClient {
+            ip_address: Some("127.0.0.1".to_string()),
+            user_agent: Some("test".to_string()),
+        };
+
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert!(registry.register(first, user_id, Role::Student, &client).await?);
+        assert!(registry.register(second, user_id, Role::Student, &client).await?);
+        assert!(registry.touch(second, &client).await?);
+        assert!(registry.register(third, user_id, Role::Student, &client).await?);
+        // Registering again doesn't take another place under the cap
+        assert!(registry.register(third, user_id, Role::Student, &client).await?);
+
+        // The least recently used session went over the cap
+        assert!(!registry.touch(first, &client).await?, "Oldest session should be revoked");
+        assert!(registry.touch(second, &client).await?);
+        assert!(registry.touch(third, &client).await?);
+        assert_eq!(registry.list_user_sessions(user_id).await?.len(), 2);
+
+        // Other users can't revoke the session
+        assert!(!registry.revoke_session(Uuid::new_v4(), second).await?);
+        assert!(registry.revoke_session(user_id, second).await?);
+        assert!(!registry.touch(second, &client).await?);
+        assert!(!registry.register(second, user_id, Role::Student, &client).await?);
+
+        assert_eq!(registry.revoke_all_sessions(user_id).await?, 1);
+        assert!(registry.list_user_sessions(user_id).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_touch_throttles_last_seen(pool: PgPool) -> anyhow::Result<()> {
+        let registry = SessionRegistry::new(pool.clone());
+        let user_id = Uuid::new_v4();
+        let client = SessionClient::default();
+        let id = Uuid::new_v4();
+        registry.register(id, user_id, Role::Student, &client).await?;
+        let last_seen = || async {
+            anyhow::Ok(registry.list_user_sessions(user_id).await?[0].last_seen_at)
+        };
+
+        // A recently used session isn't written again
+        let registered = last_seen().await?;
+        assert!(registry.touch(id, &client).await?);
+        assert_eq!(last_seen().await?, registered);
+
+        // Once the interval passed it is
+        sqlx::query!(
+            "UPDATE user_sessions SET last_seen_at = last_seen_at - INTERVAL '2 minutes' WHERE id = $1",
+            id
+        )
+        .execute(&pool)
+        .await?;
+        let stale = last_seen().await?;
+        assert!(registry.touch(id, &client).await?);
+        assert!(last_seen().await? > stale);
+
+        // Revocation is still seen right away
+        registry.end_session(id).await?;
+        assert!(!registry.touch(id, &client).await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let limits = parse_limits("student=2, captain = 3,").unwrap();
+        assert_eq!(limits, HashMap::from([(Role::Student, 2), (Role::Captain, 3)]));
+        assert!(parse_limits("").unwrap().is_empty());
+        assert!(parse_limits("student").is_err());
+        assert!(parse_limits("janitor=1").is_err());
+        assert!(parse_limits("student=many").is_err());
+    }
+}
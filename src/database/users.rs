@@ -7,7 +7,11 @@ use time::OffsetDateTime;
 use tracing::{debug, info, instrument};
 use uuid::Uuid;
 
-use crate::{idp::ext::IdpUser, Role};
+use crate::{
+    idp::ext::IdpUser,
+    vault::transit::{Encrypted, KeyContext},
+    Role,
+};
 
 use super::IdpSyncService;
 
@@ -71,6 +75,8 @@ impl IdpSyncService {
         // Convert attributes to JSON if any
         let attributes = serde_json::to_value(&idp_user.attributes)
             .unwrap_or_else(|_| serde_json::Value::Object(serde_json::Map::new()));
+        // With encryption configured, the attributes are only stored as ciphertext
+        let plain_attributes = self.crypto.is_none().then(|| attributes.clone());
         
         // Check if user already exists in idp_entities
         let entity_exists = sqlx::query!(
@@ -102,7 +108,7 @@ impl IdpSyncService {
                     "#,
                     idp_user.username,
                     idp_user.email,
-                    attributes,
+                    plain_attributes,
                     idp_user.id
                 )
                 .execute(&mut **tx)
@@ -124,7 +130,7 @@ impl IdpSyncService {
                     idp_user.id,
                     idp_user.username,
                     idp_user.email,
-                    attributes
+                    plain_attributes
                 )
                 .fetch_one(&mut **tx)
                 .await
@@ -136,7 +142,14 @@ impl IdpSyncService {
         // Convert to a domain user to get team and institution information
         let domain_user = self.idp_admin.to_domain_user(idp_user).await
             .context("Failed to convert to domain user")?;
-
+        let attributes_ciphertext = match &self.crypto {
+            Some(crypto) => {
+                let context = user_key_context(domain_user.institution.as_ref().map(|i| i.id));
+                Some(Encrypted::seal(crypto, context, &attributes).await
+                    .context("Failed to encrypt user attributes")?)
+            },
+            None => None,
+        };
 
         // Check if user entry already exists
         let user_exists = sqlx::query!(
@@ -168,18 +181,20 @@ impl IdpSyncService {
                         attributes = $6,
                         role = $7,
                         team_id = $8,
-                        institution_id = $9
-                    WHERE id = $10
+                        institution_id = $9,
+                        attributes_ciphertext = $10
+                    WHERE id = $11
                     "#,
                     idp_user.username,
                     idp_user.email,
                     idp_user.first_name,
                     idp_user.last_name,
                     idp_user.enabled,
-                    attributes,
+                    plain_attributes,
                     domain_user.role.as_str(),
                     domain_user.team.as_ref().map(|t| t.id),
                     domain_user.institution.as_ref().map(|t| t.id),
+                    attributes_ciphertext.as_ref().map(Encrypted::ciphertext),
                     record.id
                 )
                 .execute(&mut **tx)
@@ -194,8 +209,8 @@ impl IdpSyncService {
                 sqlx::query!(
                     r#"
                     INSERT INTO users 
-                    (entity_id, username, email, first_name, last_name, enabled, attributes, role, team_id, institution_id)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                    (entity_id, username, email, first_name, last_name, enabled, attributes, role, team_id, institution_id, attributes_ciphertext)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
                     RETURNING id
                     "#,
                     entity_id,
@@ -204,10 +219,11 @@ impl IdpSyncService {
                     idp_user.first_name,
                     idp_user.last_name,
                     idp_user.enabled,
-                    attributes,
+                    plain_attributes,
                     domain_user.role.as_str(),
                     domain_user.team.as_ref().map(|t| t.id),
                     domain_user.institution.as_ref().map(|t| t.id),
+                    attributes_ciphertext.as_ref().map(Encrypted::ciphertext),
                 )
                 .fetch_one(&mut **tx)
                 .await
@@ -240,6 +256,7 @@ impl IdpSyncService {
                 u.role,
                 u.team_id,
                 u.institution_id,
+                u.attributes_ciphertext as "attributes_ciphertext: Encrypted<serde_json::Value>",
                 u.created_at,
                 u.updated_at
             FROM users u
@@ -252,7 +269,14 @@ impl IdpSyncService {
         .context("Failed to fetch user data")?;
 
         // Construct the complete user
-        let attributes = user.attributes.unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new()));
+        let attributes = match (user.attributes_ciphertext, &self.crypto) {
+            (Some(ciphertext), Some(crypto)) => ciphertext
+                .open(crypto, user_key_context(user.institution_id))
+                .await
+                .context("Failed to decrypt user attributes")?,
+            (Some(_), None) => anyhow::bail!("User attributes are encrypted, but no VaultCrypto is configured"),
+            (None, _) => user.attributes.unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new())),
+        };
         let role = Role::parse(&user.role);
 
         Ok(CompleteUser {
@@ -382,6 +406,11 @@ impl IdpSyncService {
     }
 }
 
+/// User attributes are encrypted under their institution's key, if they have one
+pub(crate) fn user_key_context(institution_id: Option<Uuid>) -> KeyContext {
+    institution_id.map_or(KeyContext::Global, KeyContext::Institution)
+}
+
 fn are_users_equal(a: &Arc<CompleteUser>, b: &Arc<CompleteUser>) -> bool {
     a.id == b.id &&
     a.username == b.username &&
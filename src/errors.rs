@@ -1,9 +1,16 @@
+use axum::http::{header::ACCEPT, HeaderMap, HeaderValue};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use std::fmt;
+use minijinja::Environment;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+};
 use thiserror::Error;
+use uuid::Uuid;
 
 use crate::{
     idp::ext::IdpError,
@@ -28,6 +35,9 @@ pub enum AppError {
     #[error("Authentication error: {0}")]
     Authentication(#[from] OidcError),
 
+    #[error("Authentication required")]
+    LoginRequired { login_url: String },
+
     #[error("Authorization error: {0}")]
     Authorization(String),
 
@@ -67,11 +77,21 @@ pub enum AppError {
 impl AppError {
     pub fn status_code(&self) -> StatusCode {
         match self {
-            Self::Authentication(_) => StatusCode::UNAUTHORIZED,
+            Self::Authentication(_) | Self::LoginRequired { .. } => StatusCode::UNAUTHORIZED,
+            Self::VerificationError(VerificationError::Malformed(_)) => StatusCode::BAD_REQUEST,
+            Self::VerificationError(VerificationError::Unavailable(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             Self::VerificationError(_) => StatusCode::UNAUTHORIZED,
             Self::Authorization(_) => StatusCode::FORBIDDEN,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Vault(VaultError::ScopeNotAllowed(_)) => StatusCode::FORBIDDEN,
+            Self::Vault(VaultError::InvalidScope(_)) => StatusCode::BAD_REQUEST,
+            Self::Vault(VaultError::Unauthorized(_)) => StatusCode::FORBIDDEN,
+            Self::Vault(VaultError::SecretNotFound) => StatusCode::NOT_FOUND,
+            Self::Vault(VaultError::InvalidSecretName(_)) => StatusCode::BAD_REQUEST,
+            Self::Vault(VaultError::WrappingTokenInvalid) => StatusCode::GONE,
             Self::Session(_)
             | Self::Vault(_)
             | Self::IdentityProvider(_)
@@ -82,6 +102,40 @@ impl AppError {
     }
 }
 
+impl IdentifierError {
+    /// Stable, machine-readable code for this error, suitable for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Uuid(_) => "identifier.invalid_uuid",
+            Self::IdpError(e) => e.code(),
+            Self::EmptyIdentifier => "identifier.empty",
+        }
+    }
+}
+
+impl AppError {
+    /// Stable, machine-readable code for this error.
+    ///
+    /// Wrapped provider errors report the code of the inner variant so clients can
+    /// distinguish e.g. `idp.not_found` from `idp.network_error`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Authentication(e) => e.code(),
+            Self::LoginRequired { .. } => "auth.login_required",
+            Self::Authorization(_) => "authorization.forbidden",
+            Self::VerificationError(e) => e.code(),
+            Self::IdentityProvider(e) => e.code(),
+            Self::Vault(e) => e.code(),
+            Self::Identifier(e) => e.code(),
+            Self::Session(_) => "session.error",
+            Self::NotFound(_) => "not_found",
+            Self::BadRequest(_) => "bad_request",
+            Self::InternalServer(_) => "internal_error",
+            Self::ServiceError { .. } => "service_error",
+        }
+    }
+}
+
 // Default error handler that converts AppError to Response
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
@@ -92,9 +146,48 @@ impl IntoResponse for AppError {
     }
 }
 
+/// Header carrying the id of a request, e.g. set by a proxy or tower-http's `SetRequestIdLayer`
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Request information available to error handlers
+///
+/// Captured by [`crate::ErrorHandlerExtensionLayer`] for every request, so handlers can
+/// adapt the response to the client (e.g. content negotiation).
+#[derive(Debug, Clone, Default)]
+pub struct ErrorContext {
+    pub accept: Option<HeaderValue>,
+    /// The request's `x-request-id`, reported as the correlation id of its errors
+    pub request_id: Option<String>,
+    /// The route accepts bearer tokens, so a `401` has to carry a bearer challenge
+    pub bearer_auth: bool,
+}
+
+impl ErrorContext {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            accept: headers.get(ACCEPT).cloned(),
+            request_id: headers
+                .get(REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok())
+                // Ends up in logs and response bodies, so only take reasonable ids
+                .filter(|id| !id.is_empty() && id.len() <= 128)
+                .map(str::to_string),
+            bearer_auth: false,
+        }
+    }
+}
+
 /// Error handler trait that can be implemented for different error handling strategies
 pub trait ErrorHandler: Send + Sync + 'static {
     fn handle_error(&self, error: AppError) -> Response;
+
+    /// Handle an error with access to the request context.
+    ///
+    /// Handlers that don't care about the request only need to implement `handle_error`.
+    fn handle_error_with_context(&self, error: AppError, context: &ErrorContext) -> Response {
+        let _ = context;
+        self.handle_error(error)
+    }
 }
 
 /// Default error handler implementation
@@ -127,18 +220,91 @@ impl ErrorHandler for DefaultErrorHandler {
 }
 
 /// HTML templated error handler implementation
+///
+/// Templates are loaded from `template_path`. For a given error the handler tries
+/// `<status>.html` (e.g. `404.html`) and then `error.html`. Templates receive `status`,
+/// `title`, `code` and `message`. Debug builds re-read templates on every render so they
+/// can be edited while the server runs; release builds load them once per template path. A
+/// missing or broken template falls back to a built-in page.
 #[derive(Clone)]
 pub struct HtmlErrorHandler {
     pub template_path: String,
 }
 
-impl ErrorHandler for HtmlErrorHandler {
-    fn handle_error(&self, error: AppError) -> Response {
-        let status = error.status_code();
+// Template environments of release builds by template path, shared by all handlers
+static TEMPLATE_ENVIRONMENTS: OnceLock<Mutex<HashMap<String, Arc<Environment<'static>>>>> =
+    OnceLock::new();
+
+impl HtmlErrorHandler {
+    pub fn new(template_path: impl Into<String>) -> Self {
+        Self {
+            template_path: template_path.into(),
+        }
+    }
+
+    fn load_environment(&self) -> Arc<Environment<'static>> {
+        let mut env = Environment::new();
+        env.set_loader(minijinja::path_loader(self.template_path.clone()));
+        Arc::new(env)
+    }
+
+    fn environment(&self) -> Arc<Environment<'static>> {
+        if cfg!(debug_assertions) {
+            return self.load_environment();
+        }
+        let mut environments = TEMPLATE_ENVIRONMENTS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        environments
+            .entry(self.template_path.clone())
+            .or_insert_with(|| self.load_environment())
+            .clone()
+    }
+
+    fn render(
+        &self,
+        env: &Environment<'static>,
+        status: StatusCode,
+        error: &AppError,
+    ) -> Option<String> {
+        let message = if status.is_server_error() {
+            "An unexpected error occurred".to_string()
+        } else {
+            error.to_string()
+        };
+        let ctx = minijinja::context! {
+            status => status.as_u16(),
+            title => status.canonical_reason().unwrap_or("Error"),
+            code => error.code(),
+            message => message,
+        };
+
+        for name in [
+            format!("{}.html", status.as_u16()),
+            "error.html".to_string(),
+        ] {
+            let template = match env.get_template(&name) {
+                Ok(template) => template,
+                Err(e) if e.kind() == minijinja::ErrorKind::TemplateNotFound => continue,
+                Err(e) => {
+                    tracing::error!(template = name, error = %e, "Failed to load error template");
+                    return None;
+                }
+            };
+            return match template.render(&ctx) {
+                Ok(html) => Some(html),
+                Err(e) => {
+                    tracing::error!(template = name, error = %e, "Failed to render error template");
+                    None
+                }
+            };
+        }
+        None
+    }
 
-        // Here you would typically render an HTML template with the error details
-        // This is a simplified example - you would integrate with your template engine
-        let html = format!(
+    fn fallback(status: StatusCode, error: &AppError) -> String {
+        format!(
             r#"<!DOCTYPE html>
             <html>
             <head><title>Error</title></head>
@@ -148,16 +314,35 @@ impl ErrorHandler for HtmlErrorHandler {
             </body>
             </html>"#,
             status.as_u16(),
-            if status == StatusCode::INTERNAL_SERVER_ERROR {
+            if status.is_server_error() {
                 "An unexpected error occurred".to_string()
             } else {
-                error.to_string()
+                html_escape(&error.to_string())
             }
-        );
+        )
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
+
+impl ErrorHandler for HtmlErrorHandler {
+    fn handle_error(&self, error: AppError) -> Response {
+        let status = error.status_code();
+
+        let html = self
+            .render(&self.environment(), status, &error)
+            .unwrap_or_else(|| Self::fallback(status, &error));
 
         (
             status,
-            [(axum::http::header::CONTENT_TYPE, "text/html")],
+            [(axum::http::header::CONTENT_TYPE, "text/html; charset=utf-8")],
             html,
         )
             .into_response()
@@ -182,7 +367,7 @@ impl ErrorHandler for JsonErrorHandler {
     fn handle_error(&self, error: AppError) -> Response {
         let status = error.status_code();
 
-        let json_body = if self.include_details {
+        let mut json_body = if self.include_details {
             // Include detailed error information in development
             serde_json::json!({
                 "error": {
@@ -206,6 +391,9 @@ impl ErrorHandler for JsonErrorHandler {
                 }
             })
         };
+        if let AppError::LoginRequired { login_url } = &error {
+            json_body["error"]["login_url"] = serde_json::Value::String(login_url.clone());
+        }
 
         (
             status,
@@ -216,12 +404,301 @@ impl ErrorHandler for JsonErrorHandler {
     }
 }
 
+/// RFC 7807 `application/problem+json` error handler
+///
+/// Every response carries the stable [`AppError::code`] and a correlation id that is
+/// also logged with the error inside the current tracing span, so a client report can
+/// be matched to the server logs. The correlation id is the request's `x-request-id` when
+/// it has one. Titles default to the HTTP reason phrase and can be
+/// overridden (e.g. localized) per code with [`ProblemJsonErrorHandler::with_title`].
+#[derive(Clone, Default)]
+pub struct ProblemJsonErrorHandler {
+    pub include_details: bool,
+    /// Base URL for the problem `type` member. `about:blank` is used when unset.
+    pub type_base_url: Option<String>,
+    /// Realm advertised in `WWW-Authenticate` challenges on bearer token failures
+    pub bearer_realm: Option<String>,
+    pub titles: HashMap<&'static str, String>,
+}
+
+impl ProblemJsonErrorHandler {
+    pub fn with_details(mut self, include_details: bool) -> Self {
+        self.include_details = include_details;
+        self
+    }
+
+    pub fn with_type_base_url(mut self, type_base_url: impl Into<String>) -> Self {
+        self.type_base_url = Some(type_base_url.into());
+        self
+    }
+
+    pub fn with_bearer_realm(mut self, realm: impl Into<String>) -> Self {
+        self.bearer_realm = Some(realm.into());
+        self
+    }
+
+    /// Override the title for a given error code
+    pub fn with_title(mut self, code: &'static str, title: impl Into<String>) -> Self {
+        self.titles.insert(code, title.into());
+        self
+    }
+
+    fn problem_type(&self, code: &str) -> String {
+        match &self.type_base_url {
+            Some(base) => format!("{}/{}", base.trim_end_matches('/'), code),
+            None => "about:blank".to_string(),
+        }
+    }
+
+    fn title(&self, code: &str, status: StatusCode) -> String {
+        match self.titles.get(code) {
+            Some(title) => title.clone(),
+            None => status
+                .canonical_reason()
+                .unwrap_or("Unknown error")
+                .to_string(),
+        }
+    }
+
+    fn detail(&self, error: &AppError, status: StatusCode) -> Option<String> {
+        if self.include_details || !status.is_server_error() {
+            Some(error.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Builds the `WWW-Authenticate` challenge for bearer token failures (RFC 6750)
+    ///
+    /// Any other `401` on a route taking bearer tokens, e.g. for a missing token, gets the
+    /// bare challenge without an error code.
+    fn bearer_challenge(&self, error: &AppError, context: &ErrorContext) -> Option<String> {
+        let bare = || match &self.bearer_realm {
+            Some(realm) => format!("Bearer realm=\"{}\"", quote_escape(realm)),
+            None => "Bearer".to_string(),
+        };
+        let (error_code, description) = match error {
+            AppError::VerificationError(VerificationError::Expired) => {
+                ("invalid_token", "The access token expired")
+            }
+            AppError::VerificationError(VerificationError::Revoked) => {
+                ("invalid_token", "The access token was revoked")
+            }
+            AppError::VerificationError(VerificationError::Malformed(_)) => {
+                ("invalid_request", "The access token is malformed")
+            }
+            AppError::VerificationError(VerificationError::Invalid(_)) => {
+                ("invalid_token", "The access token is invalid or expired")
+            }
+            _ if context.bearer_auth && error.status_code() == StatusCode::UNAUTHORIZED => {
+                return Some(bare())
+            }
+            _ => return None,
+        };
+        let mut challenge = String::from("Bearer ");
+        if let Some(realm) = &self.bearer_realm {
+            challenge.push_str(&format!("realm=\"{}\", ", quote_escape(realm)));
+        }
+        challenge.push_str(&format!(
+            "error=\"{}\", error_description=\"{}\"",
+            error_code, description
+        ));
+        Some(challenge)
+    }
+}
+
+fn quote_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl ErrorHandler for ProblemJsonErrorHandler {
+    fn handle_error(&self, error: AppError) -> Response {
+        self.handle_error_with_context(error, &ErrorContext::default())
+    }
+
+    fn handle_error_with_context(&self, error: AppError, context: &ErrorContext) -> Response {
+        let status = error.status_code();
+        let code = error.code();
+        // Reuse the id the request came with, so proxies, logs and the client agree
+        let correlation_id = context
+            .request_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        if status.is_server_error() {
+            tracing::error!(%correlation_id, code, %error, "Request failed");
+        } else {
+            tracing::debug!(%correlation_id, code, %error, "Request rejected");
+        }
+
+        let mut body = serde_json::json!({
+            "type": self.problem_type(code),
+            "title": self.title(code, status),
+            "status": status.as_u16(),
+            "code": code,
+            "correlation_id": correlation_id,
+        });
+        if let Some(detail) = self.detail(&error, status) {
+            body["detail"] = serde_json::Value::String(detail);
+        }
+        // Extension member telling the client where to send the user to log in
+        if let AppError::LoginRequired { login_url } = &error {
+            body["login_url"] = serde_json::Value::String(login_url.clone());
+        }
+
+        let mut response = (
+            status,
+            [(axum::http::header::CONTENT_TYPE, "application/problem+json")],
+            serde_json::to_string(&body).unwrap(),
+        )
+            .into_response();
+
+        if let Some(challenge) = self.bearer_challenge(&error, context) {
+            if let Ok(value) = axum::http::HeaderValue::from_str(&challenge) {
+                response
+                    .headers_mut()
+                    .insert(axum::http::header::WWW_AUTHENTICATE, value);
+            }
+        }
+
+        response
+    }
+}
+
+/// Response formats a [`NegotiatingErrorHandler`] can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl ErrorFormat {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "text/html" | "application/xhtml+xml" => Some(Self::Html),
+            "application/json" | "application/problem+json" => Some(Self::Json),
+            "text/plain" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    /// Pick the best format for an `Accept` header value, honouring q-values.
+    ///
+    /// Wildcards and unknown or missing headers select `default`.
+    pub fn negotiate(accept: Option<&HeaderValue>, default: Self) -> Self {
+        let accept = match accept.and_then(|value| value.to_str().ok()) {
+            Some(accept) => accept,
+            None => return default,
+        };
+
+        let mut best: Option<(Self, f32)> = None;
+        for entry in accept.split(',') {
+            let mut params = entry.split(';');
+            let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+            let format = match media_type.as_str() {
+                "*/*" => default,
+                "text/*" if default == Self::Json => Self::Text,
+                "text/*" => default,
+                "application/*" => Self::Json,
+                other => match Self::from_media_type(other) {
+                    Some(format) => format,
+                    None => continue,
+                },
+            };
+            if best.is_none_or(|(_, q)| quality > q) {
+                best = Some((format, quality));
+            }
+        }
+
+        best.map(|(format, _)| format).unwrap_or(default)
+    }
+}
+
+/// Error handler that picks HTML, JSON or plain text from the request's `Accept` header
+pub struct NegotiatingErrorHandler {
+    html: Box<dyn ErrorHandler>,
+    json: Box<dyn ErrorHandler>,
+    text: Box<dyn ErrorHandler>,
+    default_format: ErrorFormat,
+}
+
+impl NegotiatingErrorHandler {
+    /// Create a negotiating handler rendering HTML from `template_path`, JSON as
+    /// `application/problem+json` and plain text without details.
+    pub fn new(template_path: impl Into<String>) -> Self {
+        Self {
+            html: Box::new(HtmlErrorHandler::new(template_path)),
+            json: Box::new(ProblemJsonErrorHandler::default()),
+            text: Box::new(DefaultErrorHandler {
+                include_details: false,
+            }),
+            default_format: ErrorFormat::Json,
+        }
+    }
+
+    pub fn with_html<H: ErrorHandler>(mut self, handler: H) -> Self {
+        self.html = Box::new(handler);
+        self
+    }
+
+    pub fn with_json<H: ErrorHandler>(mut self, handler: H) -> Self {
+        self.json = Box::new(handler);
+        self
+    }
+
+    pub fn with_text<H: ErrorHandler>(mut self, handler: H) -> Self {
+        self.text = Box::new(handler);
+        self
+    }
+
+    /// Format used when the client sends no `Accept` header or only wildcards
+    pub fn with_default_format(mut self, format: ErrorFormat) -> Self {
+        self.default_format = format;
+        self
+    }
+
+    fn handler_for(&self, format: ErrorFormat) -> &dyn ErrorHandler {
+        match format {
+            ErrorFormat::Html => self.html.as_ref(),
+            ErrorFormat::Json => self.json.as_ref(),
+            ErrorFormat::Text => self.text.as_ref(),
+        }
+    }
+}
+
+impl ErrorHandler for NegotiatingErrorHandler {
+    fn handle_error(&self, error: AppError) -> Response {
+        self.handler_for(self.default_format).handle_error(error)
+    }
+
+    fn handle_error_with_context(&self, error: AppError, context: &ErrorContext) -> Response {
+        let format = ErrorFormat::negotiate(context.accept.as_ref(), self.default_format);
+        self.handler_for(format)
+            .handle_error_with_context(error, context)
+    }
+}
+
 // Helper methods to create AppErrors from strings
 impl AppError {
     pub fn unauthorized(message: impl fmt::Display) -> Self {
         Self::Authentication(OidcError::AuthenticationError(message.to_string()))
     }
 
+    /// Authentication is required, the client should send the user to `login_url`
+    pub fn login_required(login_url: impl Into<String>) -> Self {
+        Self::LoginRequired {
+            login_url: login_url.into(),
+        }
+    }
+
     pub fn forbidden(message: impl fmt::Display) -> Self {
         Self::Authorization(message.to_string())
     }
@@ -242,3 +719,172 @@ impl AppError {
         Self::Session(message.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn problem_body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_negotiate_error_format() {
+        let negotiate = |accept: &str| {
+            ErrorFormat::negotiate(
+                Some(&HeaderValue::from_str(accept).unwrap()),
+                ErrorFormat::Json,
+            )
+        };
+
+        assert_eq!(
+            ErrorFormat::negotiate(None, ErrorFormat::Json),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            negotiate("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            ErrorFormat::Html
+        );
+        assert_eq!(negotiate("application/json"), ErrorFormat::Json);
+        assert_eq!(negotiate("text/plain"), ErrorFormat::Text);
+        assert_eq!(negotiate("text/html;q=0.5, text/plain"), ErrorFormat::Text);
+        assert_eq!(negotiate("text/html;q=0, */*"), ErrorFormat::Json);
+        assert_eq!(negotiate("image/png"), ErrorFormat::Json);
+    }
+
+    #[tokio::test]
+    async fn test_html_handler_falls_back_without_templates() {
+        let handler = HtmlErrorHandler {
+            template_path: "/nonexistent/templates".to_string(),
+        };
+        let response = handler.handle_error(AppError::not_found("<script>"));
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(html.contains("Error 404"));
+        assert!(html.contains("&lt;script&gt;"));
+
+        // Server errors other than 500 don't leak their details either
+        let response = handler.handle_error(AppError::VerificationError(
+            VerificationError::Unavailable("vault.internal:8200 refused".to_string()),
+        ));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(!html.contains("vault.internal"));
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_forbidden() {
+        let handler = ProblemJsonErrorHandler::default()
+            .with_type_base_url("https://errors.example.com/")
+            .with_title("authorization.forbidden", "Zugriff verweigert");
+        let response = handler.handle_error(AppError::forbidden("Not your team"));
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[axum::http::header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert!(response
+            .headers()
+            .get(axum::http::header::WWW_AUTHENTICATE)
+            .is_none());
+
+        let body = problem_body(response).await;
+        assert_eq!(body["status"], 403);
+        assert_eq!(body["code"], "authorization.forbidden");
+        assert_eq!(
+            body["type"],
+            "https://errors.example.com/authorization.forbidden"
+        );
+        assert_eq!(body["title"], "Zugriff verweigert");
+        assert!(body["detail"].as_str().unwrap().contains("Not your team"));
+        assert!(body["correlation_id"].as_str().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_hides_server_error_details() {
+        let handler = ProblemJsonErrorHandler::default();
+        let response = handler.handle_error(AppError::IdentityProvider(IdpError::NetworkError(
+            "keycloak:8080 refused connection".to_string(),
+        )));
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = problem_body(response).await;
+        assert_eq!(body["code"], "idp.network_error");
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Internal Server Error");
+        assert!(body.get("detail").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_token_verification_errors() {
+        let handler = ProblemJsonErrorHandler::default().with_bearer_realm("aicl");
+
+        let response =
+            handler.handle_error(AppError::VerificationError(VerificationError::Expired));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[axum::http::header::WWW_AUTHENTICATE],
+            "Bearer realm=\"aicl\", error=\"invalid_token\", \
+             error_description=\"The access token expired\""
+        );
+        assert_eq!(problem_body(response).await["code"], "token.expired");
+
+        let response = handler.handle_error(AppError::VerificationError(
+            VerificationError::Malformed("Token contains invalid characters".to_string()),
+        ));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(problem_body(response).await["code"], "token.malformed");
+
+        // An outage is not the client's fault, so there is no challenge
+        let response = handler.handle_error(AppError::VerificationError(
+            VerificationError::Unavailable("connection refused".to_string()),
+        ));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response
+            .headers()
+            .get(axum::http::header::WWW_AUTHENTICATE)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_request_context() {
+        let handler = ProblemJsonErrorHandler::default().with_bearer_realm("aicl");
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("req-42"));
+        let context = ErrorContext {
+            bearer_auth: true,
+            ..ErrorContext::from_headers(&headers)
+        };
+
+        // A missing token on a bearer route gets the challenge without an error code
+        let response = handler
+            .handle_error_with_context(AppError::unauthorized("No API token given"), &context);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[axum::http::header::WWW_AUTHENTICATE],
+            "Bearer realm=\"aicl\""
+        );
+        assert_eq!(problem_body(response).await["correlation_id"], "req-42");
+
+        // Routes without bearer tokens don't challenge
+        let response = handler.handle_error_with_context(
+            AppError::unauthorized("Not logged in"),
+            &ErrorContext::from_headers(&headers),
+        );
+        assert!(response
+            .headers()
+            .get(axum::http::header::WWW_AUTHENTICATE)
+            .is_none());
+    }
+}
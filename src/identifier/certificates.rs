@@ -0,0 +1,164 @@
+use uuid::Uuid;
+
+use crate::{
+    database::client_certificates::ClientCertificateRecord,
+    errors::AppError,
+    vault::pki::{certificate_serial, IssuedCertificate, VaultCertificate},
+    AiclIdentifier, AiclIdentity, Role,
+};
+
+/// The registry entry of a client certificate, if it is registered, active and matches the
+/// certificate Vault issued under its serial number
+pub(crate) fn check_client_certificate(
+    der: &[u8],
+    record: Option<ClientCertificateRecord>,
+    issued: Option<&VaultCertificate>,
+) -> Result<ClientCertificateRecord, AppError> {
+    let record = record
+        .ok_or_else(|| AppError::unauthorized("Unknown, expired or revoked client certificate"))?;
+    match issued {
+        Some(certificate) if certificate.revoked => {
+            Err(AppError::unauthorized("Client certificate was revoked"))
+        }
+        Some(certificate) if certificate.der == der => Ok(record),
+        _ => Err(AppError::unauthorized(
+            "Client certificate was not issued by Vault",
+        )),
+    }
+}
+
+// Certificates stop working once their user leaves the team they were issued for
+pub(crate) fn check_certificate_team(
+    record: &ClientCertificateRecord,
+    identity: &AiclIdentity,
+) -> Result<(), AppError> {
+    match &identity.team {
+        Some(team) if team.name == record.team => Ok(()),
+        _ => Err(AppError::forbidden("The certificate's user left its team")),
+    }
+}
+
+impl AiclIdentifier {
+    /// Issue a client certificate for an agent of the captain's team and record it
+    ///
+    /// The certificate authenticates as `user_id`, a member of the team, or as the captain
+    /// when it is `None`. Returns the certificate with its private key, which is shown once
+    /// and never stored, and its registry entry.
+    pub async fn issue_client_certificate(
+        &self,
+        captain: &AiclIdentity,
+        user_id: Option<Uuid>,
+        name: Option<&str>,
+        ttl: Option<std::time::Duration>,
+    ) -> Result<(IssuedCertificate, ClientCertificateRecord), AppError> {
+        let team = match (&captain.team, captain.role) {
+            (Some(team), Role::Captain) => team,
+            _ => {
+                return Err(AppError::forbidden(
+                    "Only captains can issue client certificates",
+                ))
+            }
+        };
+        let user = match user_id {
+            Some(user_id) if user_id != captain.id => self.idp.get_domain_user(user_id).await?,
+            _ => captain.clone(),
+        };
+        if user.team.as_ref().map(|t| t.id) != Some(team.id) {
+            return Err(AppError::forbidden("The user is not a member of the team"));
+        }
+        if name.is_some_and(|name| name.len() > 255) {
+            return Err(AppError::bad_request(
+                "Certificate name must be at most 255 characters",
+            ));
+        }
+
+        let certificate = self
+            .vault
+            .issue_client_certificate(team, &user, ttl)
+            .await?;
+        let record = match self
+            .certificates
+            .record(user.id, &team.name, captain.id, name, &certificate)
+            .await
+        {
+            Ok(record) => record,
+            Err(e) => {
+                // Don't leave a certificate behind that nobody can revoke
+                if let Err(e) = self
+                    .vault
+                    .revoke_client_certificate(&certificate.serial_number)
+                    .await
+                {
+                    tracing::error!("Failed to revoke an unrecorded client certificate: {}", e);
+                }
+                return Err(AppError::internal_error(e));
+            }
+        };
+
+        Ok((certificate, record))
+    }
+
+    /// Revoke one of the captain's team certificates by serial number
+    ///
+    /// Returns `false` if the team has no such active certificate.
+    pub async fn revoke_client_certificate(
+        &self,
+        captain: &AiclIdentity,
+        serial_number: &str,
+    ) -> Result<bool, AppError> {
+        let team = match (&captain.team, captain.role) {
+            (Some(team), Role::Captain) => team,
+            _ => {
+                return Err(AppError::forbidden(
+                    "Only captains can revoke client certificates",
+                ))
+            }
+        };
+        if !self
+            .certificates
+            .mark_revoked(&team.name, serial_number)
+            .await
+            .map_err(AppError::internal_error)?
+        {
+            return Ok(false);
+        }
+        self.certificate_identities.invalidate(serial_number).await;
+
+        // The registry already refuses the certificate, Vault adds it to the CRL
+        self.vault.revoke_client_certificate(serial_number).await?;
+        Ok(true)
+    }
+
+    /// The identity a verified client certificate, in DER, authenticates as
+    ///
+    /// The certificate must be in the registry, known to Vault with the same contents, not
+    /// revoked, and its user must still be in the team it was issued for. The outcome is
+    /// reused for [`CERTIFICATE_CACHE_TTL`](crate::vault::CERTIFICATE_CACHE_TTL), revoking through this replica ends it right away.
+    pub async fn certificate_identity(&self, der: &[u8]) -> Result<AiclIdentity, AppError> {
+        let serial_number = certificate_serial(der)
+            .ok_or_else(|| AppError::bad_request("Malformed client certificate"))?;
+        if let Some((cached, identity)) = self.certificate_identities.get(&serial_number).await {
+            if cached == der {
+                return Ok(identity);
+            }
+        }
+
+        let record = self
+            .certificates
+            .get_active(&serial_number)
+            .await
+            .map_err(AppError::internal_error)?;
+        let issued = match record {
+            Some(_) => self.vault.client_certificate(&serial_number).await?,
+            None => None,
+        };
+        let record = check_client_certificate(der, record, issued.as_ref())?;
+
+        let identity = self.idp.get_domain_user(record.user_id).await?;
+        check_certificate_team(&record, &identity)?;
+        self.certificate_identities
+            .insert(serial_number, (der.to_vec(), identity.clone()))
+            .await;
+        Ok(identity)
+    }
+}
@@ -0,0 +1,86 @@
+use uuid::Uuid;
+
+use crate::{
+    database::database_leases::DatabaseLease, errors::AppError, oidc::keycloak::KeyCloakToken,
+    vault::database::DatabaseCredentials, AiclIdentifier, AiclIdentity,
+};
+
+impl AiclIdentifier {
+    /// Issue Postgres credentials for the user's team and record their lease
+    ///
+    /// Credentials issued to a session, `session_id` being its id in the session registry,
+    /// are revoked when it logs out.
+    pub async fn issue_database_credentials(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+        session_id: Option<Uuid>,
+    ) -> Result<(DatabaseCredentials, DatabaseLease), AppError> {
+        let (team, role) = self.vault.team_database_role(identity)?;
+        let credentials = self
+            .vault
+            .team_database_credentials(identity, oidc_token)
+            .await?;
+        let lease = match self
+            .leases
+            .record(identity.id, session_id, &team, &role, &credentials)
+            .await
+        {
+            Ok(lease) => lease,
+            Err(e) => {
+                // Don't leave credentials behind that nobody can revoke
+                self.revoke_leases(&[credentials.lease_id]).await;
+                return Err(AppError::internal_error(e));
+            }
+        };
+
+        Ok((credentials, lease))
+    }
+
+    /// Revoke one of the user's database credentials by lease id
+    ///
+    /// Returns `false` if the user has no such active lease.
+    pub async fn revoke_database_credentials(
+        &self,
+        user_id: Uuid,
+        lease_id: &str,
+    ) -> Result<bool, AppError> {
+        let leases = self
+            .leases
+            .list_user_leases(user_id)
+            .await
+            .map_err(AppError::internal_error)?;
+        if !leases.iter().any(|lease| lease.lease_id == lease_id) {
+            return Ok(false);
+        }
+
+        self.vault.revoke_lease(lease_id).await?;
+        self.leases
+            .mark_revoked(user_id, lease_id)
+            .await
+            .map_err(AppError::internal_error)
+    }
+
+    /// Revoke all database credentials of a team, e.g. when the team is removed
+    ///
+    /// [`Self::reconcile_vault_roles`] does so for the teams whose roles it deletes.
+    /// Returns how many were revoked.
+    pub async fn revoke_team_database_credentials(&self, team: &str) -> Result<u64, AppError> {
+        let lease_ids = self
+            .leases
+            .mark_team_revoked(team)
+            .await
+            .map_err(AppError::internal_error)?;
+        self.revoke_leases(&lease_ids).await;
+        Ok(lease_ids.len() as u64)
+    }
+
+    // Leases that fail to revoke still end when they expire, so failures are only logged
+    pub(crate) async fn revoke_leases(&self, lease_ids: &[String]) {
+        for lease_id in lease_ids {
+            if let Err(e) = self.vault.revoke_lease(lease_id).await {
+                tracing::error!(lease_id, "Failed to revoke database lease: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub(crate) mod certificates;
+mod leases;
+mod rotation;
+mod wrapping;
@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use crate::{
+    errors::AppError, idp::admin::IdpAdmin, oidc::keycloak::KeycloakOidcProvider,
+    vault::VaultService, AiclIdentifier, AiclIdentity,
+};
+
+impl AiclIdentifier {
+    /// Apply changes to the IdP config in Vault while running
+    ///
+    /// The config's version is checked every `every`. When it changed, the OIDC client
+    /// secret is swapped and the admin connection re-established with the new credentials.
+    /// A config that fails to apply keeps the current one in place until the next change.
+    pub fn spawn_idp_config_watch(
+        &self,
+        every: std::time::Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let vault = Arc::downgrade(&self.vault);
+        let oidc = self.oidc.clone();
+        let idp = self.idp.clone();
+        tokio::spawn(async move {
+            let mut current_version = None;
+            loop {
+                let Some(vault) = vault.upgrade() else {
+                    return;
+                };
+                match vault.idp_config_version().await {
+                    Ok(version) if current_version.is_none() => current_version = Some(version),
+                    Ok(version) if current_version != Some(version) => {
+                        match Self::apply_idp_config(&vault, &oidc, &idp).await {
+                            Ok(()) => {
+                                tracing::info!("Applied version {} of the IdP config", version);
+                                current_version = Some(version);
+                            }
+                            Err(e) => tracing::error!(
+                                "Failed to apply version {} of the IdP config: {}",
+                                version,
+                                e
+                            ),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Failed to check the IdP config version: {}", e),
+                }
+                drop(vault);
+                tokio::time::sleep(every).await;
+            }
+        })
+    }
+
+    async fn apply_idp_config(
+        vault: &VaultService,
+        oidc: &KeycloakOidcProvider,
+        idp: &Arc<IdpAdmin>,
+    ) -> Result<(), AppError> {
+        let config = vault.get_idp_config_from_vault().await?;
+        let client_secret = config.client_secret.clone();
+        idp.reload(config).await?;
+        oidc.set_client_secret(client_secret);
+        Ok(())
+    }
+
+    /// Rotate the OIDC client secret in the identity provider and store it in Vault
+    ///
+    /// Only admins may rotate it. The secret is used by this replica right away, the others
+    /// pick it up from Vault through [`Self::spawn_idp_config_watch`]. Should Vault keep
+    /// refusing the new config, the error is returned and the secret only lives in the
+    /// identity provider: store it in Vault by hand or rotate again.
+    pub async fn rotate_client_secret(&self, admin: &AiclIdentity) -> Result<(), AppError> {
+        if !admin.role.is_admin() {
+            return Err(AppError::forbidden(
+                "Only admins can rotate the client secret",
+            ));
+        }
+
+        let mut config = self.idp.config();
+        let client_secret = self.idp.rotate_client_secret(&config.client_id).await?;
+        tracing::info!(
+            target: "aicl_oidc::audit",
+            admin = %admin.id,
+            client_id = %config.client_id,
+            "Client secret rotated"
+        );
+        config.client_secret = Some(client_secret.clone());
+        self.oidc.set_client_secret(Some(client_secret.clone()));
+
+        let mut attempt = 1;
+        let stored = loop {
+            match self.vault.set_idp_client_secret(&client_secret).await {
+                Ok(version) => break Ok(version),
+                Err(e) if attempt < 3 => {
+                    tracing::warn!("Failed to store the rotated client secret, retrying: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        // A failed store is reported over a failed reload, the secret exists nowhere else
+        let reloaded = self.idp.reload(config).await;
+        match stored {
+            Ok(version) => {
+                tracing::info!(
+                    "Stored the rotated client secret as IdP config version {}",
+                    version
+                );
+                reloaded?;
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(reload_error) = reloaded {
+                    tracing::error!(
+                        "Failed to reconnect with the rotated client secret: {}",
+                        reload_error
+                    );
+                }
+                tracing::error!(
+                    "The rotated secret of client {} is not in Vault, \
+                    other replicas can't log users in: {}",
+                    self.idp.config().client_id,
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    errors::AppError,
+    vault::{wrapping::UnwrappedValue, VaultError},
+    AiclIdentifier, AiclIdentity,
+};
+
+impl AiclIdentifier {
+    /// Retrieve a wrapped value for its recipient, logged in or not
+    ///
+    /// Every attempt is logged under the `aicl_oidc::audit` target. Refused attempts on
+    /// tokens that were used before are the ones to look into.
+    pub async fn unwrap(
+        &self,
+        token: &str,
+        recipient: Option<&AiclIdentity>,
+    ) -> Result<UnwrappedValue, AppError> {
+        let recipient = recipient.map(|identity| identity.username.as_str());
+        match self.vault.unwrap(token).await {
+            Ok(unwrapped) => {
+                tracing::info!(
+                    target: "aicl_oidc::audit",
+                    recipient,
+                    creation_path = unwrapped.creation_path,
+                    "Unwrapped a wrapping token"
+                );
+                Ok(unwrapped)
+            }
+            Err(e @ VaultError::WrappingTokenInvalid) => {
+                tracing::warn!(
+                    target: "aicl_oidc::audit",
+                    recipient,
+                    "Refused an invalid, expired or already used wrapping token"
+                );
+                Err(e.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -3,7 +3,7 @@
 use atomic_time::AtomicInstant;
 use moka::future::{Cache, CacheBuilder};
 use std::{
-    sync::{atomic::Ordering, Arc},
+    sync::{atomic::Ordering, Arc, RwLock},
     time::{Duration, Instant},
 };
 use uuid::Uuid;
@@ -16,8 +16,9 @@ use super::{
 };
 
 pub struct IdpAdmin {
-    _config: IdpConfig,
-    provider: Box<dyn IdentityProvider>,
+    config: RwLock<IdpConfig>,
+    // Swapped for a new connection when the admin credentials change
+    provider: RwLock<Arc<dyn IdentityProvider>>,
     teams_group_id: Uuid,
     institutions_group_id: Uuid,
     // Cache for user data by user ID
@@ -42,9 +43,10 @@ pub struct IdpAdmin {
 }
 
 impl IdpAdmin {
-    pub async fn new(config: IdpConfig) -> Result<Arc<Self>, IdpError> {
+    /// Create and log in to the provider the config describes
+    async fn connect(config: &IdpConfig) -> Result<Arc<dyn IdentityProvider>, IdpError> {
         let mut provider = match config.provider_type.as_str() {
-            "keycloak" => KeycloakProvider::new(&config)?,
+            "keycloak" => KeycloakProvider::new(config)?,
             _ => {
                 return Err(IdpError::InvalidInput(format!(
                     "Unsupported identity provider type: {}",
@@ -54,6 +56,11 @@ impl IdpAdmin {
         };
 
         provider.initialize().await?;
+        Ok(Arc::new(provider))
+    }
+
+    pub async fn new(config: IdpConfig) -> Result<Arc<Self>, IdpError> {
+        let provider = Self::connect(&config).await?;
         let groups = provider.get_groups(None).await?;
         let teams_group_id = groups.iter().find(|g| g.name == "Teams").map(|g| g.id);
         if teams_group_id.is_none() {
@@ -94,8 +101,8 @@ impl IdpAdmin {
         let comprehensive_report = CacheBuilder::new(10).time_to_live(cache_ttl).build();
 
         Ok(Arc::new(IdpAdmin {
-            _config: config,
-            provider: Box::new(provider),
+            config: RwLock::new(config),
+            provider: RwLock::new(provider),
             teams_group_id,
             institutions_group_id,
             all_users_call,
@@ -111,6 +118,38 @@ impl IdpAdmin {
         }))
     }
 
+    fn provider(&self) -> Arc<dyn IdentityProvider> {
+        self.provider.read().expect("IdP provider poisoned").clone()
+    }
+
+    /// Apply a changed config, e.g. after the admin credentials were rotated in Vault
+    ///
+    /// The provider is only replaced when the new config logs in, so a bad config leaves
+    /// the current connection in place. Returns whether the provider was replaced; a
+    /// changed client secret alone only concerns the admin API when it logs in with the
+    /// client's service account.
+    pub async fn reload(self: &Arc<Self>, config: IdpConfig) -> Result<bool, IdpError> {
+        let changed = needs_reconnect(&self.config.read().expect("IdP config poisoned"), &config);
+        if changed {
+            let provider = Self::connect(&config).await?;
+            *self.provider.write().expect("IdP provider poisoned") = provider;
+            self.invalidate_caches();
+            tracing::info!("Reconnected to the identity provider with the new config");
+        }
+        *self.config.write().expect("IdP config poisoned") = config;
+        Ok(changed)
+    }
+
+    /// The config currently in use
+    pub fn config(&self) -> IdpConfig {
+        self.config.read().expect("IdP config poisoned").clone()
+    }
+
+    /// Have the identity provider generate a new secret for an OIDC client
+    pub async fn rotate_client_secret(&self, client_id: &str) -> Result<String, IdpError> {
+        self.provider().rotate_client_secret(client_id).await
+    }
+
     /// Get a specific user by ID with caching
     pub async fn get_user(self: &Arc<Self>, user_id: Uuid) -> Result<IdpUser, IdpError> {
         let this = self.clone();
@@ -118,7 +157,7 @@ impl IdpAdmin {
         self.users_by_id
             .get_with(
                 user_id,
-                async move { this.provider.get_user(user_id).await },
+                async move { this.provider().get_user(user_id).await },
             )
             .await
     }
@@ -130,7 +169,7 @@ impl IdpAdmin {
         if all_users_call.elapsed() > std::time::Duration::from_secs(60) {
             self.all_users_call
                 .store(std::time::Instant::now(), Ordering::Relaxed);
-            let all_users = this.provider.get_users().await?;
+            let all_users = this.provider().get_users().await?;
             for user in all_users {
                 self.users_by_id.insert(user.id, Ok(user)).await;
             }
@@ -152,7 +191,7 @@ impl IdpAdmin {
 
         self.users_by_username
             .get_with(username_arc, async move {
-                this.provider.find_users_by_username(username).await
+                this.provider().find_users_by_username(username).await
             })
             .await
     }
@@ -162,7 +201,7 @@ impl IdpAdmin {
         let this = self.clone();
         self.all_groups
             .entry(parent)
-            .or_try_insert_with(async move { this.provider.get_groups(parent).await })
+            .or_try_insert_with(async move { this.provider().get_groups(parent).await })
             .await
             .map(|entry| entry.into_value())
     }
@@ -182,7 +221,7 @@ impl IdpAdmin {
         self.group_by_id
             .get_with(
                 group_id,
-                async move { this.provider.get_group(group_id).await },
+                async move { this.provider().get_group(group_id).await },
             )
             .await
     }
@@ -196,7 +235,7 @@ impl IdpAdmin {
 
         self.group_members
             .get_with(group_id, async move {
-                this.provider.get_group_members(group_id).await
+                this.provider().get_group_members(group_id).await
             })
             .await
     }
@@ -210,7 +249,7 @@ impl IdpAdmin {
 
         self.user_groups
             .get_with(user_id, async move {
-                this.provider.get_user_groups(user_id).await
+                this.provider().get_user_groups(user_id).await
             })
             .await
     }
@@ -221,7 +260,7 @@ impl IdpAdmin {
 
         self.user_roles
             .get_with(user_id, async move {
-                this.provider.get_user_roles(user_id).await
+                this.provider().get_user_roles(user_id).await
             })
             .await
     }
@@ -271,7 +310,7 @@ impl IdpAdmin {
                 "User has multiple roles, only the first one will be used."
             );
         }
-        if roles.len() == 0 {
+        if roles.is_empty() {
             tracing::error!(user.username, "User has no roles.");
         }
 
@@ -333,3 +372,55 @@ impl IdpAdmin {
         self.comprehensive_report.invalidate_all();
     }
 }
+
+/// Whether the admin API has to reconnect to use the new config
+///
+/// The client secret only matters when the admin API logs in with the client's service
+/// account, admin users log in with their password.
+fn needs_reconnect(current: &IdpConfig, new: &IdpConfig) -> bool {
+    match new.admin_username {
+        Some(_) => {
+            IdpConfig { client_secret: None, ..new.clone() }
+                != IdpConfig { client_secret: None, ..current.clone() }
+        }
+        None => new != current,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(admin_username: Option<&str>) -> IdpConfig {
+        IdpConfig {
+            provider_type: "keycloak".to_string(),
+            base_url: "http://localhost:8080".to_string(),
+            realm: Some("aicl".to_string()),
+            client_id: "aicl-app".to_string(),
+            client_secret: Some("secret".to_string()),
+            admin_username: admin_username.map(str::to_string),
+            admin_password: admin_username.map(|_| "password".to_string()),
+            service_account_key_path: None,
+            domain: None,
+        }
+    }
+
+    #[test]
+    fn test_reload_change_detection() {
+        let current = config(None);
+        assert!(!needs_reconnect(&current, &current.clone()));
+
+        // The service account logs in with the client secret
+        let rotated = IdpConfig { client_secret: Some("rotated".to_string()), ..current.clone() };
+        assert!(needs_reconnect(&current, &rotated));
+
+        // Admin users don't
+        let current = config(Some("admin"));
+        let rotated = IdpConfig { client_secret: Some("rotated".to_string()), ..current.clone() };
+        assert!(!needs_reconnect(&current, &rotated));
+        let moved = IdpConfig { base_url: "http://keycloak:8080".to_string(), ..current.clone() };
+        assert!(needs_reconnect(&current, &moved));
+        let password = IdpConfig { admin_password: Some("new".to_string()), ..current.clone() };
+        assert!(needs_reconnect(&current, &password));
+    }
+}
@@ -69,6 +69,20 @@ pub enum IdpError {
     Unknown(String),
 }
 
+impl IdpError {
+    /// Stable, machine-readable code for this error, suitable for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::AuthenticationError(_) => "idp.authentication_failed",
+            Self::NotFound(_) => "idp.not_found",
+            Self::PermissionDenied(_) => "idp.permission_denied",
+            Self::NetworkError(_) => "idp.network_error",
+            Self::InvalidInput(_) => "idp.invalid_input",
+            Self::Unknown(_) => "idp.unknown",
+        }
+    }
+}
+
 /// The core trait that all identity providers must implement
 #[async_trait]
 pub trait IdentityProvider: Send + Sync {
@@ -136,7 +150,7 @@ pub trait IdentityProvider: Send + Sync {
                 "User has multiple roles, only the first one will be used."
             );
         }
-        if roles.len() == 0 {
+        if roles.is_empty() {
             tracing::error!(user.username, "User has no roles.");
         }
 
@@ -157,6 +171,15 @@ pub trait IdentityProvider: Send + Sync {
         })
     }
 
+    /// Generate a new secret for an OIDC client, returning it. The old one stops working.
+    async fn rotate_client_secret(&self, client_id: &str) -> Result<String, IdpError> {
+        Err(IdpError::InvalidInput(format!(
+            "Rotating the secret of client {} is not supported by {}",
+            client_id,
+            self.issuer()
+        )))
+    }
+
     async fn get_domain_user(
         &self,
         user_id: Uuid,
@@ -167,7 +190,7 @@ pub trait IdentityProvider: Send + Sync {
 }
 
 /// Configuration for identity providers
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IdpConfig {
     pub provider_type: String, // "keycloak", "google", etc.
     pub base_url: String,
@@ -2,24 +2,71 @@ use super::ext::{
     IdentityProvider, IdpConfig, IdpError, IdpGroup, IdpGroupHeader, IdpRole, IdpUser,
 };
 use async_trait::async_trait;
-use reqwest::{header, Client};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tracing::{debug, error, info};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 // Types for authentication
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 struct TokenRequest<'a> {
-    username: &'a str,
-    password: &'a str,
     grant_type: &'a str,
     client_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<&'a str>,
 }
 
 #[derive(Deserialize, Debug)]
 struct TokenResponse {
     access_token: String,
+    expires_in: u64,
+    refresh_token: Option<String>,
+    #[serde(default)]
+    refresh_expires_in: u64,
+}
+
+/// How the provider logs in to the admin API
+enum AdminCredentials {
+    /// A master realm admin, through the `admin-cli` client
+    Password { username: String, password: String },
+    /// The service account of a confidential client in the managed realm
+    ClientCredentials {
+        client_id: String,
+        client_secret: String,
+    },
+}
+
+// An admin API token and when to replace it
+#[derive(Clone)]
+struct AdminToken {
+    access_token: String,
+    // Refreshed from two thirds of its lifetime on, like the Vault service token
+    refresh_at: Instant,
+    expires_at: Instant,
+    refresh_token: Option<String>,
+    refresh_expires_at: Instant,
+}
+
+impl AdminToken {
+    fn new(response: TokenResponse) -> Self {
+        let now = Instant::now();
+        Self {
+            access_token: response.access_token,
+            refresh_at: now + Duration::from_secs(response.expires_in * 2 / 3),
+            expires_at: now + Duration::from_secs(response.expires_in),
+            refresh_token: response.refresh_token,
+            refresh_expires_at: now + Duration::from_secs(response.refresh_expires_in),
+        }
+    }
 }
 
 // Keycloak specific types (kept private to this module)
@@ -69,118 +116,164 @@ struct ClientRoleMapping {
     pub mappings: Vec<KeycloakRole>,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct KeycloakClient {
+    pub id: Uuid,
+    pub client_id: String,
+}
+
+#[derive(Deserialize)]
+struct KeycloakClientSecret {
+    pub value: String,
+}
+
 pub struct KeycloakProvider {
     client: Client,
     base_url: String,
     realm: String,
-    access_token: Option<String>,
-    admin_username: String,
-    admin_password: String,
+    credentials: AdminCredentials,
+    token: RwLock<Option<AdminToken>>,
+    // Held while getting a new token, so concurrent requests wait for one login
+    refresh: tokio::sync::Mutex<()>,
 }
 
 impl KeycloakProvider {
+    /// Log in with the admin username and password when configured, otherwise with the
+    /// service account of the config's client, which needs the `realm-management` roles
+    /// for the calls it makes
     pub fn new(config: &IdpConfig) -> Result<Self, IdpError> {
         let realm = config.realm.clone().ok_or_else(|| {
             IdpError::InvalidInput("Realm is required for Keycloak provider".to_string())
         })?;
 
-        let admin_username = config.admin_username.clone().ok_or_else(|| {
-            IdpError::InvalidInput("Admin username is required for Keycloak provider".to_string())
-        })?;
-
-        let admin_password = config.admin_password.clone().ok_or_else(|| {
-            IdpError::InvalidInput("Admin password is required for Keycloak provider".to_string())
-        })?;
+        let credentials = match (&config.admin_username, &config.admin_password) {
+            (Some(username), Some(password)) => AdminCredentials::Password {
+                username: username.clone(),
+                password: password.clone(),
+            },
+            (None, None) => {
+                let client_secret = config.client_secret.clone().ok_or_else(|| {
+                    IdpError::InvalidInput(
+                        "Admin credentials or a client secret are required for Keycloak provider"
+                            .to_string(),
+                    )
+                })?;
+                AdminCredentials::ClientCredentials {
+                    client_id: config.client_id.clone(),
+                    client_secret,
+                }
+            }
+            _ => {
+                return Err(IdpError::InvalidInput(
+                    "Admin username and password must be set together".to_string(),
+                ))
+            }
+        };
 
         Ok(KeycloakProvider {
             client: Client::new(),
             base_url: config.base_url.clone(),
             realm,
-            access_token: None,
-            admin_username,
-            admin_password,
+            credentials,
+            token: RwLock::new(None),
+            refresh: tokio::sync::Mutex::new(()),
         })
     }
 
-    // Helper method to get auth headers
-    fn get_auth_headers(&self) -> Result<header::HeaderMap, IdpError> {
-        let mut headers = header::HeaderMap::new();
-
-        let token = match &self.access_token {
-            Some(token) => token,
-            None => {
-                return Err(IdpError::AuthenticationError(
-                    "Not authenticated. Call initialize() first.".to_string(),
-                ))
-            }
-        };
-
-        let auth_value = format!("Bearer {}", token);
-        match header::HeaderValue::from_str(&auth_value) {
-            Ok(value) => {
-                headers.insert(header::AUTHORIZATION, value);
-                Ok(headers)
-            }
-            Err(e) => Err(IdpError::Unknown(format!(
-                "Failed to create authorization header: {}",
-                e
-            ))),
-        }
+    fn current_token(&self, usable: impl Fn(&AdminToken) -> bool) -> Option<String> {
+        self.token
+            .read()
+            .expect("Keycloak token poisoned")
+            .as_ref()
+            .filter(|token| usable(token))
+            .map(|token| token.access_token.clone())
     }
 
-    // Convert Keycloak types to generic IdP types
-    fn convert_kc_user_to_idp_user(&self, kc_user: KeycloakUser) -> IdpUser {
-        IdpUser {
-            id: kc_user.id,
-            username: kc_user.username,
-            email: kc_user.email,
-            first_name: kc_user.first_name,
-            last_name: kc_user.last_name,
-            enabled: kc_user.enabled,
-            attributes: kc_user.attributes.unwrap_or_default(),
+    // A token for the admin API, refreshed once it gets old
+    async fn access_token(&self) -> Result<String, IdpError> {
+        let fresh = |token: &AdminToken| Instant::now() < token.refresh_at;
+        if let Some(token) = self.current_token(fresh) {
+            return Ok(token);
         }
-    }
 
-    fn convert_kc_group_to_idp_group(&self, kc_group: KeycloakGroup) -> IdpGroup {
-        IdpGroup {
-            id: kc_group.id,
-            name: kc_group.name,
-            path: kc_group.path,
-            parent_id: kc_group.parent_id,
-            attributes: kc_group.attributes.unwrap_or_default(),
+        let _refresh = self.refresh.lock().await;
+        // Another request may have refreshed it while this one waited
+        if let Some(token) = self.current_token(fresh) {
+            return Ok(token);
+        }
+        let previous = self.token.read().expect("Keycloak token poisoned").clone();
+        match self.obtain_token(previous.as_ref()).await {
+            Ok(token) => {
+                let access_token = token.access_token.clone();
+                *self.token.write().expect("Keycloak token poisoned") = Some(token);
+                Ok(access_token)
+            }
+            // Keep using the old token while it lasts, Keycloak may be back by then
+            Err(e) => match previous {
+                Some(previous) if Instant::now() < previous.expires_at => {
+                    warn!("Failed to refresh the Keycloak admin token: {}", e);
+                    Ok(previous.access_token)
+                }
+                _ => Err(e),
+            },
         }
     }
 
-    fn convert_kc_role_to_idp_role(&self, kc_role: KeycloakRole) -> IdpRole {
-        IdpRole {
-            id: kc_role.id,
-            name: kc_role.name,
-            description: kc_role.description,
-            is_composite: kc_role.composite,
-            source: if kc_role.client_role {
-                format!("client:{}", kc_role.container_id)
-            } else {
-                "realm".to_string()
-            },
+    async fn obtain_token(&self, previous: Option<&AdminToken>) -> Result<AdminToken, IdpError> {
+        let refresh_token = previous
+            .filter(|previous| Instant::now() < previous.refresh_expires_at)
+            .and_then(|previous| previous.refresh_token.as_deref());
+        if let Some(refresh_token) = refresh_token {
+            match self.request_token(Some(refresh_token)).await {
+                Ok(token) => return Ok(token),
+                Err(e) => debug!("Failed to refresh the Keycloak admin token, logging in: {}", e),
+            }
         }
+        self.request_token(None).await
     }
-}
 
-#[async_trait]
-impl IdentityProvider for KeycloakProvider {
-    async fn initialize(&mut self) -> Result<(), IdpError> {
+    // Log in, or refresh the session when given a refresh token
+    async fn request_token(&self, refresh_token: Option<&str>) -> Result<AdminToken, IdpError> {
+        let (realm, token_request) = match &self.credentials {
+            AdminCredentials::Password { username, password } => (
+                "master",
+                TokenRequest {
+                    grant_type: "password",
+                    client_id: "admin-cli",
+                    username: Some(username.as_str()),
+                    password: Some(password.as_str()),
+                    ..Default::default()
+                },
+            ),
+            AdminCredentials::ClientCredentials {
+                client_id,
+                client_secret,
+            } => (
+                self.realm.as_str(),
+                TokenRequest {
+                    grant_type: "client_credentials",
+                    client_id: client_id.as_str(),
+                    client_secret: Some(client_secret.as_str()),
+                    ..Default::default()
+                },
+            ),
+        };
+        let token_request = match refresh_token {
+            Some(refresh_token) => TokenRequest {
+                grant_type: "refresh_token",
+                username: None,
+                password: None,
+                refresh_token: Some(refresh_token),
+                ..token_request
+            },
+            None => token_request,
+        };
         let token_url = format!(
-            "{}/realms/master/protocol/openid-connect/token",
-            self.base_url
+            "{}/realms/{}/protocol/openid-connect/token",
+            self.base_url, realm
         );
 
-        let token_request = TokenRequest {
-            username: &self.admin_username,
-            password: &self.admin_password,
-            grant_type: "password",
-            client_id: "admin-cli",
-        };
-
         let response = match self
             .client
             .post(&token_url)
@@ -219,7 +312,88 @@ impl IdentityProvider for KeycloakProvider {
             }
         };
 
-        self.access_token = Some(token_response.access_token);
+        Ok(AdminToken::new(token_response))
+    }
+
+    // Send an admin API request, logging in again once if Keycloak refuses the token
+    async fn send_authorized(&self, request: RequestBuilder) -> Result<Response, IdpError> {
+        let retry = request.try_clone();
+        let token = self.access_token().await?;
+        let response = send(request.bearer_auth(&token)).await?;
+        match retry {
+            // E.g. the session was ended in Keycloak, or Keycloak restarted
+            Some(retry) if response.status() == StatusCode::UNAUTHORIZED => {
+                debug!("Keycloak refused the admin token, getting a new one");
+                self.expire_token(&token);
+                let token = self.access_token().await?;
+                send(retry.bearer_auth(&token)).await
+            }
+            _ => Ok(response),
+        }
+    }
+
+    // Stop using a token Keycloak refused, unless it was replaced already
+    fn expire_token(&self, access_token: &str) {
+        let mut token = self.token.write().expect("Keycloak token poisoned");
+        if token
+            .as_ref()
+            .is_some_and(|token| token.access_token == access_token)
+        {
+            *token = None;
+        }
+    }
+
+    // Convert Keycloak types to generic IdP types
+    fn convert_kc_user_to_idp_user(&self, kc_user: KeycloakUser) -> IdpUser {
+        IdpUser {
+            id: kc_user.id,
+            username: kc_user.username,
+            email: kc_user.email,
+            first_name: kc_user.first_name,
+            last_name: kc_user.last_name,
+            enabled: kc_user.enabled,
+            attributes: kc_user.attributes.unwrap_or_default(),
+        }
+    }
+
+    fn convert_kc_group_to_idp_group(&self, kc_group: KeycloakGroup) -> IdpGroup {
+        IdpGroup {
+            id: kc_group.id,
+            name: kc_group.name,
+            path: kc_group.path,
+            parent_id: kc_group.parent_id,
+            attributes: kc_group.attributes.unwrap_or_default(),
+        }
+    }
+
+    fn convert_kc_role_to_idp_role(&self, kc_role: KeycloakRole) -> IdpRole {
+        IdpRole {
+            id: kc_role.id,
+            name: kc_role.name,
+            description: kc_role.description,
+            is_composite: kc_role.composite,
+            source: if kc_role.client_role {
+                format!("client:{}", kc_role.container_id)
+            } else {
+                "realm".to_string()
+            },
+        }
+    }
+}
+
+async fn send(request: RequestBuilder) -> Result<Response, IdpError> {
+    request
+        .send()
+        .await
+        .map_err(|e| IdpError::NetworkError(format!("Failed to connect to Keycloak: {}", e)))
+}
+
+#[async_trait]
+impl IdentityProvider for KeycloakProvider {
+    async fn initialize(&mut self) -> Result<(), IdpError> {
+        // Log in right away so bad credentials fail at startup
+        let token = self.request_token(None).await?;
+        *self.token.get_mut().expect("Keycloak token poisoned") = Some(token);
         info!("Successfully logged in to Keycloak");
 
         Ok(())
@@ -230,19 +404,10 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_users(&self) -> Result<Vec<IdpUser>, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!("{}/admin/realms/{}/users", self.base_url, self.realm);
 
         debug!("Fetching users from: {}", url);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if !response.status().is_success() {
             let error_text = match response.text().await {
@@ -277,22 +442,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_user(&self, user_id: Uuid) -> Result<IdpUser, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!(
             "{}/admin/realms/{}/users/{}",
             self.base_url, self.realm, user_id
         );
 
         debug!("Fetching user from: {}", url);
-        let response = match self.client.get(&url).headers(headers.clone()).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if response.status().is_client_error() {
             if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -338,22 +494,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn find_users_by_username(&self, username: &str) -> Result<Vec<IdpUser>, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!(
             "{}/admin/realms/{}/users?username={}",
             self.base_url, self.realm, username
         );
 
         debug!("Searching users with username: {}", username);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if !response.status().is_success() {
             let error_text = match response.text().await {
@@ -387,22 +534,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_groups(&self, parent: Option<Uuid>) -> Result<Vec<IdpGroupHeader>, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = if let Some(parent) = parent {
             format!("{}/admin/realms/{}/groups/{}/children", self.base_url, self.realm, parent)
         } else {
             format!("{}/admin/realms/{}/groups", self.base_url, self.realm)
         };        
         debug!("Fetching groups from: {}", url);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if !response.status().is_success() {
             let error_text = match response.text().await {
@@ -431,22 +569,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_group(&self, group_id: Uuid) -> Result<IdpGroup, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!(
             "{}/admin/realms/{}/groups/{}",
             self.base_url, self.realm, group_id
         );
 
         debug!("Fetching group from: {}", url);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if response.status().is_client_error() {
             if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -492,22 +621,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_group_members(&self, group_id: Uuid) -> Result<Vec<IdpUser>, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!(
             "{}/admin/realms/{}/groups/{}/members",
             self.base_url, self.realm, group_id
         );
 
         debug!("Fetching group members from: {}", url);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if response.status().is_client_error() {
             if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -559,22 +679,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_user_groups(&self, user_id: Uuid) -> Result<Vec<IdpGroup>, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!(
             "{}/admin/realms/{}/users/{}/groups",
             self.base_url, self.realm, user_id
         );
 
         debug!("Fetching user groups from: {}", url);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if response.status().is_client_error() {
             if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -626,22 +737,13 @@ impl IdentityProvider for KeycloakProvider {
     }
 
     async fn get_user_roles(&self, user_id: Uuid) -> Result<Vec<IdpRole>, IdpError> {
-        let headers = self.get_auth_headers()?;
         let url = format!(
             "{}/admin/realms/{}/users/{}/role-mappings",
             self.base_url, self.realm, user_id
         );
 
         debug!("Fetching user roles from: {}", url);
-        let response = match self.client.get(&url).headers(headers).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(IdpError::NetworkError(format!(
-                    "Failed to connect to Keycloak: {}",
-                    e
-                )))
-            }
-        };
+        let response = self.send_authorized(self.client.get(&url)).await?;
 
         if response.status().is_client_error() {
             if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -705,4 +807,71 @@ impl IdentityProvider for KeycloakProvider {
 
         Ok(roles)
     }
+
+    async fn rotate_client_secret(&self, client_id: &str) -> Result<String, IdpError> {
+        let url = format!("{}/admin/realms/{}/clients", self.base_url, self.realm);
+
+        // The admin API addresses clients by their internal id
+        let request = self.client.get(&url).query(&[("clientId", client_id)]);
+        let response = self.send_authorized(request).await?;
+
+        if !response.status().is_success() {
+            let error_text = match response.text().await {
+                Ok(text) => text,
+                Err(_) => "Unknown error".to_string(),
+            };
+            error!("Failed to get client: {}", error_text);
+            return Err(IdpError::PermissionDenied(format!(
+                "Failed to get client: {}",
+                error_text
+            )));
+        }
+
+        let clients: Vec<KeycloakClient> = match response.json().await {
+            Ok(clients) => clients,
+            Err(e) => {
+                return Err(IdpError::Unknown(format!(
+                    "Failed to parse clients response: {}",
+                    e
+                )))
+            }
+        };
+        let client = match clients.into_iter().find(|client| client.client_id == client_id) {
+            Some(client) => client,
+            None => {
+                return Err(IdpError::NotFound(format!(
+                    "Client {} not found",
+                    client_id
+                )))
+            }
+        };
+
+        let url = format!("{}/{}/client-secret", url, client.id);
+        let response = self.send_authorized(self.client.post(&url)).await?;
+
+        if !response.status().is_success() {
+            let error_text = match response.text().await {
+                Ok(text) => text,
+                Err(_) => "Unknown error".to_string(),
+            };
+            error!("Failed to rotate client secret: {}", error_text);
+            return Err(IdpError::Unknown(format!(
+                "Failed to rotate client secret: {}",
+                error_text
+            )));
+        }
+
+        let secret: KeycloakClientSecret = match response.json().await {
+            Ok(secret) => secret,
+            Err(e) => {
+                return Err(IdpError::Unknown(format!(
+                    "Failed to parse client secret response: {}",
+                    e
+                )))
+            }
+        };
+
+        info!("Rotated the secret of client {}", client_id);
+        Ok(secret.value)
+    }
 }
@@ -382,3 +382,22 @@ async fn test_cache_invalidation() {
     let _ = admin.get_groups(None).await.unwrap();
     let _ = admin.get_comprehensive_report().await.unwrap();
 }
+
+// Test the service account login in the app realm, without master admin credentials
+#[tracing_test::traced_test]
+#[tokio::test]
+async fn test_service_account_login() {
+    let config = IdpConfig {
+        admin_username: None,
+        admin_password: None,
+        ..create_keycloak_config()
+    };
+    let admin = IdpAdmin::new(config)
+        .await
+        .expect("Failed to create IdpAdmin with the service account");
+
+    // Concurrent calls share one token
+    let (teams, institutions) = tokio::join!(admin.get_teams(), admin.get_institutions());
+    assert!(!teams.expect("Failed to get teams").is_empty());
+    assert!(!institutions.expect("Failed to get institutions").is_empty());
+}
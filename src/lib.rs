@@ -4,6 +4,7 @@ pub mod idp;
 pub mod oidc;
 pub mod vault;
 pub mod database;
+mod identifier;
 
 #[cfg(feature = "test-utils")]
 pub mod test_utils;
@@ -17,19 +18,38 @@ use anyhow::Context;
 use axum::middleware::IdentifierLayer;
 pub use axum::{
     error::{AppErrorHandler, ErrorHandlerExtensionLayer},
-    extractors::OptionalIdentity,
-    middleware::{ApiTokenAuthLayer, AuthenticateLayer, LoginEnforcerLayer},
+    extractors::{OptionalIdentity, UserVaultClient},
+    handlers::AuthRouterConfig,
+    client_cert::{ClientCertAuthLayer, PeerCertificate},
+    middleware::{
+        ApiRequestRules, ApiTokenAuthLayer, AuthenticateLayer, LoginEnforcerLayer, TrustedProxies,
+    },
+    router::AiclRouterExt,
 };
-use database::IdpSyncService;
+use database::{
+    api_tokens::{ApiTokenRecord, ApiTokenRegistry},
+    client_certificates::ClientCertificateRegistry,
+    database_leases::DatabaseLeaseRegistry,
+    sessions::{SessionRegistry, SESSION_REGISTRY_KEY},
+    IdpSyncService,
+};
+use errors::AppError;
 use idp::admin::IdpAdmin;
 use oidc::{
-    keycloak::{KeycloakOidcBuilder, KeycloakOidcProvider},
+    keycloak::{KeyCloakToken, KeycloakOidcBuilder, KeycloakOidcProvider},
     logout::LogoutService,
 };
+use moka::future::Cache;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use tower_sessions::Session;
 use uuid::Uuid;
-use vault::VaultService;
+use vault::{
+    reconcile::{DesiredRoles, ReconcilePlan},
+    revocation::PgRevocationBus,
+    transit::VaultCrypto,
+    ApiToken, TokenRenewal, TokenScope, VaultError, VaultService, CERTIFICATE_CACHE_TTL,
+};
 
 /// Represents a team identity
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -77,10 +97,7 @@ impl Role {
     }
 
     pub fn is_admin(&self) -> bool {
-        match self {
-            Self::Admin => true,
-            _ => false,
-        }
+        matches!(self, Self::Admin)
     }
 }
 
@@ -95,21 +112,42 @@ pub struct AiclIdentity {
     pub role: Role,
 }
 
+/// How often the IdP config in Vault is checked for changes
+pub const IDP_CONFIG_WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
 #[derive(Clone)]
 pub struct AiclIdentifier {
     pub vault: Arc<VaultService>,
     pub oidc: Arc<KeycloakOidcProvider>,
     pub idp: Arc<IdpAdmin>,
     pub db: Arc<IdpSyncService>,
+    pub sessions: Arc<SessionRegistry>,
+    pub tokens: Arc<ApiTokenRegistry>,
+    pub crypto: Arc<VaultCrypto>,
+    pub leases: Arc<DatabaseLeaseRegistry>,
+    pub certificates: Arc<ClientCertificateRegistry>,
+    /// Proxies allowed to tell the client address, see [`Self::with_trusted_proxies`]
+    pub trusted_proxies: Arc<TrustedProxies>,
+    // Identities of accepted client certificates by serial number, with the certificate
+    certificate_identities: Cache<String, (Vec<u8>, AiclIdentity)>,
 }
 
 impl AiclIdentifier {
     pub async fn from_env(database: PgPool) -> anyhow::Result<Self> {
+        let vault = VaultService::from_env()
+            .await
+            .with_context(|| "Vault service initialization failed")?;
+        let token_profiles = vault
+            .get_token_profiles()
+            .await
+            .with_context(|| "Failed to get token profiles from Vault")?;
         let vault = Arc::new(
-            VaultService::from_env()
-                .await
-                .with_context(|| "Vault service initialization failed")?,
+            vault
+                .with_token_profiles(token_profiles)
+                .with_revocation_bus(PgRevocationBus::new(database.clone())),
         );
+        vault.spawn_token_renewal();
+        vault.spawn_revocation_listener();
         let idp_config = vault
             .get_idp_config_from_vault()
             .await
@@ -134,9 +172,53 @@ impl AiclIdentifier {
             .await
             .with_context(|| "Failed to build KeycloakOidcProvider")?,
         );
-        let db = Arc::new(IdpSyncService::new(database, idp.clone()));
+        let sessions = Arc::new(
+            SessionRegistry::new(database.clone())
+                .with_limits_from_env()
+                .with_context(|| "Invalid SESSION_LIMITS")?,
+        );
+        let tokens = Arc::new(ApiTokenRegistry::new(database.clone()));
+        let leases = Arc::new(DatabaseLeaseRegistry::new(database.clone()));
+        let certificates = Arc::new(ClientCertificateRegistry::new(database.clone()));
+        let crypto = Arc::new(VaultCrypto::new(vault.clone()));
+        let db = Arc::new(IdpSyncService::new(database, idp.clone()).with_crypto(crypto.clone()));
+
+        let trusted_proxies = Arc::new(TrustedProxies::from_env());
+
+        let identifier = Self {
+            oidc,
+            vault,
+            idp,
+            db,
+            sessions,
+            tokens,
+            crypto,
+            leases,
+            certificates,
+            trusted_proxies,
+            certificate_identities: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(CERTIFICATE_CACHE_TTL)
+                .build(),
+        };
+        identifier.spawn_idp_config_watch(IDP_CONFIG_WATCH_INTERVAL);
+        Ok(identifier)
+    }
 
-        Ok(Self { oidc, vault, idp, db })
+    /// Believe the `X-Forwarded-For` header of these proxies, instead of `TRUSTED_PROXIES`
+    ///
+    /// The client address is used for the session registry and network bound API tokens.
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = Arc::new(trusted_proxies);
+        self
+    }
+
+    /// Cap the concurrent sessions of users with this role, overriding `SESSION_LIMITS`
+    ///
+    /// Going over the cap revokes the least recently used sessions.
+    pub fn with_session_limit(mut self, role: Role, max_sessions: usize) -> Self {
+        self.sessions = Arc::new(self.sessions.as_ref().clone().with_limit(role, max_sessions));
+        self
     }
 
     #[cfg(feature = "test-utils")]
@@ -157,6 +239,224 @@ impl AiclIdentifier {
         }
     }
 
+    /// Log out of the identity provider and close the session in the registry
+    ///
+    /// Database credentials issued to the session are revoked with it.
+    /// Returns the URI to redirect the user to.
+    pub async fn logout(&self, session: &Session) -> Result<::axum::http::Uri, AppError> {
+        let registry_id: Option<Uuid> = session
+            .remove(SESSION_REGISTRY_KEY)
+            .await
+            .map_err(AppError::session_error)?;
+        if let Some(registry_id) = registry_id {
+            self.sessions
+                .end_session(registry_id)
+                .await
+                .map_err(AppError::internal_error)?;
+            let lease_ids = self
+                .leases
+                .mark_session_revoked(registry_id)
+                .await
+                .map_err(AppError::internal_error)?;
+            self.revoke_leases(&lease_ids).await;
+        }
+        Ok(self.oidc.logout(session).await?)
+    }
+
+    /// Issue an API token for the user and record it in the token registry
+    ///
+    /// The token is restricted to the named profile, if any, narrowed further by `scope`.
+    /// Returns the token, which is shown once and never stored, with its registry entry.
+    pub async fn create_api_token(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+        name: Option<&str>,
+        profile: Option<&str>,
+        scope: TokenScope,
+    ) -> Result<(ApiToken, ApiTokenRecord), AppError> {
+        let name = match name.map(str::trim) {
+            Some(name) if name.is_empty() || name.len() > 255 => {
+                return Err(AppError::bad_request(
+                    "Token name must be between 1 and 255 characters",
+                ))
+            }
+            Some(name) => name.to_string(),
+            None => format!(
+                "api-token-{}-{}",
+                identity.username,
+                time::OffsetDateTime::now_utc().unix_timestamp()
+            ),
+        };
+
+        let scope = match profile {
+            Some(profile) => self
+                .vault
+                .token_profile(profile)
+                .cloned()
+                .ok_or_else(|| AppError::bad_request(format!("Unknown token profile {}", profile)))?
+                .restrict(scope)?,
+            None => scope,
+        };
+
+        let token = self
+            .vault
+            .create_api_token_with_oidc(identity, oidc_token, &scope)
+            .await?;
+        let record = match self
+            .tokens
+            .record(identity.id, &name, &token, profile, &scope)
+            .await
+        {
+            Ok(record) => record,
+            Err(e) => {
+                // Don't leave a token behind that the user can't see or revoke
+                if let Err(revoke_error) =
+                    self.vault.revoke_api_token(identity.id, &token.accessor).await
+                {
+                    tracing::error!("Failed to revoke unrecorded API token: {}", revoke_error);
+                }
+                return Err(AppError::internal_error(e));
+            }
+        };
+
+        Ok((token, record))
+    }
+
+    /// Revoke one of the user's API tokens by accessor
+    ///
+    /// Returns `false` if the user has no such active token.
+    pub async fn revoke_api_token(&self, user_id: Uuid, accessor: &str) -> Result<bool, AppError> {
+        let token = self
+            .tokens
+            .get_user_token(user_id, accessor)
+            .await
+            .map_err(AppError::internal_error)?;
+        if token.is_none() {
+            return Ok(false);
+        }
+
+        self.revoke_in_vault(user_id, accessor).await?;
+        self.tokens
+            .mark_revoked(user_id, accessor)
+            .await
+            .map_err(AppError::internal_error)
+    }
+
+    /// Revoke all of the user's API tokens, returning how many were revoked
+    pub async fn revoke_all_api_tokens(&self, user_id: Uuid) -> Result<u64, AppError> {
+        let tokens = self
+            .tokens
+            .list_user_tokens(user_id)
+            .await
+            .map_err(AppError::internal_error)?;
+        for token in &tokens {
+            self.revoke_in_vault(user_id, &token.accessor).await?;
+        }
+
+        // Also closes the entries of tokens that expired on their own
+        self.tokens
+            .mark_all_revoked(user_id)
+            .await
+            .map_err(AppError::internal_error)?;
+        Ok(tokens.len() as u64)
+    }
+
+    /// Renew an API token and record its new expiry
+    ///
+    /// The token is verified first, so only tokens issued by this service can be renewed.
+    pub async fn renew_api_token(
+        &self,
+        token: &str,
+        increment: Option<std::time::Duration>,
+    ) -> Result<TokenRenewal, AppError> {
+        self.vault.verify_token(token).await?;
+        let renewal = self.vault.renew_token(token, increment).await?;
+
+        self.tokens
+            .update_expiry(&renewal.accessor, renewal.expires_at)
+            .await
+            .map_err(AppError::internal_error)?;
+        Ok(renewal)
+    }
+
+    /// Create, update or delete the teams' and institutions' Vault policies and roles
+    ///
+    /// Teams and institutions are synced from the identity provider first, so ones created
+    /// there at runtime get their Vault roles without rerunning Terraform. With `dry_run`
+    /// nothing is changed, the plan lists what would be.
+    pub async fn reconcile_vault_roles(&self, dry_run: bool) -> Result<ReconcilePlan, AppError> {
+        self.db
+            .sync_all_institutions()
+            .await
+            .map_err(AppError::internal_error)?;
+        self.db
+            .sync_all_teams()
+            .await
+            .map_err(AppError::internal_error)?;
+        let teams = self.db.all_teams().await;
+        let institutions = self.db.all_institutions().await;
+
+        let mut desired = DesiredRoles::new(
+            self.oidc.client_id(),
+            teams.iter().map(|team| team.name.as_str()),
+            institutions.iter().map(|institution| institution.name.as_str()),
+        );
+        // The team policies grant credentials from the database roles
+        if self.vault.has_database_connection().await? {
+            desired = desired.with_database_roles(vault::database::CONNECTION);
+        }
+        let plan = self.vault.reconcile_roles(&desired, dry_run).await?;
+        if !plan.is_empty() {
+            tracing::info!(
+                dry_run,
+                changes = plan.changes.len(),
+                "Reconciled Vault roles:\n{}",
+                plan
+            );
+        }
+        if plan.applied {
+            // Their roles are gone, the credentials they issued shouldn't outlive them
+            for team in plan.removed_teams() {
+                if let Err(e) = self.revoke_team_database_credentials(team).await {
+                    tracing::error!(
+                        team,
+                        "Failed to revoke the database credentials of a removed team: {}",
+                        e
+                    );
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Reconcile the Vault roles every `every`, see [`Self::reconcile_vault_roles`]
+    ///
+    /// Not started by [`Self::from_env`]: the service's Vault policy must allow managing
+    /// team policies and roles, and Terraform should no longer manage them.
+    pub fn spawn_vault_reconciler(
+        &self,
+        every: std::time::Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let identifier = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = identifier.reconcile_vault_roles(false).await {
+                    tracing::error!("Failed to reconcile Vault roles: {}", e);
+                }
+                tokio::time::sleep(every).await;
+            }
+        })
+    }
+
+    async fn revoke_in_vault(&self, user_id: Uuid, accessor: &str) -> Result<(), AppError> {
+        match self.vault.revoke_api_token(user_id, accessor).await {
+            // Vault no longer knows the token, it already expired
+            Ok(()) | Err(VaultError::Unauthorized(_)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub fn identifier_layer(&self) -> IdentifierLayer {
         IdentifierLayer {
             identifier: self.clone(),
@@ -168,7 +468,7 @@ impl AiclIdentifier {
     }
 
     pub fn login_layer(&self) -> LoginEnforcerLayer {
-        LoginEnforcerLayer {}
+        LoginEnforcerLayer::default()
     }
 
     pub fn logout_service(&self) -> LogoutService {
@@ -178,4 +478,43 @@ impl AiclIdentifier {
     pub fn api_token_layer(&self) -> ApiTokenAuthLayer {
         ApiTokenAuthLayer {}
     }
+
+    pub fn client_cert_layer(&self) -> ClientCertAuthLayer {
+        ClientCertAuthLayer::default()
+    }
+
+    /// Router with `/auth/login`, `/auth/callback`, `/auth/logout`, `/auth/me`,
+    /// `/auth/tokens` and `/auth/sessions`, ready to be merged into the application router.
+    /// Use [`Self::auth_router_with`] for `/auth/database/credentials`,
+    /// `/auth/certificates` and `/auth/unwrap`.
+    ///
+    /// Merge it before calling [`AiclRouterExt::with_aicl_auth`], which installs the layers
+    /// the routes need.
+    pub fn auth_router<S>(&self) -> ::axum::Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        self.auth_router_with(AuthRouterConfig::default())
+    }
+
+    /// Router with the team and institution secrets API under `/secrets`, e.g.
+    /// `GET /secrets/team/data/{name}` or `PUT /secrets/institution/data/{name}`
+    ///
+    /// Secrets are read and written as the logged in user, so Vault policy has the final say.
+    /// On top of that, only captains and advisors may change secrets. Merge it before calling
+    /// [`AiclRouterExt::with_aicl_auth`].
+    pub fn secrets_router<S>(&self) -> ::axum::Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        axum::secrets::secrets_router("/secrets")
+    }
+
+    /// Same as [`AiclIdentifier::auth_router`] with a custom configuration
+    pub fn auth_router_with<S>(&self, config: AuthRouterConfig) -> ::axum::Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        axum::handlers::auth_router(config)
+    }
 }
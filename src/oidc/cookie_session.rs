@@ -0,0 +1,679 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use async_trait::async_trait;
+use axum::{
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap, HeaderValue, Request,
+    },
+    response::{IntoResponse, Response},
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use tower_sessions::{
+    cookie::{time::OffsetDateTime, Cookie},
+    session::{Id, Record},
+    session_store, Expiry, Session, SessionStore,
+};
+
+use crate::{errors::AppError, vault::VaultService, AppErrorHandler};
+
+use super::{ext::OidcError, keycloak::TOKEN_KEY, session::SessionConfig};
+
+/// Browsers cap a cookie at about 4096 bytes including its name and attributes
+const DEFAULT_CHUNK_SIZE: usize = 3800;
+const DEFAULT_MAX_CHUNKS: usize = 4;
+
+/// Key material for sealed cookie sessions, stored in Vault at `secret/idp/session-keys`
+///
+/// The first key seals new cookies, the others only open existing ones. To rotate, put a
+/// new key first and drop the last one once the cookies sealed with it have expired.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionKeys {
+    pub keys: Vec<SessionKeyMaterial>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionKeyMaterial {
+    /// Short identifier written in clear into the cookie, must not contain `.`
+    pub id: String,
+    /// Base64 encoded 32 byte key
+    pub key: String,
+}
+
+impl fmt::Debug for SessionKeyMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeyMaterial")
+            .field("id", &self.id)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// AES-256-GCM keys used to seal and open session cookies
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: Arc<Vec<(String, Aes256Gcm)>>,
+}
+
+impl KeyRing {
+    pub fn new(keys: &SessionKeys) -> Result<Self, OidcError> {
+        if keys.keys.is_empty() {
+            return Err(OidcError::ConfigurationError(
+                "The session key ring is empty".to_string(),
+            ));
+        }
+        let keys = keys
+            .keys
+            .iter()
+            .map(|material| {
+                if material.id.is_empty() || material.id.contains('.') {
+                    return Err(OidcError::ConfigurationError(format!(
+                        "Invalid session key id: {:?}",
+                        material.id
+                    )));
+                }
+                let bytes = STANDARD.decode(&material.key).map_err(|e| {
+                    OidcError::ConfigurationError(format!(
+                        "Session key {} is not base64: {}",
+                        material.id, e
+                    ))
+                })?;
+                let cipher = Aes256Gcm::new_from_slice(&bytes).map_err(|_| {
+                    OidcError::ConfigurationError(format!(
+                        "Session key {} must be 32 bytes",
+                        material.id
+                    ))
+                })?;
+                Ok((material.id.clone(), cipher))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            keys: Arc::new(keys),
+        })
+    }
+
+    /// Encrypt with the primary key, binding the ciphertext to `aad`
+    pub fn seal(&self, aad: &str, plaintext: &[u8]) -> Result<String, OidcError> {
+        let (id, cipher) = &self.keys[0];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| OidcError::SessionError("Failed to seal the session".to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!("{}.{}", id, URL_SAFE_NO_PAD.encode(sealed)))
+    }
+
+    /// Decrypt a value sealed by any key in the ring. Returns `None` if it was tampered with,
+    /// sealed for a different `aad`, or the key is no longer in the ring.
+    pub fn open(&self, aad: &str, sealed: &str) -> Option<Vec<u8>> {
+        let (id, sealed) = sealed.split_once('.')?;
+        let (_, cipher) = self.keys.iter().find(|(key_id, _)| key_id == id)?;
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+        if sealed.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .ok()
+    }
+}
+
+/// What goes into the cookie
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedSession {
+    record: Record,
+    expiry: Option<Expiry>,
+}
+
+/// Session store holding the single record of the current request
+#[derive(Debug, Default)]
+struct RequestStore {
+    record: Mutex<Option<Record>>,
+}
+
+impl RequestStore {
+    fn take(&self) -> Option<Record> {
+        self.record.lock().expect("Request store poisoned").take()
+    }
+}
+
+#[async_trait]
+impl SessionStore for RequestStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        self.save(record).await
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        *self.record.lock().expect("Request store poisoned") = Some(record.clone());
+        Ok(())
+    }
+
+    async fn load(&self, id: &Id) -> session_store::Result<Option<Record>> {
+        Ok(self
+            .record
+            .lock()
+            .expect("Request store poisoned")
+            .as_ref()
+            .filter(|record| &record.id == id)
+            .cloned())
+    }
+
+    async fn delete(&self, id: &Id) -> session_store::Result<()> {
+        let mut record = self.record.lock().expect("Request store poisoned");
+        if record.as_ref().is_some_and(|record| &record.id == id) {
+            *record = None;
+        }
+        Ok(())
+    }
+}
+
+/// Session layer that keeps the whole session in encrypted cookies, with no server-side store
+///
+/// Provides the same [`Session`] extension as a `SessionManagerLayer`, so the OIDC provider,
+/// the middlewares and the handlers work unchanged. The session is sealed with AES-256-GCM
+/// and split over `<name>.0`, `<name>.1`, ... cookies. When it doesn't fit, the access token
+/// is dropped, everything this crate needs comes from the ID token.
+///
+/// Sessions can't be deleted server-side in this mode, use the session registry to revoke
+/// them.
+#[derive(Clone)]
+pub struct CookieSessionLayer {
+    keys: Arc<RwLock<KeyRing>>,
+    config: Arc<SessionConfig>,
+    chunk_size: usize,
+    max_chunks: usize,
+}
+
+impl CookieSessionLayer {
+    pub fn new(keys: KeyRing, config: SessionConfig) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(keys)),
+            config: Arc::new(config),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_chunks: DEFAULT_MAX_CHUNKS,
+        }
+    }
+
+    /// Build the layer with the key ring stored in Vault
+    pub async fn from_vault(vault: &VaultService, config: SessionConfig) -> anyhow::Result<Self> {
+        let keys = KeyRing::new(&vault.get_session_keys().await?)?;
+        Ok(Self::new(keys, config))
+    }
+
+    /// Maximum number of cookies the session may be split over
+    pub fn with_max_chunks(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = max_chunks;
+        self
+    }
+
+    /// Replace the key ring, e.g. after a rotation
+    pub fn set_keys(&self, keys: KeyRing) {
+        *self.keys.write().expect("Key ring poisoned") = keys;
+    }
+
+    /// Reload the key ring from Vault periodically, so rotations are picked up without a restart
+    pub fn spawn_key_refresh(
+        &self,
+        vault: Arc<VaultService>,
+        every: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let layer = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let keys = match vault.get_session_keys().await {
+                    Ok(keys) => keys,
+                    Err(e) => {
+                        tracing::warn!("Failed to reload the session keys from Vault: {}", e);
+                        continue;
+                    }
+                };
+                match KeyRing::new(&keys) {
+                    Ok(keys) => layer.set_keys(keys),
+                    Err(e) => tracing::error!("Invalid session keys in Vault: {}", e),
+                }
+            }
+        })
+    }
+
+    fn chunk_name(&self, index: usize) -> String {
+        format!("{}.{}", self.config.full_cookie_name(), index)
+    }
+
+    /// The sealed value from the request cookies, and how many chunks it was split over
+    fn read_chunks(&self, headers: &HeaderMap) -> (String, usize) {
+        let cookies: HashMap<String, String> = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(Cookie::split_parse)
+            .filter_map(Result::ok)
+            .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+            .collect();
+
+        let mut sealed = String::new();
+        let mut count = 0;
+        while count < self.max_chunks {
+            match cookies.get(&self.chunk_name(count)) {
+                Some(chunk) => sealed.push_str(chunk),
+                None => break,
+            }
+            count += 1;
+        }
+        (sealed, count)
+    }
+
+    fn open(&self, sealed: &str) -> Option<SealedSession> {
+        if sealed.is_empty() {
+            return None;
+        }
+        let keys = self.keys.read().expect("Key ring poisoned").clone();
+        let plaintext = keys.open(&self.config.full_cookie_name(), sealed)?;
+        let session: SealedSession = serde_json::from_slice(&plaintext).ok()?;
+        (session.record.expiry_date > OffsetDateTime::now_utc()).then_some(session)
+    }
+
+    /// Seal the session, dropping the access token if the result doesn't fit the cookies
+    fn seal(&self, mut session: SealedSession) -> Result<String, OidcError> {
+        let keys = self.keys.read().expect("Key ring poisoned").clone();
+        let limit = self.chunk_size * self.max_chunks;
+        let seal = |session: &SealedSession| {
+            let plaintext = serde_json::to_vec(session)
+                .map_err(|e| OidcError::SessionError(format!("Failed to encode session: {}", e)))?;
+            keys.seal(&self.config.full_cookie_name(), &plaintext)
+        };
+
+        let sealed = seal(&session)?;
+        if sealed.len() <= limit {
+            return Ok(sealed);
+        }
+
+        let dropped = match session.record.data.get_mut(TOKEN_KEY) {
+            Some(serde_json::Value::Object(token)) => token.remove("access_token").is_some(),
+            _ => false,
+        };
+        if dropped {
+            tracing::debug!("Session cookie too large, dropped the access token");
+            let sealed = seal(&session)?;
+            if sealed.len() <= limit {
+                return Ok(sealed);
+            }
+        }
+
+        Err(OidcError::SessionError(format!(
+            "Session doesn't fit in {} cookies of {} bytes",
+            self.max_chunks, self.chunk_size
+        )))
+    }
+
+    fn cookie(&self, name: String, value: String) -> Cookie<'static> {
+        Cookie::build((name, value))
+            .path("/")
+            .secure(self.config.secure)
+            .http_only(true)
+            .same_site(self.config.same_site)
+            .build()
+    }
+
+    /// Write the chunks of the sealed session, removing chunks left over from a larger one
+    fn write_cookies(
+        &self,
+        headers: &mut HeaderMap,
+        sealed: Option<(&str, Option<OffsetDateTime>)>,
+        previous_chunks: usize,
+    ) {
+        let mut written = 0;
+        if let Some((sealed, expires)) = sealed {
+            for chunk in sealed.as_bytes().chunks(self.chunk_size) {
+                // The sealed value is ASCII, so chunks are valid UTF-8
+                let chunk = String::from_utf8_lossy(chunk).into_owned();
+                let mut cookie = self.cookie(self.chunk_name(written), chunk);
+                if let Some(expires) = expires {
+                    cookie.set_expires(expires);
+                }
+                append_cookie(headers, &cookie);
+                written += 1;
+            }
+        }
+        for index in written..previous_chunks {
+            let mut cookie = self.cookie(self.chunk_name(index), String::new());
+            cookie.make_removal();
+            append_cookie(headers, &cookie);
+        }
+    }
+}
+
+fn append_cookie(headers: &mut HeaderMap, cookie: &Cookie<'_>) {
+    match HeaderValue::from_str(&cookie.to_string()) {
+        Ok(value) => {
+            headers.append(SET_COOKIE, value);
+        }
+        Err(e) => tracing::error!("Invalid session cookie: {}", e),
+    }
+}
+
+impl<S> Layer<S> for CookieSessionLayer {
+    type Service = CookieSessionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CookieSessionService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CookieSessionService<S> {
+    inner: S,
+    layer: CookieSessionLayer,
+}
+
+impl<S, B> Service<Request<B>> for CookieSessionService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let layer = self.layer.clone();
+        let inner = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, inner);
+
+        Box::pin(async move {
+            let (sealed, previous_chunks) = layer.read_chunks(req.headers());
+            let store = Arc::new(RequestStore::default());
+            let default_expiry = Expiry::OnInactivity(layer.config.inactivity_timeout);
+            let session = match layer.open(&sealed) {
+                Some(sealed) => {
+                    let id = sealed.record.id;
+                    let expiry = sealed.expiry.unwrap_or(default_expiry);
+                    *store.record.lock().expect("Request store poisoned") = Some(sealed.record);
+                    Session::new(Some(id), store.clone(), Some(expiry))
+                }
+                None => Session::new(None, store.clone(), Some(default_expiry)),
+            };
+            let error_handler = req.extensions().get::<AppErrorHandler>().cloned();
+            req.extensions_mut().insert(session.clone());
+
+            let mut response = inner.call(req).await?;
+            // Like `SessionManagerLayer`, inactivity sessions are re-sealed on every response so
+            // the expiry moves forward
+            let refresh = matches!(session.expiry(), Some(Expiry::OnInactivity(_)));
+            if !session.is_modified() && !refresh {
+                return Ok(response);
+            }
+
+            if session.is_empty().await {
+                if session.is_modified() {
+                    layer.write_cookies(response.headers_mut(), None, previous_chunks);
+                }
+                return Ok(response);
+            }
+
+            let sealed = async {
+                session
+                    .save()
+                    .await
+                    .map_err(|e| OidcError::SessionError(e.to_string()))?;
+                let record = store
+                    .take()
+                    .ok_or_else(|| OidcError::SessionError("Session was not saved".to_string()))?;
+                let expires = match session.expiry() {
+                    Some(Expiry::OnSessionEnd) => None,
+                    _ => Some(record.expiry_date),
+                };
+                let sealed = layer.seal(SealedSession {
+                    record,
+                    expiry: session.expiry(),
+                })?;
+                Ok::<_, OidcError>((sealed, expires))
+            }
+            .await;
+
+            match sealed {
+                Ok((sealed, expires)) => {
+                    layer.write_cookies(
+                        response.headers_mut(),
+                        Some((&sealed, expires)),
+                        previous_chunks,
+                    );
+                    Ok(response)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to write the session cookie: {}", e);
+                    let error = AppError::from(e);
+                    Ok(match error_handler {
+                        Some(error_handler) => error_handler.handle_error(error),
+                        None => (error.status_code(), error.to_string()).into_response(),
+                    })
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn key(id: &str, byte: u8) -> SessionKeyMaterial {
+        SessionKeyMaterial {
+            id: id.to_string(),
+            key: STANDARD.encode([byte; 32]),
+        }
+    }
+
+    fn ring(keys: Vec<SessionKeyMaterial>) -> KeyRing {
+        KeyRing::new(&SessionKeys { keys }).unwrap()
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = ring(vec![key("k1", 1)]);
+        let sealed = old.seal("session", b"data").unwrap();
+        assert!(sealed.starts_with("k1."));
+
+        // Cookies sealed with the old key still open after rotation
+        let rotated = ring(vec![key("k2", 2), key("k1", 1)]);
+        assert_eq!(rotated.open("session", &sealed).unwrap(), b"data");
+        assert!(rotated.seal("session", b"data").unwrap().starts_with("k2."));
+
+        // But not once the old key is dropped, for another cookie name, or when tampered with
+        assert!(ring(vec![key("k2", 2)]).open("session", &sealed).is_none());
+        assert!(rotated.open("other", &sealed).is_none());
+        let mut tampered = sealed.clone();
+        tampered.pop();
+        assert!(rotated.open("session", &tampered).is_none());
+    }
+
+    fn cookies_from(response: &Response) -> String {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| Cookie::parse(value.to_str().unwrap().to_string()).ok())
+            .filter(|cookie| !cookie.value().is_empty())
+            .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    #[tokio::test]
+    async fn test_session_round_trip_over_chunks() {
+        let layer = CookieSessionLayer::new(ring(vec![key("k1", 1)]), SessionConfig::insecure());
+        let app = Router::new()
+            .route(
+                "/set",
+                get(|session: Session| async move {
+                    // Big enough to need several cookies
+                    session.insert("big", "x".repeat(6000)).await.unwrap();
+                }),
+            )
+            .route(
+                "/get",
+                get(|session: Session| async move {
+                    session
+                        .get::<String>("big")
+                        .await
+                        .unwrap()
+                        .unwrap_or_default()
+                        .len()
+                        .to_string()
+                }),
+            )
+            .layer(layer);
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/set").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookies = cookies_from(&response);
+        assert!(cookies.contains("aicl-session.0=") && cookies.contains("aicl-session.1="));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/get")
+                    .header(COOKIE, cookies)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "6000");
+    }
+
+    #[tokio::test]
+    async fn test_inactivity_expiry_is_refreshed() {
+        let config = SessionConfig::insecure()
+            .with_inactivity_timeout(tower_sessions::cookie::time::Duration::seconds(1));
+        let layer = CookieSessionLayer::new(ring(vec![key("k1", 1)]), config);
+        let app = Router::new()
+            .route(
+                "/set",
+                get(|session: Session| async move {
+                    session.insert("user", "alice").await.unwrap();
+                }),
+            )
+            .route(
+                "/get",
+                get(|session: Session| async move {
+                    session
+                        .get::<String>("user")
+                        .await
+                        .unwrap()
+                        .unwrap_or_default()
+                }),
+            )
+            .layer(layer);
+        let get_with = |cookies: String| {
+            Request::builder()
+                .uri("/get")
+                .header(COOKIE, cookies)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/set").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let first = cookies_from(&response);
+
+        // More than half the timeout later, reading the session re-seals it
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let response = app.clone().oneshot(get_with(first.clone())).await.unwrap();
+        let refreshed = cookies_from(&response);
+        assert!(!refreshed.is_empty());
+
+        // The original cookie has expired by now, the refreshed one hasn't
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let response = app.clone().oneshot(get_with(first)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "");
+
+        let response = app.oneshot(get_with(refreshed)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "alice");
+    }
+
+    #[test]
+    fn test_oversized_session_drops_access_token() {
+        let layer = CookieSessionLayer::new(ring(vec![key("k1", 1)]), SessionConfig::insecure())
+            .with_max_chunks(1);
+        let mut record = Record {
+            id: Id::default(),
+            data: HashMap::new(),
+            expiry_date: OffsetDateTime::now_utc()
+                + tower_sessions::cookie::time::Duration::hours(1),
+        };
+        record.data.insert(
+            TOKEN_KEY.to_string(),
+            serde_json::json!({ "id_token": "header.payload.signature", "access_token": "a".repeat(4000) }),
+        );
+
+        let sealed = layer
+            .seal(SealedSession {
+                record,
+                expiry: None,
+            })
+            .expect("Session should fit without the access token");
+        let opened = layer.open(&sealed).unwrap();
+        assert!(opened.record.data[TOKEN_KEY].get("access_token").is_none());
+        assert!(opened.record.data[TOKEN_KEY].get("id_token").is_some());
+    }
+}
@@ -21,3 +21,17 @@ pub enum OidcError {
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
+
+impl OidcError {
+    /// Stable, machine-readable code for this error, suitable for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::AuthenticationError(_) => "oidc.authentication_failed",
+            Self::ValidationError(_) => "oidc.validation_failed",
+            Self::SessionError(_) => "oidc.session_error",
+            Self::ConfigurationError(_) => "oidc.configuration_error",
+            Self::NetworkError(_) => "oidc.network_error",
+            Self::Unknown(_) => "oidc.unknown",
+        }
+    }
+}
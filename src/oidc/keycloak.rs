@@ -1,4 +1,7 @@
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    sync::{Arc, RwLock},
+};
 
 use axum::http::{request, Uri};
 use openidconnect::{
@@ -10,16 +13,19 @@ use openidconnect::{
         CoreSubjectIdentifierType, CoreTokenIntrospectionResponse, CoreTokenType,
     },
     AccessToken, AdditionalProviderMetadata, Client, ClientId, ClientSecret, CsrfToken,
-    EmptyAdditionalClaims, EmptyExtraTokenFields, EndpointMaybeSet, EndpointNotSet, EndpointSet,
+    EmptyAdditionalClaims, EndpointMaybeSet, EndpointNotSet, EndpointSet, ExtraTokenFields,
     IdToken, IdTokenFields, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeVerifier,
     ProviderMetadata, RefreshToken, StandardErrorResponse, StandardTokenResponse, TokenResponse,
 };
 use serde::{Deserialize, Serialize};
-use tower_sessions::Session;
+use tower_sessions::{
+    cookie::time::{Duration, OffsetDateTime},
+    Expiry, Session,
+};
 use url::Url;
 use uuid::Uuid;
 
-use crate::idp::admin::IdpAdmin;
+use crate::{database::sessions::SESSION_REGISTRY_KEY, idp::admin::IdpAdmin};
 
 use super::ext::OidcError;
 
@@ -45,10 +51,19 @@ pub type KeycloakMetadata = ProviderMetadata<
     CoreSubjectIdentifierType,
 >;
 
+/// Keycloak specific fields of the token response
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeycloakExtraTokenFields {
+    /// Lifetime of the refresh token in seconds
+    pub refresh_expires_in: Option<u64>,
+}
+
+impl ExtraTokenFields for KeycloakExtraTokenFields {}
+
 pub type KeycloakTokenResponse = StandardTokenResponse<
     IdTokenFields<
         EmptyAdditionalClaims,
-        EmptyExtraTokenFields,
+        KeycloakExtraTokenFields,
         CoreGenderClaim,
         CoreJweContentEncryptionAlgorithm,
         CoreJwsSigningAlgorithm,
@@ -98,12 +113,74 @@ pub struct KeyCloakToken {
         CoreJweContentEncryptionAlgorithm,
         CoreJwsSigningAlgorithm,
     >,
-    pub access_token: AccessToken,
+    /// Dropped by the cookie session mode when the session gets too large
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<AccessToken>,
 }
 
 pub const SESSION_KEY: &str = "aicl-oidc-keycloak-session";
 pub const TOKEN_KEY: &str = "aicl-oidc-keycloak-token";
 pub const REFRESH_KEY: &str = "aicl-oidc-keycloak-refresh";
+/// Session key holding when the refresh token of the login expires, as a Unix timestamp
+pub const REFRESH_EXPIRY_KEY: &str = "aicl-oidc-keycloak-refresh-expiry";
+
+/// Give the session a new id, keeping its data
+///
+/// Called after login and logout. Applications should also call it whenever the privileges
+/// behind a session change, such as after step-up authentication or when an admin starts or
+/// stops impersonating a user, so a session id planted or leaked before the change is useless
+/// after it.
+pub async fn cycle_session_id(session: &Session) -> Result<(), OidcError> {
+    session
+        .cycle_id()
+        .await
+        .map_err(|e| OidcError::SessionError(format!("Failed to cycle session id: {}", e)))
+}
+
+// Store the tokens of a completed login in the session and give it a new id
+async fn store_login(
+    session: &Session,
+    token: KeyCloakToken,
+    refresh_expires_in: Option<u64>,
+) -> Result<(), OidcError> {
+    // Don't let the session outlive the refresh token, the user has to log in again then.
+    // An idle session that expires later is checked against it in `authenticate`.
+    if let Some(refresh_expires_in) = refresh_expires_in {
+        let refresh_expiry =
+            OffsetDateTime::now_utc() + Duration::seconds(refresh_expires_in as i64);
+        if session.expiry_date() > refresh_expiry {
+            session.set_expiry(Some(Expiry::AtDateTime(refresh_expiry)));
+        }
+        session
+            .insert(REFRESH_EXPIRY_KEY, refresh_expiry.unix_timestamp())
+            .await
+            .map_err(|e| OidcError::SessionError(format!("Failed to save token expiry: {}", e)))?;
+    }
+
+    session
+        .insert(TOKEN_KEY, token)
+        .await
+        .map_err(|e| OidcError::SessionError(format!("Failed to save token data: {}", e)))?;
+    // Registered by the first request, concurrent ones all find this id
+    session
+        .insert(SESSION_REGISTRY_KEY, Uuid::new_v4())
+        .await
+        .map_err(|e| OidcError::SessionError(format!("Failed to save session id: {}", e)))?;
+
+    // The session is now authenticated, don't keep the id it had before
+    cycle_session_id(session).await
+}
+
+// Whether the refresh token of the session's login expired, the session is over then
+async fn login_expired(session: &Session) -> Result<bool, OidcError> {
+    let refresh_expiry: Option<i64> = session.get(REFRESH_EXPIRY_KEY).await.map_err(|e| {
+        OidcError::SessionError(format!(
+            "Failed to get token expiry from session store: {}",
+            e
+        ))
+    })?;
+    Ok(refresh_expiry.is_some_and(|expiry| expiry <= OffsetDateTime::now_utc().unix_timestamp()))
+}
 
 pub struct KeycloakOidcBuilder {
     application_base_url: String,
@@ -145,14 +222,19 @@ impl KeycloakOidcBuilder {
             .clone();
         let client_id = ClientId::new(self.client_id.clone());
         let client_secret = self.client_secret.map(ClientSecret::new);
-        let oidc_client =
-            KeycloakOidcClient::from_provider_metadata(provider_metadata, client_id, client_secret);
+        let oidc_client = KeycloakOidcClient::from_provider_metadata(
+            provider_metadata.clone(),
+            client_id.clone(),
+            client_secret,
+        );
         let application_base_url = Url::parse(&self.application_base_url)?;
         Ok(KeycloakOidcProvider {
             application_base_url,
             end_session_endpoint,
             http_client,
-            oidc_client,
+            provider_metadata,
+            client_id,
+            oidc_client: RwLock::new(oidc_client),
             scopes: self.scopes,
         })
     }
@@ -161,12 +243,39 @@ impl KeycloakOidcBuilder {
 pub struct KeycloakOidcProvider {
     application_base_url: Url,
     end_session_endpoint: Url,
-    oidc_client: KeycloakOidcClient,
+    provider_metadata: KeycloakMetadata,
+    client_id: ClientId,
+    // Rebuilt when the client secret is rotated
+    oidc_client: RwLock<KeycloakOidcClient>,
     http_client: reqwest::Client,
     scopes: Vec<String>,
 }
 
 impl KeycloakOidcProvider {
+    fn oidc_client(&self) -> KeycloakOidcClient {
+        self.oidc_client
+            .read()
+            .expect("OIDC client poisoned")
+            .clone()
+    }
+
+    /// The OIDC client id the provider logs users in with
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_str()
+    }
+
+    /// Use a new client secret for the token exchanges from now on
+    ///
+    /// Logins started before keep working, only the code exchange uses the secret.
+    pub fn set_client_secret(&self, client_secret: Option<String>) {
+        let oidc_client = KeycloakOidcClient::from_provider_metadata(
+            self.provider_metadata.clone(),
+            self.client_id.clone(),
+            client_secret.map(ClientSecret::new),
+        );
+        *self.oidc_client.write().expect("OIDC client poisoned") = oidc_client;
+    }
+
     pub fn uri_to_url(&self, uri: &Uri) -> Result<Url, url::ParseError> {
         // Check if the URI has a scheme
         if uri.scheme().is_some() {
@@ -176,7 +285,7 @@ impl KeycloakOidcProvider {
             // If the URI has an authority part but no scheme, add the scheme
             if uri.authority().is_some() {
                 // It has authority but no scheme, add scheme and parse
-                let uri_str = format!("http://{}", uri.to_string());
+                let uri_str = format!("http://{}", uri);
                 Url::parse(&uri_str)
             } else {
                 // Fully relative, join with base URL
@@ -196,6 +305,15 @@ impl KeycloakOidcProvider {
         session: &Session,
         idp: &Arc<IdpAdmin>,
     ) -> Result<(), OidcError> {
+        if login_expired(session).await? {
+            tracing::debug!("The refresh token of the login expired, ending the session");
+            session
+                .flush()
+                .await
+                .map_err(|e| OidcError::SessionError(format!("Failed to end session: {}", e)))?;
+            return Ok(());
+        }
+
         let login_session: Option<AiclOidcSession> =
             session.get(SESSION_KEY).await.map_err(|e| {
                 OidcError::SessionError(format!(
@@ -223,7 +341,7 @@ impl KeycloakOidcProvider {
                 tracing::debug!("We have a session, but no identity token or refresh token");
                 // We have no identity token or refresh token, so we can't do anything with the session.
             }
-            (Some(login_session), None, Some(refresh_token)) => {
+            (Some(_), None, Some(_)) => {
                 tracing::debug!("We have a session, no identity token, and a refresh token. Refreshing and aquireing a new token");
                 todo!("Implement token refresh logic here")
             }
@@ -231,7 +349,10 @@ impl KeycloakOidcProvider {
                 tracing::debug!("We have a session and identity token");
                 let verified_claims = id_token
                     .id_token
-                    .claims(&self.oidc_client.id_token_verifier(), &login_session.nonce)
+                    .claims(
+                        &self.oidc_client().id_token_verifier(),
+                        &login_session.nonce,
+                    )
                     .unwrap();
 
                 let user_id = verified_claims.subject().parse::<Uuid>().map_err(|e| {
@@ -286,8 +407,8 @@ impl KeycloakOidcProvider {
         let redirect_url = self.uri_to_url(redirect_uri).unwrap();
 
         // Exchange the authorization code for tokens
-        let token_response = self
-            .oidc_client
+        let oidc_client = self.oidc_client();
+        let token_response = oidc_client
             .exchange_code(openidconnect::AuthorizationCode::new(code.to_string()))
             .unwrap()
             .set_redirect_uri(Cow::Owned(openidconnect::RedirectUrl::from_url(
@@ -304,20 +425,22 @@ impl KeycloakOidcProvider {
             .ok_or_else(|| OidcError::AuthenticationError("No ID token in response".to_string()))?
             .clone();
 
-        let access_token = token_response.access_token().clone();
+        let access_token = Some(token_response.access_token().clone());
 
         // Store the tokens in the session
         let token = KeyCloakToken {
             id_token,
             access_token,
         };
-
-        session
-            .insert(TOKEN_KEY, token)
-            .await
-            .map_err(|e| OidcError::SessionError(format!("Failed to save token data: {}", e)))?;
-
-        Ok(())
+        store_login(
+            session,
+            token,
+            token_response
+                .extra_fields()
+                .extra_fields()
+                .refresh_expires_in,
+        )
+        .await
     }
 
     pub async fn start_auth(
@@ -331,8 +454,8 @@ impl KeycloakOidcProvider {
         // Calculate the redirect URI
         let redirect_uri = self.uri_to_url(redirect_uri).unwrap();
         // Build the authorization URI
-        let auth_url = self
-            .oidc_client
+        let oidc_client = self.oidc_client();
+        let auth_url = oidc_client
             .authorize_url(
                 openidconnect::AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
                 CsrfToken::new_random,
@@ -364,8 +487,8 @@ impl KeycloakOidcProvider {
             .map_err(|e| OidcError::SessionError(format!("Failed to save session data: {}", e)))?;
 
         // Return the authorization URL
-        Ok(axum::http::Uri::from_maybe_shared(auth_url.to_string())
-            .map_err(|e| OidcError::Unknown(format!("Invalid auth URL: {}", e)))?)
+        axum::http::Uri::from_maybe_shared(auth_url.to_string())
+            .map_err(|e| OidcError::Unknown(format!("Invalid auth URL: {}", e)))
     }
 
     pub async fn logout(&self, session: &Session) -> Result<axum::http::Uri, OidcError> {
@@ -394,6 +517,8 @@ impl KeycloakOidcProvider {
                 OidcError::SessionError(format!("Failed to remove refresh token: {}", e))
             })?;
 
+        cycle_session_id(session).await?;
+
         // Create a redirect URL to the Keycloak end session endpoint if available
         if let Some(token) = token {
             // Build the end_session_endpoint URL with ID token hint and post_logout_redirect_uri
@@ -411,10 +536,8 @@ impl KeycloakOidcProvider {
                 .query_pairs_mut()
                 .append_pair("post_logout_redirect_uri", &redirect_uri);
 
-            return Ok(
-                axum::http::Uri::from_maybe_shared(end_session_url.to_string())
-                    .map_err(|e| OidcError::Unknown(format!("Invalid logout URL: {}", e)))?,
-            );
+            return axum::http::Uri::from_maybe_shared(end_session_url.to_string())
+                .map_err(|e| OidcError::Unknown(format!("Invalid logout URL: {}", e)));
         }
 
         // If we don't have a token or end_session_endpoint, just return to the application root
@@ -425,9 +548,9 @@ impl KeycloakOidcProvider {
 #[cfg(test)]
 mod tests {
     use axum::http::Request;
-    use tower_sessions::MemoryStore;
+    use tower_sessions::{MemoryStore, SessionStore};
 
-    use crate::idp::ext::IdpConfig;
+    use crate::{axum::handlers::LOGIN_REDIRECT_KEY, idp::ext::IdpConfig};
 
     use super::*;
 
@@ -543,6 +666,139 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_cycle_session_id_keeps_data() {
+        let session_store = Arc::new(MemoryStore::default());
+        let session = Session::new(None, session_store.clone(), None);
+        session
+            .insert(LOGIN_REDIRECT_KEY, "/dashboard")
+            .await
+            .unwrap();
+        session.save().await.unwrap();
+        let old_id = session.id().expect("Saved session should have an id");
+
+        cycle_session_id(&session)
+            .await
+            .expect("Failed to cycle session id");
+        session.save().await.unwrap();
+        let new_id = session.id().expect("Cycled session should have an id");
+
+        assert_ne!(old_id, new_id, "Session id should change");
+        assert!(
+            session_store.load(&old_id).await.unwrap().is_none(),
+            "Old session id should no longer be valid"
+        );
+        let redirect: Option<String> = session.get(LOGIN_REDIRECT_KEY).await.unwrap();
+        assert_eq!(redirect.as_deref(), Some("/dashboard"));
+
+        // Loading the new id from the store gets the same data
+        let reloaded = Session::new(Some(new_id), session_store, None);
+        let redirect: Option<String> = reloaded.get(LOGIN_REDIRECT_KEY).await.unwrap();
+        assert_eq!(redirect.as_deref(), Some("/dashboard"));
+    }
+
+    // An ID token that parses, its signature is never checked here
+    fn test_token() -> KeyCloakToken {
+        KeyCloakToken {
+            id_token: "eyJhbGciOiJSUzI1NiJ9.eyJpc3MiOiJodHRwOi8va2V5Y2xvYWs6ODA4MC9yZWFsbXMvYXBwLXJlY\
+                       WxtIiwiYXVkIjoicnVzdC1hcHAiLCJzdWIiOiIwMDAwMDAwMC0wMDAwLTAwMDAtMDAwMC0wMDAwMDA\
+                       wMDAwMDEiLCJleHAiOjQxMDI0NDQ4MDAsImlhdCI6MTcwMDAwMDAwMH0.c2ln"
+                .parse()
+                .expect("Failed to parse the ID token"),
+            access_token: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_cycles_session_id() {
+        let session_store = Arc::new(MemoryStore::default());
+        let session = Session::new(
+            None,
+            session_store.clone(),
+            Some(Expiry::OnInactivity(Duration::minutes(30))),
+        );
+        session.insert(SESSION_KEY, "login").await.unwrap();
+        session.save().await.unwrap();
+        let old_id = session.id().expect("Saved session should have an id");
+
+        // What `handle_callback` does once the code is exchanged
+        store_login(&session, test_token(), Some(3600))
+            .await
+            .expect("Failed to store the login");
+        session.save().await.unwrap();
+
+        assert_ne!(session.id(), Some(old_id), "Session id should change");
+        assert!(
+            session_store.load(&old_id).await.unwrap().is_none(),
+            "Old session id should no longer be valid"
+        );
+        assert!(session
+            .get::<KeyCloakToken>(TOKEN_KEY)
+            .await
+            .unwrap()
+            .is_some());
+        // Requests register the session under the id handed out here
+        assert!(session
+            .get::<Uuid>(SESSION_REGISTRY_KEY)
+            .await
+            .unwrap()
+            .is_some());
+        // The idle timeout is shorter, the refresh token is checked on later requests
+        assert_eq!(
+            session.expiry(),
+            Some(Expiry::OnInactivity(Duration::minutes(30)))
+        );
+        assert!(!login_expired(&session).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_session_ends_with_refresh_token() {
+        let session_store = Arc::new(MemoryStore::default());
+        let session = Session::new(
+            None,
+            session_store,
+            Some(Expiry::OnInactivity(Duration::minutes(30))),
+        );
+
+        // A refresh token that expires before the idle timeout caps the session
+        store_login(&session, test_token(), Some(60)).await.unwrap();
+        assert!(matches!(session.expiry(), Some(Expiry::AtDateTime(_))));
+        assert!(session.expiry_date() <= OffsetDateTime::now_utc() + Duration::seconds(60));
+
+        session
+            .insert(
+                REFRESH_EXPIRY_KEY,
+                OffsetDateTime::now_utc().unix_timestamp() - 1,
+            )
+            .await
+            .unwrap();
+        assert!(login_expired(&session).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_logout_cycles_session_id() {
+        let session_store = Arc::new(MemoryStore::default());
+        let session = Session::new(None, session_store.clone(), None);
+        let (provider, _) = create_test_provider().await;
+
+        let redirect_uri = "http://localhost:4040/callback".parse::<Uri>().unwrap();
+        provider
+            .start_auth(&session, &redirect_uri)
+            .await
+            .expect("Failed to start auth");
+        session.save().await.unwrap();
+        let old_id = session.id().expect("Saved session should have an id");
+
+        provider.logout(&session).await.expect("Logout failed");
+        session.save().await.unwrap();
+
+        assert_ne!(session.id(), Some(old_id), "Session id should change");
+        assert!(
+            session_store.load(&old_id).await.unwrap().is_none(),
+            "Old session id should no longer be valid"
+        );
+    }
+
     #[tokio::test]
     async fn test_session_setup_for_login() {
         // Create the session store and a session
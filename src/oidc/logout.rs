@@ -5,10 +5,12 @@ use axum::{
     response::{IntoResponse, Redirect, Response},
 };
 use futures_util::future::BoxFuture;
-use reqwest::StatusCode;
 use tower_sessions::Session;
 
-use crate::AiclIdentifier;
+use crate::{
+    axum::error::{handle_error, required_extension},
+    AiclIdentifier,
+};
 
 #[derive(Clone)]
 pub struct LogoutService {}
@@ -29,28 +31,25 @@ where
     }
 
     fn call(&mut self, req: Request<B>) -> Self::Future {
-        let identifier = req
-            .extensions()
-            .get::<AiclIdentifier>()
-            .expect("Identifier not found")
-            .clone();
-
-        // Get the session from the request extensions
-        let session = match req.extensions().get::<Session>() {
-            Some(session) => session.clone(),
-            None => {
-                return Box::pin(
-                    async move { Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()) },
-                );
+        let extensions = req.extensions();
+        let (identifier, session) = match (
+            required_extension::<AiclIdentifier>(extensions),
+            required_extension::<Session>(extensions),
+        ) {
+            (Ok(identifier), Ok(session)) => (identifier, session),
+            (Err(response), _) | (_, Err(response)) => {
+                return Box::pin(async move { Ok(response) })
             }
         };
 
+        let (parts, _) = req.into_parts();
+
         Box::pin(async move {
-            match identifier.oidc.logout(&session).await {
+            match identifier.logout(&session).await {
                 Ok(logout_uri) => Ok(Redirect::to(logout_uri.to_string().as_str()).into_response()),
                 Err(e) => {
                     tracing::error!("Logout failed: {}", e);
-                    Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                    Ok(handle_error(&parts.extensions, e))
                 }
             }
         })
@@ -1,3 +1,4 @@
+pub mod cookie_session;
 pub mod ext;
 pub mod keycloak;
 pub mod logout;
@@ -1,5 +1,121 @@
-use tower_sessions::CachingSessionStore;
+use sqlx::PgPool;
+use tower_sessions::{
+    cookie::{time::Duration, SameSite},
+    CachingSessionStore, Expiry, SessionManagerLayer, SessionStore,
+};
 use tower_sessions_moka_store::MokaStore;
 use tower_sessions_sqlx_store::PostgresStore;
 
 pub type AiclSessionStore = CachingSessionStore<MokaStore, PostgresStore>;
+
+/// Cookie and expiry settings for the session layer
+///
+/// The defaults are meant for production: the cookie is `Secure`, `HttpOnly` and uses the
+/// `__Host-` prefix, which pins it to the exact origin. `SameSite=Lax` is the strictest
+/// setting that still sends the cookie on the redirect back from the identity provider.
+///
+/// The inactivity timeout applies for the whole session. After login the session also ends
+/// when the refresh token expires, so it never outlives its credentials.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Cookie name, without the `__Host-` prefix
+    pub cookie_name: String,
+    /// Only send the cookie over HTTPS. Turning this off also drops the `__Host-` prefix,
+    /// which browsers reject on insecure cookies.
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Session lifetime without requests. Defaults to 30 minutes, the default SSO session
+    /// idle timeout of a Keycloak realm, so set it when the realm uses another value.
+    pub inactivity_timeout: Duration,
+    /// Number of sessions kept in the in-memory cache in front of Postgres
+    pub cache_capacity: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: "aicl-session".to_string(),
+            secure: true,
+            same_site: SameSite::Lax,
+            inactivity_timeout: Duration::minutes(30),
+            cache_capacity: 10_000,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Settings for local development over plain HTTP
+    pub fn insecure() -> Self {
+        Self {
+            secure: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_cookie_name(mut self, cookie_name: impl Into<String>) -> Self {
+        self.cookie_name = cookie_name.into();
+        self
+    }
+
+    pub fn with_inactivity_timeout(mut self, inactivity_timeout: Duration) -> Self {
+        self.inactivity_timeout = inactivity_timeout;
+        self
+    }
+
+    pub fn with_cache_capacity(mut self, cache_capacity: u64) -> Self {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// The cookie name actually sent to the browser
+    pub fn full_cookie_name(&self) -> String {
+        if self.secure {
+            format!("__Host-{}", self.cookie_name)
+        } else {
+            self.cookie_name.clone()
+        }
+    }
+
+    /// Apply these settings to a session layer over any store
+    pub fn layer<Store: SessionStore>(&self, store: Store) -> SessionManagerLayer<Store> {
+        SessionManagerLayer::new(store)
+            .with_name(self.full_cookie_name())
+            .with_secure(self.secure)
+            .with_http_only(true)
+            .with_same_site(self.same_site)
+            .with_path("/")
+            .with_expiry(Expiry::OnInactivity(self.inactivity_timeout))
+    }
+
+    /// Postgres backed session layer with a moka cache in front of it
+    ///
+    /// Creates the session table if it doesn't exist yet.
+    pub async fn postgres_layer(
+        &self,
+        pool: PgPool,
+    ) -> anyhow::Result<SessionManagerLayer<AiclSessionStore>> {
+        let postgres = PostgresStore::new(pool);
+        postgres.migrate().await?;
+        let cache = MokaStore::new(Some(self.cache_capacity));
+        Ok(self.layer(CachingSessionStore::new(cache, postgres)))
+    }
+
+    /// In-memory session layer for development, sessions are lost on restart
+    pub fn memory_layer(&self) -> SessionManagerLayer<MokaStore> {
+        self.layer(MokaStore::new(Some(self.cache_capacity)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_prefix_only_on_secure_cookies() {
+        assert_eq!(
+            SessionConfig::default().full_cookie_name(),
+            "__Host-aicl-session"
+        );
+        assert_eq!(SessionConfig::insecure().full_cookie_name(), "aicl-session");
+    }
+}
@@ -120,7 +120,7 @@ impl AuthTestUtils {
             .ok_or_else(|| anyhow::anyhow!("No ID token in response"))?
             .clone();
 
-        let access_token = token_response.access_token().clone();
+        let access_token = Some(token_response.access_token().clone());
 
         let keycloak_token = KeyCloakToken {
             id_token,
@@ -163,12 +163,12 @@ impl AuthTestUtils {
         let api_token = match identity.role {
             crate::Role::Admin => Some(
                 self.vault
-                    .create_api_token_with_oidc(&identity, &keycloak_token)
+                    .create_api_token_with_oidc(&identity, &keycloak_token, &Default::default())
                     .await?,
             ),
             crate::Role::Captain => Some(
                 self.vault
-                    .create_api_token_with_oidc(&identity, &keycloak_token)
+                    .create_api_token_with_oidc(&identity, &keycloak_token, &Default::default())
                     .await?,
             ),
             crate::Role::Advisor => None,
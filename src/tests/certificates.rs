@@ -0,0 +1,72 @@
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    database::client_certificates::ClientCertificateRegistry,
+    identifier::certificates::{check_certificate_team, check_client_certificate},
+    vault::pki::{IssuedCertificate, VaultCertificate},
+    AiclIdentity, Role, TeamIdentity,
+};
+
+fn issued(serial_number: &str) -> IssuedCertificate {
+    IssuedCertificate {
+        serial_number: serial_number.to_string(),
+        certificate: String::new(),
+        private_key: String::new(),
+        ca_chain: vec![],
+        expires_at: (OffsetDateTime::now_utc().unix_timestamp() + 3600) as u64,
+    }
+}
+
+fn agent(id: Uuid, team: Option<&str>) -> AiclIdentity {
+    AiclIdentity {
+        id,
+        email: "agent@test.com".to_string(),
+        username: "agent".to_string(),
+        team: team.map(|name| TeamIdentity {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+        }),
+        institution: None,
+        role: Role::Student,
+    }
+}
+
+#[sqlx::test]
+async fn test_client_certificate_decision(pool: PgPool) -> anyhow::Result<()> {
+    let registry = ClientCertificateRegistry::new(pool);
+    let user_id = Uuid::new_v4();
+    let der = b"certificate".to_vec();
+    let vault = |der: &[u8], revoked| VaultCertificate {
+        der: der.to_vec(),
+        revoked,
+    };
+
+    // Unknown to the registry, whatever Vault says
+    let record = registry.get_active("0b:01").await?;
+    assert!(check_client_certificate(&der, record, Some(&vault(&der, false))).is_err());
+
+    registry
+        .record(user_id, "Team1", Uuid::new_v4(), None, &issued("0b:01"))
+        .await?;
+    let record = registry.get_active("0b:01").await?;
+    let accepted = check_client_certificate(&der, record.clone(), Some(&vault(&der, false)))?;
+    assert_eq!(accepted.user_id, user_id);
+
+    // Vault must have issued this very certificate, and not revoked it
+    assert!(check_client_certificate(&der, record.clone(), None).is_err());
+    assert!(check_client_certificate(&der, record.clone(), Some(&vault(b"other", false))).is_err());
+    assert!(check_client_certificate(&der, record, Some(&vault(&der, true))).is_err());
+
+    // Only while the user is in the team it was issued for
+    assert!(check_certificate_team(&accepted, &agent(user_id, Some("Team1"))).is_ok());
+    assert!(check_certificate_team(&accepted, &agent(user_id, Some("Team2"))).is_err());
+    assert!(check_certificate_team(&accepted, &agent(user_id, None)).is_err());
+
+    // Revoked in the registry
+    assert!(registry.mark_revoked("Team1", "0b:01").await?);
+    let record = registry.get_active("0b:01").await?;
+    assert!(check_client_certificate(&der, record, Some(&vault(&der, false))).is_err());
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use crate::{
     errors::{AppError, JsonErrorHandler},
-    AiclIdentifier, AiclIdentity, AppErrorHandler, Role,
+    AiclIdentifier, AiclIdentity, AiclRouterExt, AppErrorHandler, Role,
 };
 use axum::{
     extract::{Path, State},
@@ -131,9 +131,7 @@ pub async fn router(identifier: AiclIdentifier) -> Router {
         // Advisor-only endpoints
         .route("/api/advisors", get(advisor_only))
         // Authentication layers
-        .layer(identifier.api_token_layer())
-        .layer(identifier.identifier_layer())
-        .layer(error_handler.layer())
+        .with_aicl_api_token_auth(&identifier, &error_handler)
         .with_state(app_state)
 }
 
@@ -1,6 +1,10 @@
 mod auth;
+mod certificates;
+#[allow(clippy::map_entry, clippy::needless_borrow)]
 mod permission;
+mod router;
 mod error;
+#[allow(clippy::needless_return, clippy::needless_borrows_for_generic_args)]
 mod harness;
 
 pub use harness::router;
@@ -0,0 +1,134 @@
+use axum::{routing::post, Json, Router};
+use axum_test::TestServer;
+use sqlx::PgPool;
+use tower_sessions::Session;
+
+use crate::{
+    database::api_tokens::ApiTokenRecord,
+    errors::JsonErrorHandler,
+    oidc::{
+        keycloak::{KeyCloakToken, TOKEN_KEY},
+        session::SessionConfig,
+    },
+    test_utils::TestUser,
+    AiclIdentifier, AiclIdentity, AiclRouterExt, ApiToken, AppErrorHandler,
+};
+
+// The auth routes, plus a route that stores an OIDC token in the session in place of the
+// browser login
+fn server(identifier: &AiclIdentifier) -> TestServer {
+    let error_handler = AppErrorHandler::new(JsonErrorHandler::default());
+    let router = Router::new()
+        .route(
+            "/test/session",
+            post(|session: Session, Json(token): Json<KeyCloakToken>| async move {
+                session.insert(TOKEN_KEY, token).await.unwrap();
+            }),
+        )
+        .merge(identifier.auth_router())
+        .with_aicl_auth(
+            identifier,
+            &error_handler,
+            SessionConfig::insecure().memory_layer(),
+        );
+    let mut server = TestServer::new(router).unwrap();
+    server.save_cookies();
+    server
+}
+
+#[tracing_test::traced_test]
+#[sqlx::test]
+async fn test_auth_router_anonymous(pool: PgPool) {
+    let aicl_identifier = AiclIdentifier::from_env(pool)
+        .await
+        .expect("Failed to get AiclIdentifier from env");
+    let server = server(&aicl_identifier);
+
+    // Login redirects to the identity provider
+    let response = server.get("/auth/login").await;
+    assert!(response.status_code().is_redirection());
+    let location = response.header("Location");
+    assert!(location
+        .to_str()
+        .unwrap()
+        .contains("/protocol/openid-connect/auth"));
+
+    // A callback without a login in progress is rejected
+    let response = server
+        .get("/auth/callback")
+        .add_query_param("code", "code")
+        .add_query_param("state", "state")
+        .expect_failure()
+        .await;
+    assert!(!response.status_code().is_redirection());
+    server.get("/auth/callback").expect_failure().await;
+
+    // Anonymous users have no identity and no tokens
+    server.get("/auth/me").await.assert_status_unauthorized();
+    server.get("/auth/tokens").await.assert_status_unauthorized();
+    server
+        .post("/auth/tokens")
+        .json(&serde_json::json!({}))
+        .await
+        .assert_status_unauthorized();
+    server
+        .delete("/auth/tokens/accessor")
+        .await
+        .assert_status_unauthorized();
+
+    // Tokens are only created by POST
+    server.get("/auth/token").await.assert_status_not_found();
+
+    // Logout changes state, so it isn't reachable by a plain link
+    server
+        .get("/auth/logout")
+        .expect_failure()
+        .await
+        .assert_status(axum::http::StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tracing_test::traced_test]
+#[sqlx::test]
+async fn test_auth_router_session(pool: PgPool) {
+    let aicl_identifier = AiclIdentifier::from_env(pool)
+        .await
+        .expect("Failed to get AiclIdentifier from env");
+    let auth_utils = aicl_identifier.test_utils().await;
+    let server = server(&aicl_identifier);
+
+    let captain_user = TestUser {
+        username: "captain1".to_string(),
+        password: "captain".to_string(),
+        expected_team: Some("Team1".to_string()),
+        expected_role: "captain",
+    };
+    let session = auth_utils
+        .authenticate_user(&captain_user)
+        .await
+        .expect("Failed to authenticate captain");
+    server.post("/test/session").json(&session.token).await;
+
+    // The session identifies the user
+    let identity = server.get("/auth/me").await.json::<AiclIdentity>();
+    assert_eq!(identity.username, "captain1");
+
+    // Create, list and revoke an API token
+    let token = server
+        .post("/auth/tokens")
+        .json(&serde_json::json!({ "name": "router-test" }))
+        .await
+        .json::<ApiToken>();
+    let tokens = server.get("/auth/tokens").await.json::<Vec<ApiTokenRecord>>();
+    assert!(tokens.iter().any(|record| record.accessor == token.accessor));
+    server
+        .delete(&format!("/auth/tokens/{}", token.accessor))
+        .await
+        .assert_status_ok();
+    let tokens = server.get("/auth/tokens").await.json::<Vec<ApiTokenRecord>>();
+    assert!(!tokens.iter().any(|record| record.accessor == token.accessor));
+
+    // Logout ends the session
+    let response = server.post("/auth/logout").await;
+    assert!(response.status_code().is_redirection());
+    server.get("/auth/me").await.assert_status_unauthorized();
+}
@@ -0,0 +1,201 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+
+use super::VaultError;
+
+/// Default location of the service account token inside a Kubernetes pod
+pub const KUBERNETES_JWT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// How the service authenticates to Vault as itself
+///
+/// Credentials that live in files are read on every login, so rotated secrets are picked up
+/// the next time the service logs in again.
+#[derive(Clone)]
+pub enum VaultAuth {
+    /// A static token, e.g. `myroot` on the development server
+    Token(String),
+    /// AppRole login with the role and secret ids read from files
+    AppRole {
+        mount: String,
+        role_id_file: PathBuf,
+        secret_id_file: PathBuf,
+    },
+    /// Kubernetes login with the pod's service account token
+    Kubernetes {
+        mount: String,
+        role: String,
+        jwt_file: PathBuf,
+    },
+    /// JWT login with a token issued by any trusted identity provider
+    Jwt {
+        mount: String,
+        role: String,
+        jwt_file: PathBuf,
+    },
+}
+
+impl fmt::Debug for VaultAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Token(_) => f.debug_tuple("Token").field(&"<redacted>").finish(),
+            Self::AppRole {
+                mount,
+                role_id_file,
+                secret_id_file,
+            } => f
+                .debug_struct("AppRole")
+                .field("mount", mount)
+                .field("role_id_file", role_id_file)
+                .field("secret_id_file", secret_id_file)
+                .finish(),
+            Self::Kubernetes {
+                mount,
+                role,
+                jwt_file,
+            } => f
+                .debug_struct("Kubernetes")
+                .field("mount", mount)
+                .field("role", role)
+                .field("jwt_file", jwt_file)
+                .finish(),
+            Self::Jwt {
+                mount,
+                role,
+                jwt_file,
+            } => f
+                .debug_struct("Jwt")
+                .field("mount", mount)
+                .field("role", role)
+                .field("jwt_file", jwt_file)
+                .finish(),
+        }
+    }
+}
+
+/// The token a login produced
+pub(crate) struct ServiceToken {
+    pub client_token: String,
+    /// Lease in seconds, 0 for tokens that never expire
+    pub lease_duration: u64,
+    pub renewable: bool,
+}
+
+impl VaultAuth {
+    /// Read the login method from the environment
+    ///
+    /// `VAULT_AUTH_METHOD` is one of `token` (the default, using `VAULT_TOKEN`), `approle`,
+    /// `kubernetes` or `jwt`. The other methods read `VAULT_AUTH_MOUNT`, `VAULT_AUTH_ROLE`,
+    /// `VAULT_ROLE_ID_FILE`, `VAULT_SECRET_ID_FILE` and `VAULT_JWT_FILE`.
+    pub fn from_env() -> Result<Self, VaultError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| VaultError::AuthError(format!("{} is not set", name)))
+        };
+        let var_or =
+            |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+
+        let method = var_or("VAULT_AUTH_METHOD", "token");
+        match method.as_str() {
+            "token" => Ok(Self::Token(var("VAULT_TOKEN")?)),
+            "approle" => Ok(Self::AppRole {
+                mount: var_or("VAULT_AUTH_MOUNT", "approle"),
+                role_id_file: var("VAULT_ROLE_ID_FILE")?.into(),
+                secret_id_file: var("VAULT_SECRET_ID_FILE")?.into(),
+            }),
+            "kubernetes" => Ok(Self::Kubernetes {
+                mount: var_or("VAULT_AUTH_MOUNT", "kubernetes"),
+                role: var("VAULT_AUTH_ROLE")?,
+                jwt_file: var_or("VAULT_JWT_FILE", KUBERNETES_JWT_PATH).into(),
+            }),
+            "jwt" => Ok(Self::Jwt {
+                mount: var_or("VAULT_AUTH_MOUNT", "jwt"),
+                role: var("VAULT_AUTH_ROLE")?,
+                jwt_file: var("VAULT_JWT_FILE")?.into(),
+            }),
+            method => Err(VaultError::AuthError(format!(
+                "Unknown VAULT_AUTH_METHOD {}",
+                method
+            ))),
+        }
+    }
+
+    /// Log in to Vault at `address`
+    pub(crate) async fn login(&self, address: &str) -> Result<ServiceToken, VaultError> {
+        let client = unauthenticated_client(address)?;
+        let auth = match self {
+            Self::Token(token) => {
+                // Static tokens are used as they are, look them up for their lease
+                let client = client_with_token(address, token)?;
+                let lookup = vaultrs::token::lookup_self(&client).await?;
+                return Ok(ServiceToken {
+                    client_token: token.clone(),
+                    lease_duration: lookup.ttl as u64,
+                    renewable: lookup.renewable,
+                });
+            }
+            Self::AppRole {
+                mount,
+                role_id_file,
+                secret_id_file,
+            } => {
+                let role_id = read_credential(role_id_file).await?;
+                let secret_id = read_credential(secret_id_file).await?;
+                vaultrs::auth::approle::login(&client, mount, &role_id, &secret_id).await?
+            }
+            Self::Kubernetes {
+                mount,
+                role,
+                jwt_file,
+            } => {
+                let jwt = read_credential(jwt_file).await?;
+                vaultrs::auth::kubernetes::login(&client, mount, role, &jwt).await?
+            }
+            Self::Jwt {
+                mount,
+                role,
+                jwt_file,
+            } => {
+                let jwt = read_credential(jwt_file).await?;
+                vaultrs::auth::oidc::login(&client, mount, &jwt, Some(role.clone())).await?
+            }
+        };
+
+        Ok(ServiceToken {
+            client_token: auth.client_token,
+            lease_duration: auth.lease_duration,
+            renewable: auth.renewable,
+        })
+    }
+
+    /// Whether logging in again can produce a new token
+    pub(crate) fn can_relogin(&self) -> bool {
+        !matches!(self, Self::Token(_))
+    }
+}
+
+async fn read_credential(path: &Path) -> Result<String, VaultError> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map(|credential| credential.trim().to_string())
+        .map_err(|e| VaultError::AuthError(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn unauthenticated_client(address: &str) -> Result<VaultClient, VaultError> {
+    let settings = VaultClientSettingsBuilder::default()
+        .address(address)
+        .build()
+        .map_err(|e| VaultError::AuthError(format!("Failed to build Vault client: {}", e)))?;
+    Ok(VaultClient::new(settings)?)
+}
+
+pub(crate) fn client_with_token(address: &str, token: &str) -> Result<VaultClient, VaultError> {
+    let settings = VaultClientSettingsBuilder::default()
+        .address(address)
+        .token(token)
+        .build()
+        .map_err(|e| VaultError::AuthError(format!("Failed to build Vault client: {}", e)))?;
+    Ok(VaultClient::new(settings)?)
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use vaultrs::error::ClientError;
+
+use super::{http::vault_request, VaultError, VaultService};
+use crate::{oidc::keycloak::KeyCloakToken, AiclIdentity, Role};
+
+/// Mount of the database secrets engine from `terraform/database.tf`
+pub(crate) const MOUNT: &str = "database";
+/// Its connection to the Postgres database the team schemas live in
+pub const CONNECTION: &str = "aicldb";
+
+/// Short-lived Postgres credentials for a team
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseCredentials {
+    pub username: String,
+    pub password: String,
+    /// Vault lease, revoking it drops the Postgres user
+    pub lease_id: String,
+    pub expires_at: u64, // Unix timestamp in seconds
+    pub renewable: bool,
+}
+
+#[derive(Deserialize)]
+struct Lease<T> {
+    lease_id: String,
+    lease_duration: u64,
+    renewable: bool,
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct GeneratedCredentials {
+    username: String,
+    password: String,
+}
+
+impl VaultService {
+    /// The user's team and the Vault role their credentials are issued under
+    ///
+    /// Database roles are named after the user's JWT role, e.g. `team-{name}-captain` for
+    /// captains and `team-{name}-member` for students. Spectators get no credentials.
+    pub fn team_database_role(
+        &self,
+        identity: &AiclIdentity,
+    ) -> Result<(String, String), VaultError> {
+        let team = identity
+            .team
+            .as_ref()
+            .ok_or_else(|| VaultError::Unauthorized("User is not in a team".to_string()))?;
+        if !matches!(identity.role, Role::Captain | Role::Student) {
+            return Err(VaultError::Unauthorized(
+                "Only captains and members get database credentials".to_string(),
+            ));
+        }
+        Ok((team.name.clone(), self.jwt_role(identity)?))
+    }
+
+    /// Issue Postgres credentials bound to the user's team role
+    ///
+    /// The credentials are read with the user's own Vault login, so Vault policy decides
+    /// whether they get them.
+    pub async fn team_database_credentials(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+    ) -> Result<DatabaseCredentials, VaultError> {
+        let (_, role) = self.team_database_role(identity)?;
+        let client = self
+            .create_user_vault_client(&oidc_token.id_token.to_string(), Some(role.clone()))
+            .await?;
+
+        let lease: Lease<GeneratedCredentials> = vault_request(
+            &client,
+            reqwest::Method::GET,
+            &format!("{}/creds/{}", MOUNT, role),
+            None,
+        )
+        .await
+        .map_err(|e| match e {
+            VaultError::ClientError(ClientError::APIError { code: 403, .. }) => {
+                VaultError::Unauthorized(
+                    "Access to the team's database credentials was denied".to_string(),
+                )
+            }
+            e => e,
+        })?
+        .ok_or_else(|| VaultError::RequestError("Vault returned no credentials".to_string()))?;
+
+        Ok(DatabaseCredentials {
+            username: lease.data.username,
+            password: lease.data.password,
+            lease_id: lease.lease_id,
+            expires_at: Self::current_timestamp()? + lease.lease_duration,
+            renewable: lease.renewable,
+        })
+    }
+
+    /// Whether the database secrets engine is mounted with its [`CONNECTION`]
+    pub async fn has_database_connection(&self) -> Result<bool, VaultError> {
+        match self
+            .admin_request::<serde_json::Value>(
+                reqwest::Method::GET,
+                &format!("{}/config/{}", MOUNT, CONNECTION),
+                None,
+            )
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(VaultError::ClientError(ClientError::APIError { code: 404, .. })) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Revoke a lease right away instead of waiting for it to expire
+    pub async fn revoke_lease(&self, lease_id: &str) -> Result<(), VaultError> {
+        let body = serde_json::json!({ "lease_id": lease_id });
+        match self
+            .admin_request::<serde_json::Value>(
+                reqwest::Method::PUT,
+                "sys/leases/revoke",
+                Some(&body),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            // Vault answers 400 for leases that expired already
+            Err(VaultError::ClientError(ClientError::APIError { code: 400, .. })) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        vault::roles::{DefaultRoleMapper, VaultRoleMapper},
+        TeamIdentity,
+    };
+
+    #[test]
+    fn test_database_roles_follow_jwt_roles() {
+        let vault = VaultService::offline();
+        let mut identity = AiclIdentity {
+            id: uuid::Uuid::new_v4(),
+            email: "captain1@test.com".to_string(),
+            username: "captain1".to_string(),
+            team: Some(TeamIdentity {
+                id: uuid::Uuid::new_v4(),
+                name: "Team1".to_string(),
+            }),
+            institution: None,
+            role: Role::Captain,
+        };
+        assert_eq!(
+            vault.team_database_role(&identity).unwrap(),
+            ("Team1".to_string(), "team-Team1-captain".to_string())
+        );
+
+        identity.role = Role::Student;
+        assert_eq!(
+            vault.team_database_role(&identity).unwrap().1,
+            "team-Team1-member"
+        );
+
+        // A custom mapper renames the database roles with the JWT roles
+        struct Prefixed;
+        impl VaultRoleMapper for Prefixed {
+            fn jwt_role(&self, identity: &AiclIdentity) -> Option<String> {
+                DefaultRoleMapper::default()
+                    .jwt_role(identity)
+                    .map(|role| format!("ctf-{}", role))
+            }
+        }
+        vault.set_role_mapper(Prefixed);
+        assert_eq!(
+            vault.team_database_role(&identity).unwrap().1,
+            "ctf-team-Team1-member"
+        );
+
+        identity.role = Role::Spectator;
+        assert!(vault.team_database_role(&identity).is_err());
+        identity.team = None;
+        identity.role = Role::Student;
+        assert!(vault.team_database_role(&identity).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Raw HTTP calls to the Vault endpoints `vaultrs` doesn't cover
+
+use serde::{de::DeserializeOwned, Deserialize};
+use vaultrs::{client::VaultClient, error::ClientError};
+
+use super::VaultError;
+
+#[derive(Deserialize)]
+struct VaultErrors {
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// Call a Vault endpoint `vaultrs` doesn't cover, with the client's address and token
+///
+/// Returns `None` for responses without a body.
+pub(crate) async fn vault_request<T: DeserializeOwned>(
+    client: &VaultClient,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<&serde_json::Value>,
+) -> Result<Option<T>, VaultError> {
+    vault_send(vault_request_builder(client, method, path, body)?).await
+}
+
+/// A request to a Vault endpoint, to add headers to before sending it with [`vault_send`]
+pub(crate) fn vault_request_builder(
+    client: &VaultClient,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<&serde_json::Value>,
+) -> Result<reqwest::RequestBuilder, VaultError> {
+    let url = client
+        .settings
+        .address
+        .join(&format!("v1/{}", path))
+        .map_err(|e| VaultError::RequestError(format!("Invalid Vault path {}: {}", path, e)))?;
+    // The HTTP client `vaultrs` built, with the TLS settings and connection pool of `client`
+    let mut request = client
+        .http
+        .http
+        .request(method, url)
+        .header("X-Vault-Token", &client.settings.token);
+    if let Some(namespace) = &client.settings.namespace {
+        request = request.header("X-Vault-Namespace", namespace);
+    }
+    if let Some(body) = body {
+        request = request.json(body);
+    }
+    Ok(request)
+}
+
+/// Send a Vault request, turning error statuses into [`ClientError::APIError`]
+pub(crate) async fn vault_send<T: DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+) -> Result<Option<T>, VaultError> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| VaultError::RequestError(e.to_string()))?;
+    let code = response.status().as_u16();
+    if !response.status().is_success() {
+        let errors = response
+            .json::<VaultErrors>()
+            .await
+            .map(|errors| errors.errors)
+            .unwrap_or_default();
+        return Err(ClientError::APIError { code, errors }.into());
+    }
+    if code == 204 {
+        return Ok(None);
+    }
+
+    response
+        .json()
+        .await
+        .map(Some)
+        .map_err(|e| VaultError::RequestError(format!("Invalid Vault response: {}", e)))
+}
@@ -0,0 +1,1572 @@
+// Todo: Remove the admin client from this file. It's not needed here.
+
+pub mod auth;
+pub mod database;
+mod http;
+pub mod pki;
+pub mod reconcile;
+pub mod revocation;
+pub mod roles;
+pub mod secrets;
+pub mod transit;
+pub mod wrapping;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::StreamExt;
+use moka::future::{Cache, CacheBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use vaultrs::api::token::requests::CreateRoleTokenRequestBuilder;
+use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+use vaultrs::error::ClientError;
+use vaultrs::kv2;
+
+use crate::idp::ext::IdpConfig;
+use crate::oidc::cookie_session::SessionKeys;
+use crate::oidc::keycloak::KeyCloakToken;
+use crate::vault::auth::{client_with_token, VaultAuth};
+use crate::vault::http::vault_request;
+use crate::vault::pki::VaultCertificate;
+use crate::vault::revocation::RevocationBus;
+use crate::vault::roles::{DefaultRoleMapper, VaultRoleMapper};
+use crate::AiclIdentity;
+
+// API token structure returned to users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub client_token: String,
+    // Non-secret handle used to list and revoke the token
+    #[serde(default)]
+    pub accessor: String,
+    pub expires_at: u64, // Unix timestamp in seconds
+    pub renewable: bool,
+    pub policies: Vec<String>,
+}
+
+/// An API token that passed verification
+#[derive(Debug, Clone)]
+pub struct VerifiedToken {
+    pub user_id: Uuid,
+    pub accessor: String,
+    pub expires_at: u64, // Unix timestamp in seconds
+    // Client networks the token is bound to, empty if unbound
+    pub bound_cidrs: Vec<String>,
+}
+
+impl VerifiedToken {
+    /// Whether a client at this address may use the token
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        if self.bound_cidrs.is_empty() {
+            return true;
+        }
+        ip.is_some_and(|ip| self.bound_cidrs.iter().any(|cidr| cidr_contains(cidr, ip)))
+    }
+}
+
+/// Restrictions for a new API token, on top of what the user's role grants
+///
+/// Unset fields keep the role's defaults. Named scopes, "token profiles", are read from
+/// `secret/idp/token-profiles` in Vault.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenScope {
+    /// Subset of the user's policies. The `default` policy is always kept, tokens need it
+    /// to look themselves up when this service verifies them.
+    pub policies: Option<Vec<String>>,
+    /// Maximum lifetime in seconds, renewals included
+    pub ttl: Option<u64>,
+    /// Number of Vault requests after which the token is revoked. Verification by this
+    /// service counts as a request, once per cache period.
+    pub num_uses: Option<u64>,
+    /// Client networks allowed to use the token through this service, e.g. `10.0.0.0/8`
+    pub bound_cidrs: Option<Vec<String>>,
+}
+
+impl TokenScope {
+    /// Narrow this scope further, keeping the stricter of both for each restriction
+    pub fn restrict(self, other: TokenScope) -> Result<TokenScope, VaultError> {
+        let policies = match (self.policies, other.policies) {
+            (Some(allowed), Some(requested)) => {
+                if let Some(policy) = requested.iter().find(|p| !allowed.contains(p)) {
+                    return Err(VaultError::ScopeNotAllowed(format!(
+                        "Policy {} is not allowed",
+                        policy
+                    )));
+                }
+                Some(requested)
+            }
+            (allowed, requested) => requested.or(allowed),
+        };
+        let bound_cidrs = match (self.bound_cidrs, other.bound_cidrs) {
+            (Some(_), Some(_)) => {
+                return Err(VaultError::ScopeNotAllowed(
+                    "The client networks are fixed by the token profile".to_string(),
+                ))
+            }
+            (fixed, requested) => requested.or(fixed),
+        };
+
+        Ok(TokenScope {
+            policies,
+            ttl: min_of(self.ttl, other.ttl),
+            num_uses: min_of(self.num_uses, other.num_uses),
+            bound_cidrs,
+        })
+    }
+
+    fn validate(&self) -> Result<(), VaultError> {
+        if self.policies.as_ref().is_some_and(|p| p.is_empty()) {
+            return Err(VaultError::InvalidScope(
+                "At least one policy is required".to_string(),
+            ));
+        }
+        if self.ttl == Some(0) || self.num_uses == Some(0) {
+            return Err(VaultError::InvalidScope(
+                "The TTL and number of uses must be positive".to_string(),
+            ));
+        }
+        if let Some(cidr) = self
+            .bound_cidrs
+            .iter()
+            .flatten()
+            .find(|cidr| parse_cidr(cidr).is_none())
+        {
+            return Err(VaultError::InvalidScope(format!("Invalid CIDR {}", cidr)));
+        }
+        Ok(())
+    }
+}
+
+fn min_of(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Parse `address/prefix`, a bare address is a single host
+pub(crate) fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, Some(prefix)),
+        None => (cidr.parse::<IpAddr>().ok()?, None),
+    };
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse::<u32>().ok().filter(|p| *p <= bits)?,
+        None => bits,
+    };
+    Some((address, prefix))
+}
+
+pub(crate) fn cidr_contains(cidr: &str, ip: IpAddr) -> bool {
+    let Some((network, prefix)) = parse_cidr(cidr) else {
+        return false;
+    };
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            (u32::from(network) as u128, u32::from(ip) as u128, 32)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+    let mask = u128::MAX.checked_shl(bits - prefix).unwrap_or(0) & (u128::MAX >> (128 - bits));
+    network & mask == ip & mask
+}
+
+/// The outcome of renewing a token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRenewal {
+    pub accessor: String,
+    pub expires_at: u64, // Unix timestamp in seconds
+    pub renewable: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum VaultError {
+    #[error("Vault client error: {0}")]
+    ClientError(#[from] ClientError),
+
+    #[error("Missing OIDC token: {0}")]
+    MissingToken(String),
+
+    #[error("Failed to create token: {0}")]
+    TokenCreationError(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Token scope not allowed: {0}")]
+    ScopeNotAllowed(String),
+
+    #[error("Invalid token scope: {0}")]
+    InvalidScope(String),
+
+    #[error("OIDC error: {0}")]
+    OidcError(String),
+
+    #[error("System time error: {0}")]
+    TimeError(String),
+
+    #[error("Service authentication failed: {0}")]
+    AuthError(String),
+
+    #[error("Secret not found")]
+    SecretNotFound,
+
+    #[error("Invalid secret name: {0}")]
+    InvalidSecretName(String),
+
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+
+    #[error("Vault request failed: {0}")]
+    RequestError(String),
+
+    // Vault doesn't tell used, expired and unknown wrapping tokens apart
+    #[error("Wrapping token is invalid, expired or was already used")]
+    WrappingTokenInvalid,
+}
+
+impl VaultError {
+    /// Stable, machine-readable code for this error, suitable for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ClientError(_) => "vault.client_error",
+            Self::MissingToken(_) => "vault.missing_token",
+            Self::TokenCreationError(_) => "vault.token_creation_failed",
+            Self::Unauthorized(_) => "vault.unauthorized",
+            Self::ScopeNotAllowed(_) => "vault.scope_not_allowed",
+            Self::InvalidScope(_) => "vault.invalid_scope",
+            Self::OidcError(_) => "vault.oidc_error",
+            Self::TimeError(_) => "vault.time_error",
+            Self::AuthError(_) => "vault.auth_error",
+            Self::SecretNotFound => "vault.secret_not_found",
+            Self::InvalidSecretName(_) => "vault.invalid_secret_name",
+            Self::EncryptionError(_) => "vault.encryption_error",
+            Self::RequestError(_) => "vault.request_failed",
+            Self::WrappingTokenInvalid => "vault.wrapping_token_invalid",
+        }
+    }
+}
+
+/// Why an API token was rejected
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerificationError {
+    #[error("Token is expired")]
+    Expired,
+
+    #[error("Token was revoked")]
+    Revoked,
+
+    #[error("Token is malformed: {0}")]
+    Malformed(String),
+
+    // Vault doesn't tell unknown, revoked and expired tokens apart once they are gone
+    #[error("Token is invalid: {0}")]
+    Invalid(String),
+
+    #[error("Token could not be verified, Vault is unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl VerificationError {
+    /// Stable, machine-readable code for this error, suitable for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Expired => "token.expired",
+            Self::Revoked => "token.revoked",
+            Self::Malformed(_) => "token.malformed",
+            Self::Invalid(_) => "token.invalid",
+            Self::Unavailable(_) => "token.verification_unavailable",
+        }
+    }
+}
+
+impl From<ClientError> for VerificationError {
+    fn from(e: ClientError) -> Self {
+        match e {
+            ClientError::APIError { code: 400, .. } => Self::Malformed(e.to_string()),
+            ClientError::APIError {
+                code: 401 | 403 | 404,
+                ..
+            } => Self::Invalid("Token is unknown, revoked or expired".to_string()),
+            e => Self::Unavailable(e.to_string()),
+        }
+    }
+}
+
+// Reject strings that can't be a Vault token before sending them anywhere
+fn check_token_format(token: &str) -> Result<(), VerificationError> {
+    if token.is_empty() || token.len() > 512 {
+        return Err(VerificationError::Malformed(
+            "Token has an invalid length".to_string(),
+        ));
+    }
+    if !token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        return Err(VerificationError::Malformed(
+            "Token contains invalid characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct VaultConfig {
+    pub address: String,
+    /// How the service logs in as itself
+    pub auth: VaultAuth,
+    pub oidc_path: String,
+    pub oidc_role: String,
+}
+
+// The service's own token and the client using it
+struct AdminToken {
+    client: Arc<VaultClient>,
+    lease_duration: u64, // Seconds, 0 if the token never expires
+    renewable: bool,
+}
+
+/// How long a user's Vault login is reused when its lease never expires
+const MAX_USER_LOGIN_REUSE: Duration = Duration::from_secs(3600);
+
+// A Vault client logged in as a user, reused until shortly before its lease ends
+#[derive(Clone)]
+struct UserLogin {
+    client: Arc<VaultClient>,
+    valid_for: Duration,
+}
+
+impl UserLogin {
+    fn new(client: VaultClient, lease_duration: u64) -> Self {
+        let valid_for = match lease_duration {
+            0 => MAX_USER_LOGIN_REUSE,
+            // Leave a margin, so a client is not handed out just before its token expires
+            lease => Duration::from_secs(lease - lease / 10).min(MAX_USER_LOGIN_REUSE),
+        };
+        Self {
+            client: Arc::new(client),
+            valid_for,
+        }
+    }
+}
+
+struct UserLoginExpiry;
+
+impl moka::Expiry<String, UserLogin> for UserLoginExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        login: &UserLogin,
+        _created_at: std::time::Instant,
+    ) -> Option<Duration> {
+        Some(login.valid_for)
+    }
+}
+
+/// How long a successful token verification is reused
+const VERIFIED_TOKEN_TTL: Duration = Duration::from_secs(60);
+/// How long a failed verification is remembered, short so fixed tokens work again soon
+const REJECTED_TOKEN_TTL: Duration = Duration::from_secs(5);
+
+/// How long a certificate looked up in Vault is trusted before checking its revocation again
+pub const CERTIFICATE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+struct TokenCacheExpiry;
+
+impl TokenCacheExpiry {
+    fn ttl(verified: &Result<VerifiedToken, VerificationError>) -> Duration {
+        match verified {
+            Ok(_) => VERIFIED_TOKEN_TTL,
+            Err(_) => REJECTED_TOKEN_TTL,
+        }
+    }
+}
+
+impl moka::Expiry<String, Result<VerifiedToken, VerificationError>> for TokenCacheExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        verified: &Result<VerifiedToken, VerificationError>,
+        _created_at: std::time::Instant,
+    ) -> Option<Duration> {
+        Some(Self::ttl(verified))
+    }
+
+    // A revoked or renewed token starts over with the TTL of its new result
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        verified: &Result<VerifiedToken, VerificationError>,
+        _updated_at: std::time::Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(Self::ttl(verified))
+    }
+}
+
+fn no_vault_role(identity: &AiclIdentity) -> VaultError {
+    VaultError::Unauthorized(format!(
+        "No Vault role for {} {}",
+        identity.role.as_str(),
+        identity.username
+    ))
+}
+
+// Errors of a cache loader are shared between everyone waiting on it
+//
+// Waiters get a copy of the same kind, so e.g. a denied login still maps to the same status.
+// Client errors other than Vault's own responses can't be copied and become request errors.
+fn shared_error(e: Arc<VaultError>) -> VaultError {
+    Arc::try_unwrap(e).unwrap_or_else(|e| match &*e {
+        VaultError::ClientError(ClientError::APIError { code, errors }) => {
+            VaultError::ClientError(ClientError::APIError {
+                code: *code,
+                errors: errors.clone(),
+            })
+        }
+        VaultError::ClientError(e) => VaultError::RequestError(e.to_string()),
+        VaultError::MissingToken(message) => VaultError::MissingToken(message.clone()),
+        VaultError::TokenCreationError(message) => VaultError::TokenCreationError(message.clone()),
+        VaultError::Unauthorized(message) => VaultError::Unauthorized(message.clone()),
+        VaultError::ScopeNotAllowed(message) => VaultError::ScopeNotAllowed(message.clone()),
+        VaultError::InvalidScope(message) => VaultError::InvalidScope(message.clone()),
+        VaultError::OidcError(message) => VaultError::OidcError(message.clone()),
+        VaultError::TimeError(message) => VaultError::TimeError(message.clone()),
+        VaultError::AuthError(message) => VaultError::AuthError(message.clone()),
+        VaultError::SecretNotFound => VaultError::SecretNotFound,
+        VaultError::InvalidSecretName(name) => VaultError::InvalidSecretName(name.clone()),
+        VaultError::EncryptionError(message) => VaultError::EncryptionError(message.clone()),
+        VaultError::RequestError(message) => VaultError::RequestError(message.clone()),
+        VaultError::WrappingTokenInvalid => VaultError::WrappingTokenInvalid,
+    })
+}
+
+pub struct VaultService {
+    admin: RwLock<AdminToken>,
+    // Serializes logins, so concurrent failures log in once
+    relogin: tokio::sync::Mutex<()>,
+    config: VaultConfig,
+    // Cache for user data by user ID
+    token_cache: Cache<String, Result<VerifiedToken, VerificationError>>,
+    // Named token scopes users can ask for
+    token_profiles: HashMap<String, TokenScope>,
+    // Vault logins of users, by role and ID token or by their own token
+    user_logins: Cache<String, UserLogin>,
+    // Which Vault roles users log in and issue tokens under
+    role_mapper: RwLock<Arc<dyn VaultRoleMapper>>,
+    // Tells the other replicas about revoked tokens
+    revocation_bus: Option<Arc<dyn RevocationBus>>,
+    // Client certificates by serial number, as issued by the PKI engine
+    certificate_cache: Cache<String, VaultCertificate>,
+}
+
+impl VaultService {
+    pub async fn from_env() -> Result<Self, VaultError> {
+        #[cfg(not(feature = "test-utils"))]
+        let address = std::env::var("VAULT_ADDR").ok().unwrap();
+        #[cfg(not(feature = "test-utils"))]
+        let auth = VaultAuth::from_env()?;
+        #[cfg(feature = "test-utils")]
+        let address = "http://localhost:8200".to_string();
+        // The root token of the development server, unless the environment configures a login
+        #[cfg(feature = "test-utils")]
+        let auth =
+            if std::env::var("VAULT_AUTH_METHOD").is_ok() || std::env::var("VAULT_TOKEN").is_ok() {
+                VaultAuth::from_env()?
+            } else {
+                VaultAuth::Token("myroot".to_string())
+            };
+
+        let config = VaultConfig {
+            address,
+            auth,
+            oidc_path: "jwt".to_string(), // Path from vault_jwt_auth_backend.keycloak in terraform
+            oidc_role: "default".to_string(), // Role from vault_jwt_auth_backend_role.default in terraform
+        };
+        Self::new(config).await
+    }
+
+    pub async fn new(config: VaultConfig) -> Result<Self, VaultError> {
+        let admin = Self::login(&config).await?;
+        Ok(Self::with_admin_token(config, admin))
+    }
+
+    fn with_admin_token(config: VaultConfig, admin: AdminToken) -> Self {
+        let token_cache = CacheBuilder::new(1000)
+            .expire_after(TokenCacheExpiry)
+            .build();
+
+        Self {
+            admin: RwLock::new(admin),
+            relogin: tokio::sync::Mutex::new(()),
+            config,
+            token_cache,
+            token_profiles: HashMap::new(),
+            user_logins: Cache::builder()
+                .max_capacity(10_000)
+                .expire_after(UserLoginExpiry)
+                .build(),
+            role_mapper: RwLock::new(Arc::new(DefaultRoleMapper::default())),
+            revocation_bus: None,
+            certificate_cache: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(CERTIFICATE_CACHE_TTL)
+                .build(),
+        }
+    }
+
+    /// A service whose calls to Vault all fail, to test what happens around them
+    #[cfg(test)]
+    pub(crate) fn offline() -> Self {
+        let config = VaultConfig {
+            address: "http://127.0.0.1:1".to_string(),
+            auth: VaultAuth::Token("offline".to_string()),
+            oidc_path: "jwt".to_string(),
+            oidc_role: "default".to_string(),
+        };
+        let client = client_with_token(&config.address, "offline").expect("Valid Vault address");
+        let admin = AdminToken {
+            client: Arc::new(client),
+            lease_duration: 0,
+            renewable: false,
+        };
+        Self::with_admin_token(config, admin)
+    }
+
+    async fn login(config: &VaultConfig) -> Result<AdminToken, VaultError> {
+        let token = config.auth.login(&config.address).await?;
+        tracing::info!(
+            lease_duration = token.lease_duration,
+            "Logged in to Vault as the service"
+        );
+        Ok(AdminToken {
+            client: Arc::new(client_with_token(&config.address, &token.client_token)?),
+            lease_duration: token.lease_duration,
+            renewable: token.renewable,
+        })
+    }
+
+    fn admin_client(&self) -> Arc<VaultClient> {
+        self.admin
+            .read()
+            .expect("Admin token poisoned")
+            .client
+            .clone()
+    }
+
+    // Log in again and swap the admin client for one with the new token
+    async fn reauthenticate(&self) -> Result<(), VaultError> {
+        let stale = self.admin_client();
+        let _guard = self.relogin.lock().await;
+        // Someone else logged in while we waited
+        if !Arc::ptr_eq(&stale, &self.admin_client()) {
+            return Ok(());
+        }
+        let admin = Self::login(&self.config).await?;
+        *self.admin.write().expect("Admin token poisoned") = admin;
+        Ok(())
+    }
+
+    // Run a call with the admin client, logging in again once if Vault rejects its token
+    async fn with_admin<T, F, Fut>(&self, call: F) -> Result<T, ClientError>
+    where
+        F: Fn(Arc<VaultClient>) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        match call(self.admin_client()).await {
+            Err(e @ ClientError::APIError { code: 403, .. }) => {
+                if !self.relogin_after_rejection().await {
+                    return Err(e);
+                }
+                call(self.admin_client()).await
+            }
+            result => result,
+        }
+    }
+
+    // `with_admin` for calls made with `vault_request` and friends
+    async fn with_admin_request<T, F, Fut>(&self, call: F) -> Result<T, VaultError>
+    where
+        F: Fn(Arc<VaultClient>) -> Fut,
+        Fut: Future<Output = Result<T, VaultError>>,
+    {
+        match call(self.admin_client()).await {
+            Err(e @ VaultError::ClientError(ClientError::APIError { code: 403, .. })) => {
+                if !self.relogin_after_rejection().await {
+                    return Err(e);
+                }
+                call(self.admin_client()).await
+            }
+            result => result,
+        }
+    }
+
+    // Call a Vault endpoint `vaultrs` doesn't cover with the admin client, see `with_admin`
+    pub(crate) async fn admin_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Option<T>, VaultError> {
+        self.with_admin_request(|client| {
+            let method = method.clone();
+            async move { vault_request(&client, method, path, body).await }
+        })
+        .await
+    }
+
+    // After Vault answered 403 to the admin client, log in again if the token was the cause
+    //
+    // Returns whether the call is worth retrying with the new token.
+    async fn relogin_after_rejection(&self) -> bool {
+        if !self.config.auth.can_relogin() {
+            return false;
+        }
+        // The request may be what Vault refused, not the service token
+        if vaultrs::token::lookup_self(&*self.admin_client())
+            .await
+            .is_ok()
+        {
+            return false;
+        }
+        tracing::warn!("Vault rejected the service token, logging in again");
+        if let Err(login_error) = self.reauthenticate().await {
+            tracing::error!("Failed to log in to Vault again: {}", login_error);
+            return false;
+        }
+        true
+    }
+
+    /// Keep the service token alive in the background
+    ///
+    /// The token is renewed once two thirds of its lease have passed. When it can't be
+    /// renewed, or renewing no longer extends it, the service logs in again. Tokens that
+    /// never expire need nothing and end the task right away.
+    pub fn spawn_token_renewal(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let vault = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let Some(this) = vault.upgrade() else {
+                    return;
+                };
+                let (lease_duration, renewable) = {
+                    let admin = this.admin.read().expect("Admin token poisoned");
+                    (admin.lease_duration, admin.renewable)
+                };
+                if lease_duration == 0 {
+                    tracing::debug!("The Vault service token never expires, not renewing it");
+                    return;
+                }
+                drop(this);
+                tokio::time::sleep(Duration::from_secs(lease_duration * 2 / 3)).await;
+                let Some(this) = vault.upgrade() else {
+                    return;
+                };
+
+                if renewable {
+                    let client = this.admin_client();
+                    match vaultrs::token::renew_self(&*client, None).await {
+                        // Renewal is capped at the max TTL, a shorter lease means log in soon
+                        Ok(auth) if auth.lease_duration * 3 >= lease_duration * 2 => {
+                            tracing::debug!(auth.lease_duration, "Renewed the Vault service token");
+                            let mut admin = this.admin.write().expect("Admin token poisoned");
+                            admin.lease_duration = auth.lease_duration;
+                            admin.renewable = auth.renewable;
+                            continue;
+                        }
+                        Ok(_) => tracing::info!("Vault service token is near its max TTL"),
+                        Err(e) => tracing::warn!("Failed to renew the Vault service token: {}", e),
+                    }
+                }
+
+                if !this.config.auth.can_relogin() {
+                    tracing::error!("The Vault service token can't be renewed and will expire");
+                    return;
+                }
+                if let Err(e) = this.reauthenticate().await {
+                    // Try again soon, the current token may still be valid for a while
+                    tracing::error!("Failed to log in to Vault again: {}", e);
+                    let mut admin = this.admin.write().expect("Admin token poisoned");
+                    admin.lease_duration = 30;
+                    admin.renewable = false;
+                }
+            }
+        })
+    }
+
+    pub fn with_token_profiles(mut self, token_profiles: HashMap<String, TokenScope>) -> Self {
+        self.token_profiles = token_profiles;
+        self
+    }
+
+    pub fn token_profile(&self, name: &str) -> Option<&TokenScope> {
+        self.token_profiles.get(name)
+    }
+
+    /// Map identities to Vault roles differently than [`DefaultRoleMapper`]
+    pub fn with_role_mapper(self, role_mapper: impl VaultRoleMapper + 'static) -> Self {
+        self.set_role_mapper(role_mapper);
+        self
+    }
+
+    /// Same as [`Self::with_role_mapper`] for a service that is already shared, e.g. the
+    /// one `AiclIdentifier::from_env` built. Only new logins and tokens use the new roles.
+    pub fn set_role_mapper(&self, role_mapper: impl VaultRoleMapper + 'static) {
+        *self.role_mapper.write().expect("Role mapper poisoned") = Arc::new(role_mapper);
+    }
+
+    fn role_mapper(&self) -> Arc<dyn VaultRoleMapper> {
+        self.role_mapper
+            .read()
+            .expect("Role mapper poisoned")
+            .clone()
+    }
+
+    /// Share revocations with the other replicas, see [`Self::spawn_revocation_listener`]
+    pub fn with_revocation_bus(mut self, bus: impl RevocationBus + 'static) -> Self {
+        self.revocation_bus = Some(Arc::new(bus));
+        self
+    }
+
+    /// Drop tokens revoked on other replicas from the token cache
+    ///
+    /// When the subscription ends the cache is cleared, since revocations may have been
+    /// missed, and the listener subscribes again. Without a bus the task ends right away.
+    pub fn spawn_revocation_listener(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let vault = Arc::downgrade(self);
+        let bus = self.revocation_bus.clone();
+        tokio::spawn(async move {
+            let Some(bus) = bus else {
+                return;
+            };
+            loop {
+                match bus.subscribe().await {
+                    Ok(mut accessors) => {
+                        while let Some(accessor) = accessors.next().await {
+                            let Some(this) = vault.upgrade() else {
+                                return;
+                            };
+                            this.forget_accessor(&accessor).await;
+                        }
+                        tracing::warn!(
+                            "Lost the revocation subscription, clearing the token cache"
+                        );
+                    }
+                    Err(e) => tracing::warn!("Failed to subscribe to revocations: {}", e),
+                }
+                let Some(this) = vault.upgrade() else {
+                    return;
+                };
+                this.token_cache.invalidate_all();
+                drop(this);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        })
+    }
+
+    /// The JWT role the identity logs in under
+    pub fn jwt_role(&self, identity: &AiclIdentity) -> Result<String, VaultError> {
+        self.role_mapper()
+            .jwt_role(identity)
+            .ok_or_else(|| no_vault_role(identity))
+    }
+
+    /// The token role the identity's API tokens are created from
+    pub fn token_role(&self, identity: &AiclIdentity) -> Result<String, VaultError> {
+        self.role_mapper()
+            .token_role(identity)
+            .ok_or_else(|| no_vault_role(identity))
+    }
+
+    pub async fn get_idp_config_from_vault(&self) -> Result<IdpConfig, VaultError> {
+        let key = "idp/app-config";
+        match self
+            .with_admin(
+                |client| async move { kv2::read::<IdpConfig>(&*client, "secret", key).await },
+            )
+            .await
+        {
+            Ok(secret) => {
+                tracing::debug!("Got secret {}", key);
+                Ok(secret)
+            }
+            Err(e) => {
+                tracing::error!("Failed to get secret {}: {}", key, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Current version of the IdP config, to notice when it changes
+    pub async fn idp_config_version(&self) -> Result<u64, VaultError> {
+        let key = "idp/app-config";
+        let metadata = self
+            .with_admin(|client| async move { kv2::read_metadata(&*client, "secret", key).await })
+            .await?;
+        Ok(metadata.current_version)
+    }
+
+    /// Store a new client secret in the IdP config, e.g. after rotating it
+    ///
+    /// The other fields of the secret, including those [`IdpConfig`] doesn't know about such
+    /// as `app_url` and `issuer`, are kept as they are.
+    pub async fn set_idp_client_secret(&self, client_secret: &str) -> Result<u64, VaultError> {
+        let key = "idp/app-config";
+        let version = self
+            .with_admin(|client| async move {
+                let mut config = kv2::read::<serde_json::Map<String, serde_json::Value>>(
+                    &*client, "secret", key,
+                )
+                .await?;
+                config.insert(
+                    "client_secret".to_string(),
+                    serde_json::Value::String(client_secret.to_string()),
+                );
+                kv2::set(&*client, "secret", key, &config).await
+            })
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to write secret {}: {}", key, e);
+                VaultError::from(e)
+            })?;
+        Ok(version.version)
+    }
+
+    /// Key ring for the sealed cookie session mode
+    pub async fn get_session_keys(&self) -> Result<SessionKeys, VaultError> {
+        let key = "idp/session-keys";
+        self.with_admin(
+            |client| async move { kv2::read::<SessionKeys>(&*client, "secret", key).await },
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get secret {}: {}", key, e);
+            e.into()
+        })
+    }
+
+    /// Named token scopes, e.g. `ci-readonly` or `grader`. Empty if none are configured.
+    pub async fn get_token_profiles(&self) -> Result<HashMap<String, TokenScope>, VaultError> {
+        let key = "idp/token-profiles";
+        match self
+            .with_admin(|client| async move {
+                kv2::read::<HashMap<String, TokenScope>>(&*client, "secret", key).await
+            })
+            .await
+        {
+            Ok(profiles) => Ok(profiles),
+            Err(ClientError::APIError { code: 404, .. }) => {
+                tracing::debug!("No token profiles at {}", key);
+                Ok(HashMap::new())
+            }
+            Err(e) => {
+                tracing::error!("Failed to get secret {}: {}", key, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    // Get current Unix timestamp
+    fn current_timestamp() -> Result<u64, VaultError> {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .map_err(|e| VaultError::TimeError(e.to_string()))
+    }
+
+    // Get a Vault client authenticated via OIDC for the user
+    //
+    // Logins are cached per ID token and role until shortly before their lease ends, so
+    // several Vault calls within a session share one login.
+    pub(crate) async fn create_user_vault_client(
+        &self,
+        jwt: &str,
+        role: Option<String>,
+    ) -> Result<Arc<VaultClient>, VaultError> {
+        let key = format!("{}:{}", role.as_deref().unwrap_or_default(), jwt);
+        let login = self
+            .user_logins
+            .try_get_with(key, async move {
+                tracing::info!("Creating a new Vault client for the user");
+                let login = vaultrs::auth::oidc::login(
+                    &*self.admin_client(),
+                    &self.config.oidc_path,
+                    jwt,
+                    role,
+                )
+                .await
+                .map_err(|e| match e {
+                    // The user's token or role doesn't allow the login
+                    ClientError::APIError {
+                        code: 400 | 403, ..
+                    } => VaultError::Unauthorized("Vault denied the login".to_string()),
+                    e => VaultError::ClientError(e),
+                })?;
+
+                // Create a new Vault client with the user's token
+                let user_settings = VaultClientSettingsBuilder::default()
+                    .address(&self.config.address)
+                    .token(&login.client_token)
+                    .build()
+                    .map_err(|e| {
+                        VaultError::OidcError(format!("Failed to build Vault client: {}", e))
+                    })?;
+                let user_client =
+                    VaultClient::new(user_settings).map_err(VaultError::ClientError)?;
+
+                Ok::<_, VaultError>(UserLogin::new(user_client, login.lease_duration))
+            })
+            .await
+            .map_err(shared_error)?;
+
+        Ok(login.client)
+    }
+
+    // Get a Vault client logged in as the user under the Vault role of their AICL role
+    pub async fn user_vault_client(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+    ) -> Result<Arc<VaultClient>, VaultError> {
+        let vault_role = self.jwt_role(identity)?;
+        self.create_user_vault_client(&oidc_token.id_token.to_string(), Some(vault_role))
+            .await
+    }
+
+    // Get a Vault client using an API token the user presented
+    //
+    // Only for tokens that passed `verify_token` and its network check, so revoked, expired
+    // or misplaced tokens never reach Vault. Clients are cached by the token's accessor.
+    pub(crate) async fn token_vault_client(
+        &self,
+        token: &str,
+        verified: &VerifiedToken,
+    ) -> Result<Arc<VaultClient>, VerificationError> {
+        let key = format!("token:{}", verified.accessor);
+        if let Some(login) = self.user_logins.get(&key).await {
+            return Ok(login.client);
+        }
+
+        let client = client_with_token(&self.config.address, token)
+            .map_err(|e| VerificationError::Unavailable(e.to_string()))?;
+        let lease = verified
+            .expires_at
+            .saturating_sub(Self::current_timestamp().unwrap_or(verified.expires_at));
+        let login = UserLogin::new(client, lease.max(1));
+        self.user_logins.insert(key, login.clone()).await;
+        Ok(login.client)
+    }
+
+    // Create a new API token for the user using their OIDC token, restricted to the scope
+    pub async fn create_api_token_with_oidc(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+        scope: &TokenScope,
+    ) -> Result<ApiToken, VaultError> {
+        scope.validate()?;
+        // Get the ID token string
+        let id_token_str = oidc_token.id_token.to_string();
+        tracing::debug!(identity.username, "Creating API token for user");
+
+        let vault_role = self.jwt_role(identity)?;
+        let token_role = self.token_role(identity)?;
+
+        // Create a Vault client authenticated as the user via OIDC
+        let user_client = self
+            .create_user_vault_client(&id_token_str, Some(vault_role))
+            .await?;
+        tracing::debug!("User Vault client created");
+        // Prepare token creation parameters using builder
+        let mut builder = CreateRoleTokenRequestBuilder::default();
+        // Set display name
+        let timestamp = Self::current_timestamp().unwrap_or(0);
+        let display_name = format!("api-token-{}-{}", identity.username, timestamp);
+        builder.display_name(display_name);
+
+        // Create token metadata
+        let mut metadata = HashMap::new();
+        metadata.insert("user_id".to_string(), identity.id.to_string());
+        metadata.insert("role".to_string(), identity.role.as_str().to_string());
+        // Checked by this service on every request, see `VerifiedToken::allows_ip`
+        if let Some(bound_cidrs) = &scope.bound_cidrs {
+            metadata.insert("bound_cidrs".to_string(), bound_cidrs.join(","));
+        }
+        builder.meta(metadata);
+        tracing::debug!("Token metadata set");
+
+        // The user can only hand out policies their own login carries
+        if let Some(policies) = &scope.policies {
+            let allowed = vaultrs::token::lookup_self(&*user_client)
+                .await
+                .map_err(|e| VaultError::TokenCreationError(e.to_string()))?
+                .policies;
+            TokenScope {
+                policies: Some(allowed),
+                ..TokenScope::default()
+            }
+            .restrict(TokenScope {
+                policies: Some(policies.clone()),
+                ..TokenScope::default()
+            })?;
+            builder.policies(policies.clone());
+        }
+        // Vault caps these at the role's limits, so they can only narrow the token
+        if let Some(ttl) = scope.ttl {
+            builder.ttl(format!("{}s", ttl));
+            builder.explicit_max_ttl(format!("{}s", ttl));
+        }
+        if let Some(num_uses) = scope.num_uses {
+            builder.num_uses(num_uses);
+        }
+        // Create the token using the user's Vault client with the builder
+        let token_result = vaultrs::token::new_role(&*user_client, &token_role, Some(&mut builder))
+            .await
+            .map_err(|e| VaultError::TokenCreationError(e.to_string()))?;
+        tracing::debug!("Token created successfully");
+        // Calculate expiration time as Unix timestamp
+        let now = Self::current_timestamp()?;
+        let expires_at = now + token_result.lease_duration;
+
+        Ok(ApiToken {
+            client_token: token_result.client_token,
+            accessor: token_result.accessor,
+            expires_at,
+            renewable: token_result.renewable,
+            policies: token_result.policies,
+        })
+    }
+
+    // Verify an API token and extract the user ID
+    //
+    // The token looks itself up, so the service's own token needs no lookup permission.
+    async fn verify_token_inter(&self, token: &str) -> Result<VerifiedToken, VerificationError> {
+        check_token_format(token)?;
+        let settings = VaultClientSettingsBuilder::default()
+            .address(&self.config.address)
+            .token(token)
+            .build()
+            .map_err(|e| VerificationError::Unavailable(e.to_string()))?;
+        let token_client = VaultClient::new(settings)?;
+        let lookup_result = vaultrs::token::lookup_self(&token_client).await?;
+
+        // Check if token is expired by verifying ttl is greater than 0
+        if lookup_result.ttl == 0 {
+            return Err(VerificationError::Expired);
+        }
+
+        // Extract metadata
+        let metadata = lookup_result
+            .meta
+            .ok_or_else(|| VerificationError::Invalid("Token has no metadata".to_string()))?;
+
+        // Extract user_id
+        let user_id = metadata.get("user_id").ok_or_else(|| {
+            VerificationError::Invalid("Token missing user_id metadata".to_string())
+        })?;
+
+        // Parse user ID
+        let user_id = uuid::Uuid::parse_str(user_id)
+            .map_err(|_| VerificationError::Invalid("Invalid user ID format".to_string()))?;
+        let bound_cidrs = metadata
+            .get("bound_cidrs")
+            .map(|cidrs| cidrs.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        let now =
+            Self::current_timestamp().map_err(|e| VerificationError::Unavailable(e.to_string()))?;
+
+        Ok(VerifiedToken {
+            user_id,
+            accessor: lookup_result.accessor,
+            expires_at: now + lookup_result.ttl as u64,
+            bound_cidrs,
+        })
+    }
+
+    pub async fn verify_token(
+        self: &Arc<Self>,
+        token: &str,
+    ) -> Result<VerifiedToken, VerificationError> {
+        let this = self.clone();
+        let this_token = token.to_string();
+        let verified = self
+            .token_cache
+            .get_with(
+                this_token,
+                async move { this.verify_token_inter(token).await },
+            )
+            .await;
+
+        match verified {
+            // The cached verification can outlive the token
+            Ok(verified)
+                if verified.expires_at <= Self::current_timestamp().unwrap_or(u64::MAX) =>
+            {
+                self.token_cache
+                    .insert(token.to_string(), Err(VerificationError::Expired))
+                    .await;
+                Err(VerificationError::Expired)
+            }
+            // Don't hold an outage against the token
+            Err(e @ VerificationError::Unavailable(_)) => {
+                self.token_cache.invalidate(token).await;
+                Err(e)
+            }
+            verified => verified,
+        }
+    }
+
+    // Extend an API token's lease, up to its max TTL
+    //
+    // The token renews itself, so only its holder can keep it alive.
+    pub async fn renew_token(
+        &self,
+        token: &str,
+        increment: Option<Duration>,
+    ) -> Result<TokenRenewal, VaultError> {
+        let increment = increment.map(|increment| format!("{}s", increment.as_secs()));
+        let client = client_with_token(&self.config.address, token)?;
+        let auth = vaultrs::token::renew_self(&client, increment.as_deref())
+            .await
+            .map_err(|e| match e {
+                ClientError::APIError {
+                    code: 400..=404, ..
+                } => VaultError::Unauthorized("Token cannot be renewed".to_string()),
+                e => VaultError::ClientError(e),
+            })?;
+        let renewal = TokenRenewal {
+            accessor: auth.accessor,
+            expires_at: Self::current_timestamp()? + auth.lease_duration,
+            renewable: auth.renewable,
+        };
+
+        // Keep the cached verification in step with the new expiry
+        if let Some(Ok(mut verified)) = self.token_cache.get(token).await {
+            verified.expires_at = renewal.expires_at;
+            self.token_cache
+                .insert(token.to_string(), Ok(verified))
+                .await;
+        }
+
+        Ok(renewal)
+    }
+
+    // Revoke an API token
+    pub async fn revoke_token(&self, token: &str) -> Result<(), VaultError> {
+        let accessor = self.token_accessor(token).await;
+
+        // Revoke the token in Vault using the admin client
+        self.with_admin(|client| async move { vaultrs::token::revoke(&*client, token).await })
+            .await
+            .map_err(VaultError::ClientError)?;
+        self.token_revoked(token, accessor.as_deref()).await;
+
+        Ok(())
+    }
+
+    // Revoke one of a user's API tokens by its accessor
+    pub async fn revoke_api_token(&self, user_id: Uuid, accessor: &str) -> Result<(), VaultError> {
+        let lookup = self
+            .with_admin(|client| async move {
+                vaultrs::token::lookup_accessor(&*client, accessor).await
+            })
+            .await
+            .map_err(|e| match e {
+                ClientError::APIError {
+                    code: 400..=404, ..
+                } => VaultError::Unauthorized("Unknown token accessor".to_string()),
+                e => VaultError::ClientError(e),
+            })?;
+        let owner = lookup.meta.as_ref().and_then(|meta| meta.get("user_id"));
+        if owner != Some(&user_id.to_string()) {
+            return Err(VaultError::Unauthorized(
+                "Token does not belong to the user".to_string(),
+            ));
+        }
+
+        self.with_admin(|client| async move {
+            vaultrs::token::revoke_accessor(&*client, accessor).await
+        })
+        .await
+        .map_err(VaultError::ClientError)?;
+        self.forget_accessor(accessor).await;
+        self.announce_revocation(accessor).await;
+
+        Ok(())
+    }
+
+    // Accessor of a token, looked up with the token itself when it isn't cached
+    async fn token_accessor(&self, token: &str) -> Option<String> {
+        if let Some(Ok(verified)) = self.token_cache.get(token).await {
+            return Some(verified.accessor);
+        }
+        let client = client_with_token(&self.config.address, token).ok()?;
+        vaultrs::token::lookup_self(&client)
+            .await
+            .ok()
+            .map(|lookup| lookup.accessor)
+    }
+
+    // Drop a revoked token here and on the other replicas
+    async fn token_revoked(&self, token: &str, accessor: Option<&str>) {
+        self.token_cache
+            .insert(token.to_string(), Err(VerificationError::Revoked))
+            .await;
+        if let Some(accessor) = accessor {
+            self.forget_accessor(accessor).await;
+            self.announce_revocation(accessor).await;
+        }
+    }
+
+    // Publish a revocation, the other replicas drop the token from their caches
+    async fn announce_revocation(&self, accessor: &str) {
+        if let Some(bus) = &self.revocation_bus {
+            if let Err(e) = bus.publish(accessor).await {
+                tracing::warn!("Failed to announce a token revocation: {}", e);
+            }
+        }
+    }
+
+    // Remember the revocation in the token cache, so the token stops working right away
+    async fn forget_accessor(&self, accessor: &str) {
+        let revoked: Vec<_> = self
+            .token_cache
+            .iter()
+            .filter(
+                |(_, verified)| matches!(verified, Ok(verified) if verified.accessor == accessor),
+            )
+            .map(|(token, _)| token)
+            .collect();
+        for token in revoked {
+            self.token_cache
+                .insert(token.as_ref().clone(), Err(VerificationError::Revoked))
+                .await;
+        }
+        self.user_logins
+            .invalidate(&format!("token:{}", accessor))
+            .await;
+    }
+
+    // Allow users to revoke their own tokens using their user-scoped Vault client
+    pub async fn revoke_own_token(
+        &self,
+        oidc_token: &KeyCloakToken,
+        token_to_revoke: &str,
+    ) -> Result<(), VaultError> {
+        // Get the ID token string
+        let id_token_str = oidc_token.id_token.to_string();
+
+        // Create a Vault client authenticated as the user via OIDC
+        let user_client = self.create_user_vault_client(&id_token_str, None).await?;
+        let accessor = self.token_accessor(token_to_revoke).await;
+
+        // Revoke the token using the user's Vault client
+        vaultrs::token::revoke(&*user_client, token_to_revoke)
+            .await
+            .map_err(VaultError::ClientError)?;
+        self.token_revoked(token_to_revoke, accessor.as_deref())
+            .await;
+
+        Ok(())
+    }
+}
+
+/// Keeps an API token alive from the side of its holder, e.g. a long running team job
+///
+/// The token renews itself once two thirds of its lease have passed, until Vault stops
+/// extending it because it reached its max TTL.
+pub struct TokenRenewer {
+    client: VaultClient,
+    token: ApiToken,
+}
+
+impl TokenRenewer {
+    // Vault caps renewals at the max TTL, once a renewal gains nothing the token is there.
+    // Short periodic tokens gain little per renewal, so any gain means keep going.
+    fn reached_max_ttl(previous_expiry: u64, renewed_expiry: u64) -> bool {
+        renewed_expiry <= previous_expiry
+    }
+
+    pub fn new(address: &str, token: ApiToken) -> Result<Self, VaultError> {
+        let settings = VaultClientSettingsBuilder::default()
+            .address(address)
+            .token(&token.client_token)
+            .build()
+            .map_err(|e| VaultError::OidcError(format!("Failed to build Vault client: {}", e)))?;
+        let client = VaultClient::new(settings)?;
+
+        Ok(Self { client, token })
+    }
+
+    pub fn token(&self) -> &ApiToken {
+        &self.token
+    }
+
+    // Renew the token with its own credentials
+    pub async fn renew_self(&mut self) -> Result<TokenRenewal, VaultError> {
+        let auth = vaultrs::token::renew_self(&self.client, None).await?;
+        let renewal = TokenRenewal {
+            accessor: auth.accessor,
+            expires_at: VaultService::current_timestamp()? + auth.lease_duration,
+            renewable: auth.renewable,
+        };
+        self.token.expires_at = renewal.expires_at;
+        self.token.renewable = renewal.renewable;
+
+        Ok(renewal)
+    }
+
+    /// Renew the token in the background
+    ///
+    /// The receiver always holds the token with its current expiry. The task ends once the
+    /// token can't be extended any further, or with the error of a failed renewal.
+    pub fn spawn(
+        mut self,
+    ) -> (
+        tokio::task::JoinHandle<Result<(), VaultError>>,
+        tokio::sync::watch::Receiver<ApiToken>,
+    ) {
+        let (sender, receiver) = tokio::sync::watch::channel(self.token.clone());
+        let handle = tokio::spawn(async move {
+            while self.token.renewable {
+                let remaining = self
+                    .token
+                    .expires_at
+                    .saturating_sub(VaultService::current_timestamp()?);
+                tokio::time::sleep(Duration::from_secs(remaining * 2 / 3)).await;
+
+                let previous = self.token.expires_at;
+                let renewal = self.renew_self().await?;
+                sender.send_replace(self.token.clone());
+                tracing::debug!(renewal.accessor, renewal.expires_at, "Renewed API token");
+
+                if Self::reached_max_ttl(previous, renewal.expires_at) {
+                    tracing::info!(renewal.accessor, "API token reached its max TTL");
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        (handle, receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::revocation::LocalRevocationBus;
+    use moka::Expiry;
+
+    // Cache a verified token and the Vault client logged in with it
+    async fn cache_token(vault: &VaultService, token: &str, accessor: &str) {
+        let verified = VerifiedToken {
+            user_id: Uuid::new_v4(),
+            accessor: accessor.to_string(),
+            expires_at: VaultService::current_timestamp().unwrap() + 3600,
+            bound_cidrs: Vec::new(),
+        };
+        vault
+            .token_cache
+            .insert(token.to_string(), Ok(verified))
+            .await;
+        let client = client_with_token(&vault.config.address, token).unwrap();
+        vault
+            .user_logins
+            .insert(format!("token:{}", accessor), UserLogin::new(client, 3600))
+            .await;
+    }
+
+    async fn is_revoked(vault: &VaultService, token: &str) -> bool {
+        matches!(
+            vault.token_cache.get(token).await,
+            Some(Err(VerificationError::Revoked))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_revocation_evicts_cached_token() {
+        let vault = Arc::new(VaultService::offline());
+        cache_token(&vault, "hvs.revoked", "accessor1").await;
+        cache_token(&vault, "hvs.kept", "accessor2").await;
+
+        vault.token_revoked("hvs.revoked", Some("accessor1")).await;
+
+        // The revoked token is refused from the cache, Vault is offline
+        assert_eq!(
+            vault.verify_token("hvs.revoked").await.unwrap_err(),
+            VerificationError::Revoked
+        );
+        assert!(vault.user_logins.get("token:accessor1").await.is_none());
+        assert!(vault.verify_token("hvs.kept").await.is_ok());
+        assert!(vault.user_logins.get("token:accessor2").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_revocation_bus_forgets_accessor() {
+        let bus = LocalRevocationBus::default();
+        let vault = Arc::new(VaultService::offline().with_revocation_bus(bus.clone()));
+        cache_token(&vault, "hvs.other", "accessor1").await;
+        let listener = vault.spawn_revocation_listener();
+
+        // Revoked on another replica, published until the listener has subscribed
+        let revoked = async {
+            while !is_revoked(&vault, "hvs.other").await {
+                bus.publish("accessor1").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), revoked)
+            .await
+            .expect("The listener should forget the accessor");
+        assert!(vault.user_logins.get("token:accessor1").await.is_none());
+        listener.abort();
+    }
+
+    #[test]
+    fn test_renewer_stops_at_max_ttl() {
+        // A 60s periodic token renewed at 2/3 of its lease gains 40s and keeps going
+        assert!(!TokenRenewer::reached_max_ttl(1_000, 1_040));
+        assert!(TokenRenewer::reached_max_ttl(1_000, 1_000));
+        assert!(TokenRenewer::reached_max_ttl(1_000, 999));
+    }
+
+    #[test]
+    fn test_rejected_tokens_expire_sooner() {
+        let key = "hvs.token".to_string();
+        let now = std::time::Instant::now();
+        let verified = Ok(VerifiedToken {
+            user_id: Uuid::new_v4(),
+            accessor: "accessor".to_string(),
+            expires_at: 0,
+            bound_cidrs: Vec::new(),
+        });
+        let rejected = Err(VerificationError::Revoked);
+
+        assert_eq!(
+            TokenCacheExpiry.expire_after_create(&key, &verified, now),
+            Some(VERIFIED_TOKEN_TTL)
+        );
+        assert_eq!(
+            TokenCacheExpiry.expire_after_create(&key, &rejected, now),
+            Some(Duration::from_secs(5))
+        );
+        // A revoked entry doesn't keep the rest of its verified TTL
+        assert_eq!(
+            TokenCacheExpiry.expire_after_update(&key, &rejected, now, Some(VERIFIED_TOKEN_TTL)),
+            Some(REJECTED_TOKEN_TTL)
+        );
+    }
+
+    #[test]
+    fn test_shared_error_keeps_kind() {
+        let shared = |error: VaultError| {
+            let error = Arc::new(error);
+            let _waiter = error.clone();
+            shared_error(error)
+        };
+
+        assert!(matches!(
+            shared(VaultError::Unauthorized("denied".to_string())),
+            VaultError::Unauthorized(message) if message == "denied"
+        ));
+        assert!(matches!(
+            shared(VaultError::ClientError(ClientError::APIError {
+                code: 403,
+                errors: vec!["permission denied".to_string()],
+            })),
+            VaultError::ClientError(ClientError::APIError { code: 403, errors }) if errors.len() == 1
+        ));
+        assert!(matches!(
+            shared(VaultError::ClientError(ClientError::FileNotFoundError {
+                path: "ca.pem".to_string(),
+            })),
+            VaultError::RequestError(_)
+        ));
+        assert!(matches!(
+            shared(VaultError::SecretNotFound),
+            VaultError::SecretNotFound
+        ));
+    }
+
+    #[test]
+    fn test_cidr_binding() {
+        let token = VerifiedToken {
+            user_id: Uuid::new_v4(),
+            accessor: "accessor".to_string(),
+            expires_at: 0,
+            bound_cidrs: vec!["10.1.0.0/16".to_string(), "2001:db8::/32".to_string()],
+        };
+        assert!(token.allows_ip("10.1.200.3".parse().ok()));
+        assert!(token.allows_ip("2001:db8::1".parse().ok()));
+        assert!(!token.allows_ip("10.2.0.1".parse().ok()));
+        assert!(!token.allows_ip(None));
+
+        assert!(cidr_contains("0.0.0.0/0", "192.168.1.1".parse().unwrap()));
+        assert!(cidr_contains("192.168.1.1", "192.168.1.1".parse().unwrap()));
+        assert!(parse_cidr("10.0.0.0/33").is_none());
+    }
+
+    #[test]
+    fn test_user_logins_end_before_their_lease() {
+        let client = || client_with_token("http://localhost:8200", "token").unwrap();
+        assert_eq!(
+            UserLogin::new(client(), 600).valid_for,
+            Duration::from_secs(540)
+        );
+        assert_eq!(UserLogin::new(client(), 0).valid_for, MAX_USER_LOGIN_REUSE);
+        assert_eq!(
+            UserLogin::new(client(), 86_400).valid_for,
+            MAX_USER_LOGIN_REUSE
+        );
+    }
+
+    #[test]
+    fn test_token_format() {
+        assert!(check_token_format("hvs.CAESIJ-abc_123").is_ok());
+        assert!(matches!(
+            check_token_format(""),
+            Err(VerificationError::Malformed(_))
+        ));
+        assert!(matches!(
+            check_token_format("hvs.abc\r\nX-Injected: 1"),
+            Err(VerificationError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_scope_can_only_narrow() {
+        let profile = TokenScope {
+            policies: Some(vec!["team-a-member".to_string(), "default".to_string()]),
+            ttl: Some(3600),
+            ..TokenScope::default()
+        };
+
+        let narrowed = profile
+            .clone()
+            .restrict(TokenScope {
+                policies: Some(vec!["team-a-member".to_string()]),
+                ttl: Some(86400),
+                num_uses: Some(5),
+                ..TokenScope::default()
+            })
+            .unwrap();
+        assert_eq!(narrowed.ttl, Some(3600));
+        assert_eq!(narrowed.num_uses, Some(5));
+        assert_eq!(narrowed.policies, Some(vec!["team-a-member".to_string()]));
+
+        let escalation = profile.restrict(TokenScope {
+            policies: Some(vec!["global-admin".to_string()]),
+            ..TokenScope::default()
+        });
+        assert!(matches!(escalation, Err(VaultError::ScopeNotAllowed(_))));
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use vaultrs::error::ClientError;
+
+use super::{VaultError, VaultService};
+use crate::{AiclIdentity, TeamIdentity};
+
+/// Mount of the PKI secrets engine from `terraform/pki.tf`
+const MOUNT: &str = "pki";
+/// PKI role agent certificates are issued from
+const ROLE: &str = "team-agent";
+/// Lifetime of certificates when none is asked for
+pub const DEFAULT_CERTIFICATE_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// URI SAN prefixes carrying the team and user a certificate was issued for
+pub const TEAM_URI_PREFIX: &str = "urn:aicl:team:";
+pub const USER_URI_PREFIX: &str = "urn:aicl:user:";
+
+/// A client certificate with its private key. Vault doesn't keep the key, it is only
+/// handed out once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedCertificate {
+    pub serial_number: String,
+    pub certificate: String,
+    pub private_key: String,
+    pub ca_chain: Vec<String>,
+    pub expires_at: u64, // Unix timestamp in seconds
+}
+
+/// A certificate as Vault knows it, checked on every mTLS request
+#[derive(Debug, Clone)]
+pub struct VaultCertificate {
+    pub der: Vec<u8>,
+    pub revoked: bool,
+}
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct GeneratedCertificate {
+    serial_number: String,
+    certificate: String,
+    private_key: String,
+    #[serde(default)]
+    ca_chain: Vec<String>,
+    expiration: u64,
+}
+
+#[derive(Deserialize)]
+struct StoredCertificate {
+    certificate: String,
+    #[serde(default)]
+    revocation_time: i64,
+}
+
+impl VaultService {
+    /// Issue a client certificate for a member of a team
+    ///
+    /// The team and user are encoded as URI SANs, the common name is for humans only.
+    /// Whether the caller may issue it is up to them, the service's own token is used.
+    pub async fn issue_client_certificate(
+        &self,
+        team: &TeamIdentity,
+        user: &AiclIdentity,
+        ttl: Option<Duration>,
+    ) -> Result<IssuedCertificate, VaultError> {
+        let ttl = ttl.unwrap_or(DEFAULT_CERTIFICATE_TTL);
+        let body = serde_json::json!({
+            "common_name": format!("{}.{}.agents.aicl", user.username, team.name),
+            "uri_sans": format!("{}{},{}{}", TEAM_URI_PREFIX, team.id, USER_URI_PREFIX, user.id),
+            "ttl": format!("{}s", ttl.as_secs()),
+            "exclude_cn_from_sans": true,
+        });
+        let generated: Data<GeneratedCertificate> = self
+            .admin_request(
+                reqwest::Method::POST,
+                &format!("{}/issue/{}", MOUNT, ROLE),
+                Some(&body),
+            )
+            .await?
+            .ok_or_else(|| VaultError::RequestError("Vault returned no certificate".to_string()))?;
+
+        let generated = generated.data;
+        Ok(IssuedCertificate {
+            serial_number: generated.serial_number,
+            certificate: generated.certificate,
+            private_key: generated.private_key,
+            ca_chain: generated.ca_chain,
+            expires_at: generated.expiration,
+        })
+    }
+
+    /// Look a certificate up by serial number, `None` if Vault never issued it
+    ///
+    /// Lookups are cached briefly, so revocations made directly in Vault apply within
+    /// [`CERTIFICATE_CACHE_TTL`](super::CERTIFICATE_CACHE_TTL).
+    pub async fn client_certificate(
+        &self,
+        serial_number: &str,
+    ) -> Result<Option<VaultCertificate>, VaultError> {
+        if let Some(certificate) = self.certificate_cache.get(serial_number).await {
+            return Ok(Some(certificate));
+        }
+
+        let stored = match self
+            .admin_request::<Data<StoredCertificate>>(
+                reqwest::Method::GET,
+                &format!("{}/cert/{}", MOUNT, serial_number),
+                None,
+            )
+            .await
+        {
+            Ok(Some(stored)) => stored.data,
+            Ok(None) | Err(VaultError::ClientError(ClientError::APIError { code: 404, .. })) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let der = pem_to_der(&stored.certificate).ok_or_else(|| {
+            VaultError::RequestError("Vault returned an invalid certificate".to_string())
+        })?;
+        let certificate = VaultCertificate {
+            der,
+            revoked: stored.revocation_time > 0,
+        };
+
+        self.certificate_cache
+            .insert(serial_number.to_string(), certificate.clone())
+            .await;
+        Ok(Some(certificate))
+    }
+
+    /// Revoke a certificate and publish it on the CRL
+    pub async fn revoke_client_certificate(&self, serial_number: &str) -> Result<(), VaultError> {
+        let body = serde_json::json!({ "serial_number": serial_number });
+        self.admin_request::<serde_json::Value>(
+            reqwest::Method::POST,
+            &format!("{}/revoke", MOUNT),
+            Some(&body),
+        )
+        .await?;
+        self.certificate_cache.invalidate(serial_number).await;
+        Ok(())
+    }
+}
+
+/// DER of the first certificate in a PEM document
+pub fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let start = pem.find("-----BEGIN CERTIFICATE-----")? + "-----BEGIN CERTIFICATE-----".len();
+    let end = start + pem[start..].find("-----END CERTIFICATE-----")?;
+    let encoded: String = pem[start..end]
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    STANDARD.decode(encoded).ok()
+}
+
+/// Serial number of a DER certificate, formatted like Vault does, e.g. `3c:0f:9a`
+pub fn certificate_serial(der: &[u8]) -> Option<String> {
+    let (0x30, certificate, _) = der_element(der)? else {
+        return None;
+    };
+    let (0x30, tbs_certificate, _) = der_element(certificate)? else {
+        return None;
+    };
+    let (mut tag, mut serial, rest) = der_element(tbs_certificate)?;
+    // Skip the explicitly tagged version
+    if tag == 0xa0 {
+        (tag, serial, _) = der_element(rest)?;
+    }
+    if tag != 0x02 || serial.is_empty() {
+        return None;
+    }
+
+    // Vault drops leading zero bytes
+    let first = serial
+        .iter()
+        .position(|&b| b != 0)
+        .unwrap_or(serial.len() - 1);
+    let hex: Vec<String> = serial[first..]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Some(hex.join(":"))
+}
+
+// Tag and contents of the DER element at the start of `der`, and the bytes after it
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&length, rest) = rest.split_first()?;
+    let (length, rest) = if length < 0x80 {
+        (length as usize, rest)
+    } else {
+        let bytes = (length & 0x7f) as usize;
+        if bytes == 0 || bytes > 4 || rest.len() < bytes {
+            return None;
+        }
+        let length = rest[..bytes]
+            .iter()
+            .fold(0usize, |length, &b| length << 8 | b as usize);
+        (length, &rest[bytes..])
+    };
+    if rest.len() < length {
+        return None;
+    }
+    Some((tag, &rest[..length], &rest[length..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_is_read_like_vault_formats_it() {
+        // Certificate { TbsCertificate { [0] { INTEGER 2 }, INTEGER 00 8f 01, ... } }
+        let der = [
+            0x30, 0x0c, 0x30, 0x0a, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x03, 0x00, 0x8f, 0x01,
+        ];
+        assert_eq!(certificate_serial(&der).as_deref(), Some("8f:01"));
+
+        // Version 1 certificates have no version field
+        let der = [0x30, 0x05, 0x30, 0x03, 0x02, 0x01, 0x2a];
+        assert_eq!(certificate_serial(&der).as_deref(), Some("2a"));
+
+        // Truncated
+        assert_eq!(certificate_serial(&der[..5]), None);
+
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            STANDARD.encode(der)
+        );
+        assert_eq!(pem_to_der(&pem).unwrap(), der);
+    }
+}
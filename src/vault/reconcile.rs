@@ -0,0 +1,632 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use vaultrs::error::ClientError;
+
+use super::{database::MOUNT as DATABASE_MOUNT, VaultError, VaultService};
+
+/// Lifetimes of the tokens the JWT roles issue, as in `terraform/modules/team/vault.tf`
+const JWT_TOKEN_TTL: u64 = 3600;
+const JWT_TOKEN_MAX_TTL: u64 = 86400;
+/// Lifetimes of the API tokens the token roles issue
+const TOKEN_PERIOD: u64 = 86400;
+const TOKEN_EXPLICIT_MAX_TTL: u64 = 604800;
+/// Lifetimes of the Postgres users the database roles issue
+const DATABASE_DEFAULT_TTL: u64 = 3600;
+const DATABASE_MAX_TTL: u64 = 86400;
+
+/// The kinds of Vault objects a team or institution needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceKind {
+    Policy,
+    JwtRole,
+    TokenRole,
+    DatabaseRole,
+}
+
+impl ResourceKind {
+    const ALL: [ResourceKind; 4] = [
+        Self::Policy,
+        Self::JwtRole,
+        Self::TokenRole,
+        Self::DatabaseRole,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Policy => "policy",
+            Self::JwtRole => "jwt_role",
+            Self::TokenRole => "token_role",
+            Self::DatabaseRole => "database_role",
+        }
+    }
+
+    fn path(&self, jwt_mount: &str) -> String {
+        match self {
+            Self::Policy => "sys/policies/acl".to_string(),
+            Self::JwtRole => format!("auth/{}/role", jwt_mount),
+            Self::TokenRole => "auth/token/roles".to_string(),
+            Self::DatabaseRole => format!("{}/roles", DATABASE_MOUNT),
+        }
+    }
+}
+
+/// A Vault object, e.g. the `team-Team1-member` policy
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Resource {
+    pub kind: ResourceKind,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Create,
+    Update,
+    Delete,
+}
+
+/// One change the reconciler makes, or would make in a dry run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub resource: Resource,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.kind {
+            ChangeKind::Create => '+',
+            ChangeKind::Update => '~',
+            ChangeKind::Delete => '-',
+        };
+        write!(
+            f,
+            "{} {} {}",
+            sign,
+            self.resource.kind.as_str(),
+            self.resource.name
+        )
+    }
+}
+
+/// The changes bringing Vault in line with the teams and institutions
+///
+/// Displays as a diff, one change per line: `+` creates, `~` updates and `-` deletes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReconcilePlan {
+    pub changes: Vec<Change>,
+    /// Whether the changes were made
+    pub applied: bool,
+}
+
+impl ReconcilePlan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Teams whose objects the plan deletes, those that were removed
+    pub fn removed_teams(&self) -> BTreeSet<&str> {
+        self.changes
+            .iter()
+            .filter(|change| change.kind == ChangeKind::Delete)
+            .filter_map(|change| {
+                let rest = change.resource.name.strip_prefix("team-")?;
+                rest.strip_suffix("-member")
+                    .or_else(|| rest.strip_suffix("-captain"))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for ReconcilePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "No changes, Vault matches the teams and institutions");
+        }
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// The policies, JWT roles, token roles and database roles teams and institutions should have
+///
+/// Grants what `terraform/modules/team/vault.tf` and `terraform/modules/institution/vault.tf`
+/// do. Policies Terraform wrote are rewritten once in this form, teams managed by the
+/// reconciler should then be left out of Terraform so the two don't keep undoing each other.
+#[derive(Debug, Clone, Default)]
+pub struct DesiredRoles {
+    resources: BTreeMap<Resource, serde_json::Value>,
+    teams: Vec<String>,
+    // Database connection the teams' database roles use, if they are managed
+    database: Option<String>,
+}
+
+impl DesiredRoles {
+    /// `audience` is the OIDC client id the JWT roles accept tokens for
+    pub fn new(
+        audience: &str,
+        teams: impl IntoIterator<Item = impl AsRef<str>>,
+        institutions: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        let mut desired = Self::default();
+        for team in teams {
+            let team = team.as_ref();
+            if !valid_name(team) {
+                tracing::warn!("Team {:?} can't be named in Vault, skipping it", team);
+                continue;
+            }
+            desired.teams.push(team.to_string());
+            let group = format!("/Teams/{}", team);
+            for (level, role_claim) in [("member", None), ("captain", Some("captain"))] {
+                let name = format!("team-{}-{}", team, level);
+                desired.add(
+                    &name,
+                    team_policy(team, level),
+                    audience,
+                    &group,
+                    role_claim,
+                );
+            }
+        }
+        for institution in institutions {
+            let institution = institution.as_ref();
+            if !valid_name(institution) {
+                tracing::warn!(
+                    "Institution {:?} can't be named in Vault, skipping it",
+                    institution
+                );
+                continue;
+            }
+            let group = format!("/Institutions/{}", institution);
+            for level in ["spectator", "advisor"] {
+                let name = format!("institution-{}-{}", institution, level);
+                let policy = institution_policy(institution, level);
+                desired.add(&name, policy, audience, &group, Some(level));
+            }
+        }
+        desired
+    }
+
+    /// Also manage the teams' database roles, issuing users of the `connection` database
+    ///
+    /// Without it, database roles are left alone, e.g. when the database secrets engine
+    /// isn't mounted.
+    pub fn with_database_roles(mut self, connection: &str) -> Self {
+        for team in &self.teams {
+            for level in ["member", "captain"] {
+                self.resources.insert(
+                    Resource {
+                        kind: ResourceKind::DatabaseRole,
+                        name: format!("team-{}-{}", team, level),
+                    },
+                    database_role(team, level, connection),
+                );
+            }
+        }
+        self.database = Some(connection.to_string());
+        self
+    }
+
+    fn manages(&self, kind: ResourceKind) -> bool {
+        kind != ResourceKind::DatabaseRole || self.database.is_some()
+    }
+
+    fn add(
+        &mut self,
+        name: &str,
+        policy: String,
+        audience: &str,
+        group: &str,
+        role_claim: Option<&str>,
+    ) {
+        let mut bound_claims = serde_json::json!({ "groups": group });
+        if let Some(role) = role_claim {
+            bound_claims["roles"] = role.into();
+        }
+        let resource = |kind| Resource {
+            kind,
+            name: name.to_string(),
+        };
+
+        self.resources.insert(
+            resource(ResourceKind::Policy),
+            serde_json::json!({ "policy": policy }),
+        );
+        self.resources.insert(
+            resource(ResourceKind::JwtRole),
+            serde_json::json!({
+                "role_type": "jwt",
+                "token_ttl": JWT_TOKEN_TTL,
+                "token_max_ttl": JWT_TOKEN_MAX_TTL,
+                "token_policies": [name],
+                "bound_audiences": [audience],
+                "user_claim": "sub",
+                "bound_claims": bound_claims,
+                "groups_claim": "groups",
+                "claim_mappings": {
+                    "preferred_username": "username",
+                    "email": "email",
+                },
+            }),
+        );
+        self.resources.insert(
+            resource(ResourceKind::TokenRole),
+            serde_json::json!({
+                "allowed_policies": [name],
+                "orphan": true,
+                "renewable": true,
+                "token_period": TOKEN_PERIOD,
+                "token_explicit_max_ttl": TOKEN_EXPLICIT_MAX_TTL,
+                "path_suffix": name,
+            }),
+        );
+    }
+}
+
+// Names end up in policy paths and role names, anything else could widen a policy
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Whether the reconciler owns the object. Global roles, e.g. `global-admin`, are left alone.
+fn managed(name: &str) -> bool {
+    let team = name
+        .strip_prefix("team-")
+        .is_some_and(|rest| rest.ends_with("-member") || rest.ends_with("-captain"));
+    let institution = name
+        .strip_prefix("institution-")
+        .is_some_and(|rest| rest.ends_with("-advisor") || rest.ends_with("-spectator"));
+    team || institution
+}
+
+fn team_policy(team: &str, level: &str) -> String {
+    let (secrets, metadata) = match level {
+        "captain" => (
+            r#"["create", "read", "update", "delete", "list"]"#,
+            r#"["read", "list", "delete"]"#,
+        ),
+        _ => (r#"["read", "list"]"#, r#"["read", "list"]"#),
+    };
+    format!(
+        r#"# Team secrets
+path "secret/data/teams/{team}/*" {{
+  capabilities = {secrets}
+}}
+
+# Team metadata
+path "secret/metadata/teams/{team}/*" {{
+  capabilities = {metadata}
+}}
+
+# Create API tokens from the team's token role
+path "auth/token/create/team-{team}-{level}" {{
+  capabilities = ["create", "read", "update"]
+}}
+
+# Request short-lived database credentials
+path "database/creds/team-{team}-{level}" {{
+  capabilities = ["read"]
+}}
+"#
+    )
+}
+
+/// Postgres users limited to the team's schema, as in `terraform/modules/team/vault.tf`
+///
+/// Members read and write data, captains also own the schema's tables.
+fn database_role(team: &str, level: &str, connection: &str) -> serde_json::Value {
+    let schema = format!("team_{}", team.to_lowercase());
+    let (creation, revocation) = match level {
+        "captain" => (
+            [
+                format!(r#"GRANT ALL ON SCHEMA "{schema}" TO "{{{{name}}}}";"#),
+                format!(r#"GRANT ALL ON ALL TABLES IN SCHEMA "{schema}" TO "{{{{name}}}}";"#),
+            ],
+            [
+                r#"REASSIGN OWNED BY "{{name}}" TO CURRENT_USER;"#.to_string(),
+                r#"DROP OWNED BY "{{name}}";"#.to_string(),
+            ],
+        ),
+        _ => (
+            [
+                format!(r#"GRANT USAGE ON SCHEMA "{schema}" TO "{{{{name}}}}";"#),
+                format!(
+                    r#"GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA "{schema}" TO "{{{{name}}}}";"#
+                ),
+            ],
+            [
+                format!(r#"REVOKE ALL ON ALL TABLES IN SCHEMA "{schema}" FROM "{{{{name}}}}";"#),
+                format!(r#"REVOKE ALL ON SCHEMA "{schema}" FROM "{{{{name}}}}";"#),
+            ],
+        ),
+    };
+    let mut creation_statements = vec![
+        format!(r#"CREATE SCHEMA IF NOT EXISTS "{schema}";"#),
+        r#"CREATE ROLE "{{name}}" WITH LOGIN PASSWORD '{{password}}' VALID UNTIL '{{expiration}}';"#
+            .to_string(),
+    ];
+    creation_statements.extend(creation);
+    let mut revocation_statements = revocation.to_vec();
+    revocation_statements.push(r#"DROP ROLE IF EXISTS "{{name}}";"#.to_string());
+
+    serde_json::json!({
+        "db_name": connection,
+        "default_ttl": DATABASE_DEFAULT_TTL,
+        "max_ttl": DATABASE_MAX_TTL,
+        "creation_statements": creation_statements,
+        "revocation_statements": revocation_statements,
+    })
+}
+
+fn institution_policy(institution: &str, level: &str) -> String {
+    let (secrets, metadata, shared) = match level {
+        "advisor" => (
+            r#"["create", "read", "update", "delete", "list"]"#,
+            r#"["read", "list", "delete"]"#,
+            r#"["read", "list", "update"]"#,
+        ),
+        _ => (
+            r#"["read", "list"]"#,
+            r#"["read", "list"]"#,
+            r#"["read", "list"]"#,
+        ),
+    };
+    format!(
+        r#"# Institution secrets
+path "secret/data/institutions/{institution}/*" {{
+  capabilities = {secrets}
+}}
+
+# Institution metadata
+path "secret/metadata/institutions/{institution}/*" {{
+  capabilities = {metadata}
+}}
+
+# Secrets shared across the institution
+path "secret/data/institutions/byinstitution/{institution}/*" {{
+  capabilities = {shared}
+}}
+
+# Create API tokens from the institution's token roles
+path "auth/token/create/institution-{institution}-{level}" {{
+  capabilities = ["create", "read", "update"]
+}}
+
+path "auth/token/create/institution-{institution}-read" {{
+  capabilities = ["create", "read", "update"]
+}}
+"#
+    )
+}
+
+/// Whether Vault's copy of an object has every field we set, with the same value
+///
+/// Vault returns defaults for the fields we leave out, those don't count as drift.
+fn up_to_date(desired: &serde_json::Value, actual: &serde_json::Value) -> bool {
+    let (Some(desired), Some(actual)) = (desired.as_object(), actual.as_object()) else {
+        return desired == actual;
+    };
+    desired
+        .iter()
+        .all(|(key, value)| match (value, actual.get(key)) {
+            // Vault may reformat the policy's whitespace at the ends
+            (serde_json::Value::String(value), Some(serde_json::Value::String(actual))) => {
+                value.trim() == actual.trim()
+            }
+            (value, Some(actual)) => value == actual,
+            (_, None) => false,
+        })
+}
+
+/// The changes turning `existing` into `desired`
+///
+/// Creates and updates come policies first, deletes come roles first, so no role ever
+/// points to a missing policy. Objects the reconciler doesn't manage are never deleted,
+/// and neither is anything when there are no teams or institutions at all: an empty list
+/// is more likely a failed sync than a wiped competition.
+fn plan(desired: &DesiredRoles, existing: &BTreeMap<Resource, serde_json::Value>) -> Vec<Change> {
+    let mut changes: Vec<Change> = desired
+        .resources
+        .iter()
+        .filter_map(|(resource, value)| {
+            let kind = match existing.get(resource) {
+                None => ChangeKind::Create,
+                Some(actual) if !up_to_date(value, actual) => ChangeKind::Update,
+                Some(_) => return None,
+            };
+            Some(Change {
+                kind,
+                resource: resource.clone(),
+            })
+        })
+        .collect();
+
+    if !desired.resources.is_empty() {
+        let mut deletes: Vec<Change> = existing
+            .keys()
+            .filter(|resource| managed(&resource.name))
+            .filter(|resource| !desired.resources.contains_key(*resource))
+            .map(|resource| Change {
+                kind: ChangeKind::Delete,
+                resource: resource.clone(),
+            })
+            .collect();
+        deletes.reverse();
+        changes.extend(deletes);
+    }
+    changes
+}
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct Keys {
+    #[serde(default)]
+    keys: Vec<String>,
+}
+
+impl VaultService {
+    /// Bring the team and institution policies, JWT roles, token roles and database roles
+    /// in line
+    ///
+    /// Missing objects are created, drifted ones rewritten and those of teams and
+    /// institutions that no longer exist deleted. Running it again without changes to the
+    /// teams does nothing. With `dry_run`, Vault is only read and the plan says what would
+    /// change.
+    pub async fn reconcile_roles(
+        &self,
+        desired: &DesiredRoles,
+        dry_run: bool,
+    ) -> Result<ReconcilePlan, VaultError> {
+        let existing = self.managed_resources(desired).await?;
+        let changes = plan(desired, &existing);
+        if dry_run || changes.is_empty() {
+            return Ok(ReconcilePlan {
+                changes,
+                applied: false,
+            });
+        }
+
+        for change in &changes {
+            let path = format!(
+                "{}/{}",
+                change.resource.kind.path(&self.config.oidc_path),
+                change.resource.name
+            );
+            let (method, body) = match change.kind {
+                ChangeKind::Create | ChangeKind::Update => (
+                    reqwest::Method::POST,
+                    desired.resources.get(&change.resource),
+                ),
+                ChangeKind::Delete => (reqwest::Method::DELETE, None),
+            };
+            self.admin_request::<serde_json::Value>(method, &path, body)
+                .await?;
+            tracing::info!("Reconciled Vault: {}", change);
+        }
+        Ok(ReconcilePlan {
+            changes,
+            applied: true,
+        })
+    }
+
+    // Every team and institution object in Vault, with its current settings
+    async fn managed_resources(
+        &self,
+        desired: &DesiredRoles,
+    ) -> Result<BTreeMap<Resource, serde_json::Value>, VaultError> {
+        let list = reqwest::Method::from_bytes(b"LIST").expect("LIST is a valid method");
+        let mut resources = BTreeMap::new();
+        for kind in ResourceKind::ALL
+            .into_iter()
+            .filter(|kind| desired.manages(*kind))
+        {
+            let path = kind.path(&self.config.oidc_path);
+            let names = match self
+                .admin_request::<Data<Keys>>(list.clone(), &path, None)
+                .await
+            {
+                Ok(listed) => listed.map(|listed| listed.data.keys).unwrap_or_default(),
+                // Nothing configured yet
+                Err(VaultError::ClientError(ClientError::APIError { code: 404, .. })) => vec![],
+                Err(e) => return Err(e),
+            };
+            for name in names.into_iter().filter(|name| managed(name)) {
+                let path = format!("{}/{}", path, name);
+                if let Some(read) = self
+                    .admin_request::<Data<serde_json::Value>>(reqwest::Method::GET, &path, None)
+                    .await?
+                {
+                    resources.insert(Resource { kind, name }, read.data);
+                }
+            }
+        }
+        Ok(resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_creates_updates_and_deletes_only_managed_roles() {
+        let desired = DesiredRoles::new("rust-app", ["Team1", "Team2"], ["School1"]);
+        assert_eq!(desired.resources.len(), 3 * 6);
+
+        // Once applied, nothing changes
+        let mut existing = desired.resources.clone();
+        assert!(plan(&desired, &existing).is_empty());
+
+        let resource = |kind, name: &str| Resource {
+            kind,
+            name: name.to_string(),
+        };
+        // Vault adds fields of its own, those are no drift
+        existing
+            .get_mut(&resource(ResourceKind::TokenRole, "team-Team1-member"))
+            .unwrap()["token_type"] = "default-service".into();
+        existing
+            .get_mut(&resource(ResourceKind::JwtRole, "team-Team1-captain"))
+            .unwrap()["bound_audiences"] = serde_json::json!(["other-app"]);
+        existing.remove(&resource(ResourceKind::Policy, "team-Team2-member"));
+        existing.insert(
+            resource(ResourceKind::JwtRole, "team-Gone-member"),
+            serde_json::json!({}),
+        );
+        existing.insert(
+            resource(ResourceKind::Policy, "team-Gone-member"),
+            serde_json::json!({}),
+        );
+        existing.insert(
+            resource(ResourceKind::Policy, "global-admin"),
+            serde_json::json!({}),
+        );
+
+        let changes = plan(&desired, &existing);
+        let removed = ReconcilePlan {
+            changes: changes.clone(),
+            applied: true,
+        };
+        assert_eq!(removed.removed_teams(), BTreeSet::from(["Gone"]));
+        let changes: Vec<String> = changes.iter().map(Change::to_string).collect();
+        assert_eq!(
+            changes,
+            [
+                "+ policy team-Team2-member",
+                "~ jwt_role team-Team1-captain",
+                "- jwt_role team-Gone-member",
+                "- policy team-Gone-member",
+            ]
+        );
+
+        // A failed sync doesn't wipe every team
+        assert!(plan(&DesiredRoles::default(), &existing).is_empty());
+
+        // Database roles for the teams, once the database secrets engine is there
+        assert!(!desired.manages(ResourceKind::DatabaseRole));
+        let desired = desired.with_database_roles("aicldb");
+        assert_eq!(desired.resources.len(), 3 * 6 + 2 * 2);
+        let captain =
+            &desired.resources[&resource(ResourceKind::DatabaseRole, "team-Team1-captain")];
+        assert_eq!(captain["db_name"], "aicldb");
+        assert_eq!(
+            captain["creation_statements"][2],
+            r#"GRANT ALL ON SCHEMA "team_team1" TO "{{name}}";"#
+        );
+        // Names that would widen a policy are skipped
+        let desired = DesiredRoles::new("rust-app", ["Team1/*"], Vec::<String>::new());
+        assert!(desired.resources.is_empty());
+    }
+}
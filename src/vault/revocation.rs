@@ -0,0 +1,131 @@
+use async_trait::async_trait;
+use futures_util::stream::{BoxStream, StreamExt};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+
+use super::VaultError;
+
+/// Postgres channel revocations are sent on
+pub const REVOCATION_CHANNEL: &str = "aicl_token_revocations";
+
+/// Tells the other replicas which API tokens were revoked
+///
+/// Messages carry token accessors, never the tokens themselves. Delivery is best effort:
+/// a replica that misses a message drops the token once its cached verification expires.
+#[async_trait]
+pub trait RevocationBus: Send + Sync {
+    /// Announce a revoked token to every replica, including this one
+    async fn publish(&self, accessor: &str) -> Result<(), VaultError>;
+
+    /// Accessors revoked from now on. The stream ends when the connection is lost.
+    async fn subscribe(&self) -> Result<BoxStream<'static, String>, VaultError>;
+}
+
+/// Revocations through Postgres `LISTEN`/`NOTIFY`, for replicas sharing a database
+pub struct PgRevocationBus {
+    db: PgPool,
+    channel: String,
+}
+
+impl PgRevocationBus {
+    pub fn new(db: PgPool) -> Self {
+        Self {
+            db,
+            channel: REVOCATION_CHANNEL.to_string(),
+        }
+    }
+
+    pub fn with_channel(mut self, channel: impl Into<String>) -> Self {
+        self.channel = channel.into();
+        self
+    }
+}
+
+#[async_trait]
+impl RevocationBus for PgRevocationBus {
+    async fn publish(&self, accessor: &str) -> Result<(), VaultError> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(&self.channel)
+            .bind(accessor)
+            .execute(&self.db)
+            .await
+            .map_err(|e| {
+                VaultError::RequestError(format!("Failed to publish revocation: {}", e))
+            })?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, String>, VaultError> {
+        let mut listener = PgListener::connect_with(&self.db)
+            .await
+            .map_err(|e| VaultError::RequestError(format!("Failed to listen: {}", e)))?;
+        listener
+            .listen(&self.channel)
+            .await
+            .map_err(|e| VaultError::RequestError(format!("Failed to listen: {}", e)))?;
+
+        // `try_recv` gives `None` when the connection drops, ending the stream
+        let notifications = futures_util::stream::unfold(listener, |mut listener| async move {
+            match listener.try_recv().await {
+                Ok(Some(notification)) => Some((notification.payload().to_string(), listener)),
+                Ok(None) | Err(_) => None,
+            }
+        });
+        Ok(notifications.boxed())
+    }
+}
+
+/// Revocations within one process, e.g. for tests or several services sharing a runtime
+#[derive(Clone)]
+pub struct LocalRevocationBus {
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for LocalRevocationBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(1024).0,
+        }
+    }
+}
+
+#[async_trait]
+impl RevocationBus for LocalRevocationBus {
+    async fn publish(&self, accessor: &str) -> Result<(), VaultError> {
+        // No subscribers is fine
+        let _ = self.sender.send(accessor.to_string());
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, String>, VaultError> {
+        // A receiver that lagged behind missed revocations, ending the stream covers that
+        let accessors =
+            futures_util::stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+                let accessor = receiver.recv().await.ok()?;
+                Some((accessor, receiver))
+            });
+        Ok(accessors.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_bus_reaches_every_subscriber() {
+        let bus = LocalRevocationBus::default();
+        let mut first = bus.subscribe().await.unwrap();
+        let mut second = bus.subscribe().await.unwrap();
+
+        bus.publish("accessor1").await.unwrap();
+        assert_eq!(first.next().await.as_deref(), Some("accessor1"));
+        assert_eq!(second.next().await.as_deref(), Some("accessor1"));
+
+        // Subscribers only see revocations published after they subscribed
+        let mut late = bus.subscribe().await.unwrap();
+        bus.publish("accessor2").await.unwrap();
+        assert_eq!(late.next().await.as_deref(), Some("accessor2"));
+    }
+}
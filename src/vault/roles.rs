@@ -0,0 +1,135 @@
+use crate::{AiclIdentity, Role};
+
+/// Maps identities to the Vault roles their logins and API tokens use
+///
+/// The JWT role is what the user's OIDC token is exchanged under, the token role is what
+/// their API tokens are created from. `None` means the identity gets no Vault access.
+pub trait VaultRoleMapper: Send + Sync {
+    /// JWT auth role for the identity's logins
+    fn jwt_role(&self, identity: &AiclIdentity) -> Option<String>;
+
+    /// Token role for the identity's API tokens, the JWT role by default
+    fn token_role(&self, identity: &AiclIdentity) -> Option<String> {
+        self.jwt_role(identity)
+    }
+}
+
+/// The roles Terraform sets up
+///
+/// | Identity                       | Role                             |
+/// |--------------------------------|----------------------------------|
+/// | Admin                          | `global-admin`                   |
+/// | Captain of a team              | `team-{team}-captain`            |
+/// | Student or spectator in a team | `team-{team}-member`             |
+/// | Advisor of an institution      | `institution-{name}-advisor`     |
+/// | Spectator of an institution    | `institution-{name}-spectator`   |
+/// | Spectator without either       | `global-spectator`               |
+/// | Student without a team         | `global-student`                 |
+///
+/// The global roles can be changed or turned off with `None`.
+#[derive(Debug, Clone)]
+pub struct DefaultRoleMapper {
+    pub admin_role: Option<String>,
+    pub spectator_role: Option<String>,
+    pub student_role: Option<String>,
+}
+
+impl Default for DefaultRoleMapper {
+    fn default() -> Self {
+        Self {
+            admin_role: Some("global-admin".to_string()),
+            spectator_role: Some("global-spectator".to_string()),
+            student_role: Some("global-student".to_string()),
+        }
+    }
+}
+
+impl VaultRoleMapper for DefaultRoleMapper {
+    fn jwt_role(&self, identity: &AiclIdentity) -> Option<String> {
+        match (&identity.team, &identity.institution, identity.role) {
+            (_, _, Role::Admin) => self.admin_role.clone(),
+            (Some(team), _, Role::Captain) => Some(format!("team-{}-captain", team.name)),
+            (Some(team), _, Role::Student | Role::Spectator) => {
+                Some(format!("team-{}-member", team.name))
+            }
+            (_, Some(institution), Role::Advisor) => {
+                Some(format!("institution-{}-advisor", institution.name))
+            }
+            (None, Some(institution), Role::Spectator) => {
+                Some(format!("institution-{}-spectator", institution.name))
+            }
+            (None, None, Role::Spectator) => self.spectator_role.clone(),
+            (None, _, Role::Student) => self.student_role.clone(),
+            // Captains without a team and advisors without an institution
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{InstitutionIdentity, TeamIdentity};
+
+    fn identity(role: Role, team: Option<&str>, institution: Option<&str>) -> AiclIdentity {
+        AiclIdentity {
+            id: Uuid::new_v4(),
+            email: "user@test.com".to_string(),
+            username: "user".to_string(),
+            team: team.map(|name| TeamIdentity {
+                id: Uuid::new_v4(),
+                name: name.to_string(),
+            }),
+            institution: institution.map(|name| InstitutionIdentity {
+                id: Uuid::new_v4(),
+                name: name.to_string(),
+            }),
+            role,
+        }
+    }
+
+    #[test]
+    fn test_every_role_maps_to_a_vault_role() {
+        let mapper = DefaultRoleMapper::default();
+        let role = |role, team, institution| mapper.jwt_role(&identity(role, team, institution));
+
+        assert_eq!(role(Role::Admin, None, None).unwrap(), "global-admin");
+        assert_eq!(
+            role(Role::Captain, Some("Team1"), Some("School1")).unwrap(),
+            "team-Team1-captain"
+        );
+        assert_eq!(
+            role(Role::Student, Some("Team1"), None).unwrap(),
+            "team-Team1-member"
+        );
+        assert_eq!(
+            role(Role::Advisor, None, Some("School1")).unwrap(),
+            "institution-School1-advisor"
+        );
+        assert_eq!(
+            role(Role::Spectator, None, Some("School1")).unwrap(),
+            "institution-School1-spectator"
+        );
+        assert_eq!(
+            role(Role::Spectator, None, None).unwrap(),
+            "global-spectator"
+        );
+        assert_eq!(
+            role(Role::Student, None, Some("School1")).unwrap(),
+            "global-student"
+        );
+        assert_eq!(role(Role::Captain, None, None), None);
+        assert_eq!(role(Role::Advisor, None, None), None);
+
+        let mapper = DefaultRoleMapper {
+            student_role: None,
+            ..DefaultRoleMapper::default()
+        };
+        assert_eq!(
+            mapper.token_role(&identity(Role::Student, None, None)),
+            None
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use std::{ops::Deref, sync::Arc, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use vaultrs::{client::VaultClient, error::ClientError, kv2};
+
+use super::{
+    http::vault_request_builder,
+    wrapping::{wrapped_request, WrappedResponse},
+    VaultError, VaultService,
+};
+use crate::{oidc::keycloak::KeyCloakToken, AiclIdentity, Role};
+
+/// KV v2 mount holding team and institution secrets
+const MOUNT: &str = "secret";
+
+/// One version of a secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretVersion {
+    pub version: u64,
+    pub created_time: String,
+    /// Set once the version was soft deleted
+    pub deletion_time: Option<String>,
+    pub destroyed: bool,
+}
+
+impl From<vaultrs::api::kv2::responses::SecretVersionMetadata> for SecretVersion {
+    fn from(metadata: vaultrs::api::kv2::responses::SecretVersionMetadata) -> Self {
+        Self {
+            version: metadata.version,
+            created_time: metadata.created_time,
+            deletion_time: Some(metadata.deletion_time).filter(|time| !time.is_empty()),
+            destroyed: metadata.destroyed,
+        }
+    }
+}
+
+/// Secrets under one KV v2 prefix, accessed as the calling user
+///
+/// Every call goes through the user's own Vault token, so Vault policy decides what they
+/// may read or change. A `403` from Vault comes back as [`VaultError::Unauthorized`].
+pub struct KvSecrets {
+    client: Arc<VaultClient>,
+    prefix: String,
+}
+
+impl KvSecrets {
+    fn path(&self, name: &str) -> Result<String, VaultError> {
+        let valid = !name.is_empty()
+            && name.split('/').all(|segment| {
+                !segment.is_empty()
+                    && segment != "."
+                    && segment != ".."
+                    && segment
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            });
+        if !valid {
+            return Err(VaultError::InvalidSecretName(name.to_string()));
+        }
+        Ok(format!("{}/{}", self.prefix, name))
+    }
+
+    /// Read the latest version of a secret
+    pub async fn read<T: DeserializeOwned>(&self, name: &str) -> Result<T, VaultError> {
+        kv2::read(&*self.client, MOUNT, &self.path(name)?)
+            .await
+            .map_err(secret_error)
+    }
+
+    /// Read the latest or the given version of a secret into a single-use wrapping token
+    ///
+    /// The secret stays in Vault until the recipient unwraps it with
+    /// [`VaultService::unwrap`].
+    pub async fn read_wrapped(
+        &self,
+        name: &str,
+        version: Option<u64>,
+        ttl: Option<Duration>,
+    ) -> Result<WrappedResponse, VaultError> {
+        let mut path = format!("{}/data/{}", MOUNT, self.path(name)?);
+        if let Some(version) = version {
+            path.push_str(&format!("?version={}", version));
+        }
+        let request = vault_request_builder(&self.client, reqwest::Method::GET, &path, None)?;
+        wrapped_request(request, ttl).await.map_err(|e| match e {
+            VaultError::ClientError(e) => secret_error(e),
+            e => e,
+        })
+    }
+
+    /// Read a specific version of a secret
+    pub async fn read_version<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        version: u64,
+    ) -> Result<T, VaultError> {
+        kv2::read_version(&*self.client, MOUNT, &self.path(name)?, version)
+            .await
+            .map_err(secret_error)
+    }
+
+    /// Write a new version of a secret
+    pub async fn write<T: Serialize>(
+        &self,
+        name: &str,
+        data: &T,
+    ) -> Result<SecretVersion, VaultError> {
+        kv2::set(&*self.client, MOUNT, &self.path(name)?, data)
+            .await
+            .map(SecretVersion::from)
+            .map_err(secret_error)
+    }
+
+    /// List the secrets and folders (ending in `/`) directly under `folder`, `None` for the root
+    pub async fn list(&self, folder: Option<&str>) -> Result<Vec<String>, VaultError> {
+        let path = match folder.map(|folder| folder.trim_end_matches('/')) {
+            Some(folder) if !folder.is_empty() => self.path(folder)?,
+            _ => self.prefix.clone(),
+        };
+        match kv2::list(&*self.client, MOUNT, &path).await {
+            Ok(keys) => Ok(keys),
+            // Vault answers 404 when there is nothing to list
+            Err(ClientError::APIError { code: 404, .. }) => Ok(Vec::new()),
+            Err(e) => Err(secret_error(e)),
+        }
+    }
+
+    /// Soft delete the latest version, older versions stay available for rollback
+    pub async fn delete(&self, name: &str) -> Result<(), VaultError> {
+        kv2::delete_latest(&*self.client, MOUNT, &self.path(name)?)
+            .await
+            .map_err(secret_error)
+    }
+
+    /// All versions of a secret, oldest first
+    pub async fn versions(&self, name: &str) -> Result<Vec<SecretVersion>, VaultError> {
+        let metadata = kv2::read_metadata(&*self.client, MOUNT, &self.path(name)?)
+            .await
+            .map_err(secret_error)?;
+        // The version number is only in the map's keys
+        let mut versions: Vec<SecretVersion> = metadata
+            .versions
+            .into_iter()
+            .filter_map(|(version, metadata)| {
+                Some(SecretVersion {
+                    version: version.parse().ok()?,
+                    created_time: metadata.created_time,
+                    deletion_time: Some(metadata.deletion_time).filter(|time| !time.is_empty()),
+                    destroyed: metadata.destroyed,
+                })
+            })
+            .collect();
+        versions.sort_by_key(|version| version.version);
+        Ok(versions)
+    }
+
+    /// Restore an older version by writing it again as the latest one
+    pub async fn rollback(&self, name: &str, version: u64) -> Result<SecretVersion, VaultError> {
+        let data: serde_json::Value = self.read_version(name, version).await?;
+        self.write(name, &data).await
+    }
+}
+
+fn secret_error(e: ClientError) -> VaultError {
+    match e {
+        ClientError::APIError { code: 403, .. } => {
+            VaultError::Unauthorized("Access to the secret was denied".to_string())
+        }
+        ClientError::APIError { code: 404, .. } => VaultError::SecretNotFound,
+        e => VaultError::ClientError(e),
+    }
+}
+
+/// Secrets of the user's team under `secret/teams/{team}`. Captains write, members read.
+pub struct TeamSecrets(KvSecrets);
+
+impl TeamSecrets {
+    pub fn into_inner(self) -> KvSecrets {
+        self.0
+    }
+}
+
+impl Deref for TeamSecrets {
+    type Target = KvSecrets;
+
+    fn deref(&self) -> &KvSecrets {
+        &self.0
+    }
+}
+
+/// Secrets of the user's institution under `secret/institutions/{institution}`.
+/// Advisors write, spectators read.
+pub struct InstitutionSecrets(KvSecrets);
+
+impl InstitutionSecrets {
+    pub fn into_inner(self) -> KvSecrets {
+        self.0
+    }
+}
+
+impl Deref for InstitutionSecrets {
+    type Target = KvSecrets;
+
+    fn deref(&self) -> &KvSecrets {
+        &self.0
+    }
+}
+
+impl VaultService {
+    /// The user's team secrets, accessed with a Vault token from their OIDC login
+    pub async fn team_secrets(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+    ) -> Result<TeamSecrets, VaultError> {
+        let team = identity
+            .team
+            .as_ref()
+            .ok_or_else(|| VaultError::Unauthorized("User is not in a team".to_string()))?;
+        let vault_role = match identity.role {
+            Role::Captain => format!("team-{}-captain", team.name),
+            _ => format!("team-{}-member", team.name),
+        };
+
+        let client = self
+            .create_user_vault_client(&oidc_token.id_token.to_string(), Some(vault_role))
+            .await?;
+        Ok(TeamSecrets(KvSecrets {
+            client,
+            prefix: format!("teams/{}", team.name),
+        }))
+    }
+
+    /// The user's institution secrets, accessed with a Vault token from their OIDC login
+    pub async fn institution_secrets(
+        &self,
+        identity: &AiclIdentity,
+        oidc_token: &KeyCloakToken,
+    ) -> Result<InstitutionSecrets, VaultError> {
+        let institution = identity
+            .institution
+            .as_ref()
+            .ok_or_else(|| VaultError::Unauthorized("User is not in an institution".to_string()))?;
+        let vault_role = match identity.role {
+            Role::Advisor => format!("institution-{}-advisor", institution.name),
+            _ => format!("institution-{}-spectator", institution.name),
+        };
+
+        let client = self
+            .create_user_vault_client(&oidc_token.id_token.to_string(), Some(vault_role))
+            .await?;
+        Ok(InstitutionSecrets(KvSecrets {
+            client,
+            prefix: format!("institutions/{}", institution.name),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::auth::client_with_token;
+
+    #[test]
+    fn test_secret_names_stay_under_the_prefix() {
+        let secrets = KvSecrets {
+            client: Arc::new(client_with_token("http://localhost:8200", "token").unwrap()),
+            prefix: "teams/red".to_string(),
+        };
+
+        assert_eq!(
+            secrets.path("ci/deploy-key").unwrap(),
+            "teams/red/ci/deploy-key"
+        );
+        for name in ["", "../blue/keys", "ci//key", "/root", "key?version=1"] {
+            assert!(
+                matches!(secrets.path(name), Err(VaultError::InvalidSecretName(_))),
+                "{} should be rejected",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use std::{fmt, marker::PhantomData, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres, Type,
+};
+use uuid::Uuid;
+use vaultrs::api::transit::requests::{
+    DecryptDataRequestBuilder, EncryptDataRequestBuilder, RewrapDataRequestBuilder,
+};
+
+use super::{VaultError, VaultService};
+
+/// Transit mount and key from `terraform/transit.tf`
+const DEFAULT_MOUNT: &str = "transit";
+const DEFAULT_KEY: &str = "aicl-data";
+
+/// Which derived key protects a value
+///
+/// The transit key is created with `derived = true`, so every team and institution gets its
+/// own encryption key without managing one transit key per team.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyContext {
+    Global,
+    Team(Uuid),
+    Institution(Uuid),
+}
+
+impl KeyContext {
+    /// Base64 encoded derivation context, as transit expects it
+    fn encoded(&self) -> String {
+        let context = match self {
+            Self::Global => "global".to_string(),
+            Self::Team(id) => format!("team:{}", id),
+            Self::Institution(id) => format!("institution:{}", id),
+        };
+        STANDARD.encode(context)
+    }
+}
+
+/// Envelope encryption through Vault's transit engine
+///
+/// Keys never leave Vault. Ciphertexts carry the key version (`vault:v3:...`), so they stay
+/// readable after a rotation until the minimum decryption version is raised, and
+/// [`VaultCrypto::rewrap`] moves them to the latest version without exposing the plaintext.
+pub struct VaultCrypto {
+    vault: Arc<VaultService>,
+    mount: String,
+    key: String,
+}
+
+impl VaultCrypto {
+    /// Use the transit key `aicl-data` on the `transit` mount
+    pub fn new(vault: Arc<VaultService>) -> Self {
+        Self::with_key(vault, DEFAULT_MOUNT, DEFAULT_KEY)
+    }
+
+    pub fn with_key(vault: Arc<VaultService>, mount: &str, key: &str) -> Self {
+        Self {
+            vault,
+            mount: mount.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    /// Encrypt `plaintext` under the context's key
+    pub async fn encrypt(
+        &self,
+        context: KeyContext,
+        plaintext: &[u8],
+    ) -> Result<String, VaultError> {
+        let plaintext = STANDARD.encode(plaintext);
+        let context = context.encoded();
+        let response = self
+            .vault
+            .with_admin(|client| {
+                let mut opts = EncryptDataRequestBuilder::default();
+                opts.context(context.clone());
+                let plaintext = plaintext.clone();
+                async move {
+                    vaultrs::transit::data::encrypt(
+                        &*client,
+                        &self.mount,
+                        &self.key,
+                        &plaintext,
+                        Some(&mut opts),
+                    )
+                    .await
+                }
+            })
+            .await?;
+        Ok(response.ciphertext)
+    }
+
+    /// Decrypt a ciphertext produced by [`VaultCrypto::encrypt`] with the same context
+    pub async fn decrypt(
+        &self,
+        context: KeyContext,
+        ciphertext: &str,
+    ) -> Result<Vec<u8>, VaultError> {
+        let context = context.encoded();
+        let response = self
+            .vault
+            .with_admin(|client| {
+                let mut opts = DecryptDataRequestBuilder::default();
+                opts.context(context.clone());
+                async move {
+                    vaultrs::transit::data::decrypt(
+                        &*client,
+                        &self.mount,
+                        &self.key,
+                        ciphertext,
+                        Some(&mut opts),
+                    )
+                    .await
+                }
+            })
+            .await?;
+        STANDARD
+            .decode(response.plaintext)
+            .map_err(|e| VaultError::EncryptionError(format!("Invalid plaintext: {}", e)))
+    }
+
+    /// Re-encrypt a ciphertext with the latest key version
+    pub async fn rewrap(
+        &self,
+        context: KeyContext,
+        ciphertext: &str,
+    ) -> Result<String, VaultError> {
+        let context = context.encoded();
+        let response = self
+            .vault
+            .with_admin(|client| {
+                let mut opts = RewrapDataRequestBuilder::default();
+                opts.context(context.clone());
+                async move {
+                    vaultrs::transit::data::rewrap(
+                        &*client,
+                        &self.mount,
+                        &self.key,
+                        ciphertext,
+                        Some(&mut opts),
+                    )
+                    .await
+                }
+            })
+            .await?;
+        Ok(response.ciphertext)
+    }
+
+    /// Encrypt many values in one request, keeping their order
+    ///
+    /// The outer error is for the request, the inner ones for the values Vault refused.
+    pub async fn encrypt_batch<P: AsRef<[u8]>>(
+        &self,
+        context: KeyContext,
+        plaintexts: &[P],
+    ) -> Result<Vec<Result<String, VaultError>>, VaultError> {
+        let context = context.encoded();
+        let items = plaintexts
+            .iter()
+            .map(|plaintext| {
+                serde_json::json!({
+                    "plaintext": STANDARD.encode(plaintext.as_ref()),
+                    "context": context,
+                })
+            })
+            .collect();
+        let results = self.batch("encrypt", items).await?;
+        Ok(results
+            .into_iter()
+            .map(|result| result.and_then(|result| present(result.ciphertext)))
+            .collect())
+    }
+
+    /// Decrypt many values in one request, keeping their order
+    ///
+    /// The outer error is for the request, the inner ones for the values Vault refused.
+    pub async fn decrypt_batch<C: AsRef<str>>(
+        &self,
+        context: KeyContext,
+        ciphertexts: &[C],
+    ) -> Result<Vec<Result<Vec<u8>, VaultError>>, VaultError> {
+        let context = context.encoded();
+        let items = ciphertexts
+            .iter()
+            .map(|ciphertext| {
+                serde_json::json!({ "ciphertext": ciphertext.as_ref(), "context": context })
+            })
+            .collect();
+        let results = self.batch("decrypt", items).await?;
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                let plaintext = result.and_then(|result| present(result.plaintext))?;
+                STANDARD
+                    .decode(plaintext)
+                    .map_err(|e| VaultError::EncryptionError(format!("Invalid plaintext: {}", e)))
+            })
+            .collect())
+    }
+
+    /// Rewrap many values in one request, keeping their order
+    ///
+    /// The outer error is for the request, the inner ones for the values Vault refused.
+    pub async fn rewrap_batch<C: AsRef<str>>(
+        &self,
+        context: KeyContext,
+        ciphertexts: &[C],
+    ) -> Result<Vec<Result<String, VaultError>>, VaultError> {
+        let context = context.encoded();
+        let items = ciphertexts
+            .iter()
+            .map(|ciphertext| {
+                serde_json::json!({ "ciphertext": ciphertext.as_ref(), "context": context })
+            })
+            .collect();
+        let results = self.batch("rewrap", items).await?;
+        Ok(results
+            .into_iter()
+            .map(|result| result.and_then(|result| present(result.ciphertext)))
+            .collect())
+    }
+
+    // Send a transit operation with `batch_input`, one result per item
+    async fn batch(
+        &self,
+        operation: &str,
+        items: Vec<serde_json::Value>,
+    ) -> Result<Vec<Result<BatchResult, VaultError>>, VaultError> {
+        if items.is_empty() {
+            return Ok(vec![]);
+        }
+        let expected = items.len();
+        let response: BatchResponse = self
+            .vault
+            .admin_request(
+                reqwest::Method::POST,
+                &format!("{}/{}/{}", self.mount, operation, self.key),
+                Some(&serde_json::json!({ "batch_input": items })),
+            )
+            .await?
+            .ok_or_else(|| VaultError::EncryptionError("Vault returned no batch results".into()))?;
+
+        let results = response.data.batch_results;
+        if results.len() != expected {
+            return Err(VaultError::EncryptionError(format!(
+                "Vault returned {} results for {} values",
+                results.len(),
+                expected
+            )));
+        }
+        Ok(results
+            .into_iter()
+            .map(|result| match &result.error {
+                Some(error) if !error.is_empty() => Err(VaultError::EncryptionError(error.clone())),
+                _ => Ok(result),
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchResponse {
+    data: BatchResults,
+}
+
+#[derive(Deserialize)]
+struct BatchResults {
+    batch_results: Vec<BatchResult>,
+}
+
+#[derive(Deserialize)]
+struct BatchResult {
+    #[serde(default)]
+    ciphertext: Option<String>,
+    #[serde(default)]
+    plaintext: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+fn present(value: Option<String>) -> Result<String, VaultError> {
+    value.ok_or_else(|| VaultError::EncryptionError("Vault returned an empty result".into()))
+}
+
+/// Key version a transit ciphertext was encrypted with
+pub fn key_version(ciphertext: &str) -> Option<u32> {
+    ciphertext
+        .strip_prefix("vault:v")?
+        .split_once(':')?
+        .0
+        .parse()
+        .ok()
+}
+
+/// A value stored as transit ciphertext
+///
+/// Stored in `TEXT` columns and serialized as the ciphertext string, so it can't leak the
+/// plaintext through a query or a log line. The value is read back with
+/// [`Encrypted::open`], using the same [`KeyContext`] it was sealed with.
+pub struct Encrypted<T> {
+    ciphertext: String,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> Encrypted<T> {
+    /// Wrap a ciphertext read from elsewhere
+    pub fn from_ciphertext(ciphertext: String) -> Self {
+        Self {
+            ciphertext,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn ciphertext(&self) -> &str {
+        &self.ciphertext
+    }
+
+    pub fn into_ciphertext(self) -> String {
+        self.ciphertext
+    }
+
+    /// Key version the value is encrypted with
+    pub fn key_version(&self) -> Option<u32> {
+        key_version(&self.ciphertext)
+    }
+
+    /// Move the value to the latest key version
+    pub async fn rewrap(
+        &self,
+        crypto: &VaultCrypto,
+        context: KeyContext,
+    ) -> Result<Self, VaultError> {
+        Ok(Self::from_ciphertext(
+            crypto.rewrap(context, &self.ciphertext).await?,
+        ))
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Encrypted<T> {
+    /// Encrypt a value as JSON
+    pub async fn seal(
+        crypto: &VaultCrypto,
+        context: KeyContext,
+        value: &T,
+    ) -> Result<Self, VaultError> {
+        let plaintext = serde_json::to_vec(value)
+            .map_err(|e| VaultError::EncryptionError(format!("Failed to serialize: {}", e)))?;
+        Ok(Self::from_ciphertext(
+            crypto.encrypt(context, &plaintext).await?,
+        ))
+    }
+
+    /// Decrypt the value
+    pub async fn open(&self, crypto: &VaultCrypto, context: KeyContext) -> Result<T, VaultError> {
+        let plaintext = crypto.decrypt(context, &self.ciphertext).await?;
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| VaultError::EncryptionError(format!("Failed to deserialize: {}", e)))
+    }
+}
+
+impl<T> Clone for Encrypted<T> {
+    fn clone(&self) -> Self {
+        Self::from_ciphertext(self.ciphertext.clone())
+    }
+}
+
+impl<T> PartialEq for Encrypted<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ciphertext == other.ciphertext
+    }
+}
+
+impl<T> fmt::Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Encrypted").field(&self.ciphertext).finish()
+    }
+}
+
+impl<T> Serialize for Encrypted<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.ciphertext.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Encrypted<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from_ciphertext)
+    }
+}
+
+impl<T> Type<Postgres> for Encrypted<T> {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<T> Encode<'_, Postgres> for Encrypted<T> {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <&str as Encode<Postgres>>::encode(self.ciphertext.as_str(), buf)
+    }
+}
+
+impl<'r, T> Decode<'r, Postgres> for Encrypted<T> {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        <String as Decode<Postgres>>::decode(value).map(Self::from_ciphertext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ciphertexts_carry_their_key_version() {
+        assert_eq!(key_version("vault:v1:abcd"), Some(1));
+        assert_eq!(key_version("vault:v12:ab:cd"), Some(12));
+        assert_eq!(key_version("plaintext"), None);
+        assert_eq!(key_version("vault:vx:abcd"), None);
+
+        let encrypted = Encrypted::<String>::from_ciphertext("vault:v3:abcd".to_string());
+        assert_eq!(encrypted.key_version(), Some(3));
+        assert_eq!(
+            serde_json::to_string(&encrypted).unwrap(),
+            "\"vault:v3:abcd\""
+        );
+    }
+
+    #[test]
+    fn test_contexts_derive_different_keys() {
+        let team = Uuid::new_v4();
+        assert_ne!(
+            KeyContext::Team(team).encoded(),
+            KeyContext::Institution(team).encoded()
+        );
+        assert_eq!(KeyContext::Global.encoded(), STANDARD.encode("global"));
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vaultrs::error::ClientError;
+
+use super::{
+    auth::client_with_token,
+    http::{vault_request, vault_request_builder, vault_send},
+    VaultError, VaultService,
+};
+
+/// How long a wrapping token can be unwrapped when no TTL is given
+pub const DEFAULT_WRAP_TTL: Duration = Duration::from_secs(300);
+/// Longest a wrapping token may live, a value waiting for its recipient is a value at risk
+pub const MAX_WRAP_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// A single-use token standing in for a value, to hand to its recipient instead of the value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedResponse {
+    /// Unwraps the value once, then stops working
+    pub token: String,
+    /// Identifies the wrapping token in audit logs without revealing it
+    pub accessor: String,
+    pub expires_at: u64, // Unix timestamp in seconds
+    /// The Vault path that produced the value
+    pub creation_path: String,
+}
+
+/// A value delivered through a wrapping token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnwrappedValue {
+    pub creation_path: String,
+    pub data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct WrapInfo {
+    token: String,
+    accessor: String,
+    ttl: u64,
+    creation_path: String,
+}
+
+#[derive(Deserialize)]
+struct Wrapped {
+    wrap_info: WrapInfo,
+}
+
+#[derive(Deserialize)]
+struct WrappingLookup {
+    data: WrappingLookupData,
+}
+
+#[derive(Deserialize)]
+struct WrappingLookupData {
+    creation_path: String,
+}
+
+#[derive(Deserialize)]
+struct Unwrapped {
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+/// The wrapping TTL to use, checked against [`MAX_WRAP_TTL`]
+pub fn wrap_ttl(ttl: Option<Duration>) -> Result<Duration, VaultError> {
+    match ttl.unwrap_or(DEFAULT_WRAP_TTL) {
+        ttl if ttl.as_secs() == 0 || ttl > MAX_WRAP_TTL => Err(VaultError::InvalidScope(format!(
+            "Wrapping TTL must be between 1 and {} seconds",
+            MAX_WRAP_TTL.as_secs()
+        ))),
+        ttl => Ok(ttl),
+    }
+}
+
+/// Ask Vault to wrap the response to a request
+pub(crate) async fn wrapped_request(
+    request: reqwest::RequestBuilder,
+    ttl: Option<Duration>,
+) -> Result<WrappedResponse, VaultError> {
+    let ttl = wrap_ttl(ttl)?;
+    let wrapped: Wrapped =
+        vault_send(request.header("X-Vault-Wrap-TTL", format!("{}s", ttl.as_secs())))
+            .await?
+            .ok_or_else(|| {
+                VaultError::RequestError("Vault returned no wrapping token".to_string())
+            })?;
+
+    let wrap_info = wrapped.wrap_info;
+    Ok(WrappedResponse {
+        token: wrap_info.token,
+        accessor: wrap_info.accessor,
+        expires_at: VaultService::current_timestamp()? + wrap_info.ttl,
+        creation_path: wrap_info.creation_path,
+    })
+}
+
+impl VaultService {
+    /// Wrap a value the service holds, e.g. an API token it just created for someone
+    pub async fn wrap<T: Serialize>(
+        &self,
+        value: &T,
+        ttl: Option<Duration>,
+    ) -> Result<WrappedResponse, VaultError> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| VaultError::RequestError(format!("Value can't be wrapped: {}", e)))?;
+        // Vault only wraps maps
+        let body = match value {
+            serde_json::Value::Object(_) => value,
+            value => serde_json::json!({ "value": value }),
+        };
+        let body = &body;
+        self.with_admin_request(|client| async move {
+            let request = vault_request_builder(
+                &client,
+                reqwest::Method::POST,
+                "sys/wrapping/wrap",
+                Some(body),
+            )?;
+            wrapped_request(request, ttl).await
+        })
+        .await
+    }
+
+    /// Retrieve a wrapped value. Every wrapping token works exactly once.
+    ///
+    /// Tokens that were used already, expired or never existed are refused with
+    /// [`VaultError::WrappingTokenInvalid`]. Audit those, a token its recipient can't
+    /// unwrap may have been intercepted.
+    pub async fn unwrap(&self, token: &str) -> Result<UnwrappedValue, VaultError> {
+        let invalid = |e: VaultError| match e {
+            VaultError::ClientError(ClientError::APIError {
+                code: 400 | 403 | 404,
+                ..
+            }) => VaultError::WrappingTokenInvalid,
+            e => e,
+        };
+
+        // The wrapping token authenticates the requests, and is used up by the unwrap
+        let client = client_with_token(&self.config.address, token)?;
+        let lookup: WrappingLookup = vault_request(
+            &client,
+            reqwest::Method::POST,
+            "sys/wrapping/lookup",
+            Some(&serde_json::json!({ "token": token })),
+        )
+        .await
+        .map_err(invalid)?
+        .ok_or(VaultError::WrappingTokenInvalid)?;
+        let unwrapped: Unwrapped =
+            vault_request(&client, reqwest::Method::POST, "sys/wrapping/unwrap", None)
+                .await
+                .map_err(invalid)?
+                .ok_or(VaultError::WrappingTokenInvalid)?;
+
+        let creation_path = lookup.data.creation_path;
+        Ok(UnwrappedValue {
+            data: unwrapped_data(&creation_path, unwrapped.data),
+            creation_path,
+        })
+    }
+}
+
+// KV v2 reads hold the secret next to its metadata, callers only want the secret
+fn unwrapped_data(creation_path: &str, data: serde_json::Value) -> serde_json::Value {
+    match data {
+        serde_json::Value::Object(mut data)
+            if creation_path.contains("/data/") && data.contains_key("metadata") =>
+        {
+            data.remove("data").unwrap_or_default()
+        }
+        data => data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapped_secrets_unwrap_to_the_secret() {
+        let secret = serde_json::json!({ "api_key": "sk-secret" });
+        let read = serde_json::json!({
+            "data": secret,
+            "metadata": { "version": 3 },
+        });
+        assert_eq!(unwrapped_data("secret/data/teams/Team1/llm", read), secret);
+
+        // Values wrapped by the service come back as they were
+        let token = serde_json::json!({ "client_token": "hvs.abc", "data": "x" });
+        assert_eq!(unwrapped_data("sys/wrapping/wrap", token.clone()), token);
+
+        assert_eq!(wrap_ttl(None).unwrap(), DEFAULT_WRAP_TTL);
+        assert!(wrap_ttl(Some(Duration::ZERO)).is_err());
+        assert!(wrap_ttl(Some(MAX_WRAP_TTL + Duration::from_secs(1))).is_err());
+    }
+}
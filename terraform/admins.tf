@@ -99,6 +99,11 @@ path "secret/data/teams/*/metadata" {
 path "secret/data/institutions/*/metadata" {
   capabilities = ["read", "list"]
 }
+
+# Allow creating API tokens
+path "auth/token/create/global-spectator" {
+  capabilities = ["create", "read", "update"]
+}
 EOT
 }
 
@@ -153,4 +158,14 @@ resource "vault_token_auth_backend_role" "global_admin_role" {
   token_period     = 86400  # 24 hours
   token_explicit_max_ttl = 604800  # 7 days
   path_suffix      = "global-admin" 
-}
\ No newline at end of file
+}
+
+resource "vault_token_auth_backend_role" "global_spectator_role" {
+  role_name        = "global-spectator"
+  allowed_policies = [vault_policy.global_spectator_policy.name]
+  orphan           = true
+  renewable        = true
+  token_period     = 86400  # 24 hours
+  token_explicit_max_ttl = 604800  # 7 days
+  path_suffix      = "global-spectator"
+}
@@ -78,10 +78,27 @@ resource "keycloak_openid_client" "app_client" {
     format("%s/*", local.app_url),
   ]
   direct_access_grants_enabled = true 
+  # The application also manages the realm through the client's service account
+  service_accounts_enabled     = true
 
   login_theme = "keycloak"
 }
 
+data "keycloak_openid_client" "realm_management" {
+  realm_id  = keycloak_realm.realm.id
+  client_id = "realm-management"
+}
+
+# What the admin API calls of the application need, rotating the client secret included
+resource "keycloak_openid_client_service_account_role" "app_client_admin_roles" {
+  for_each = toset(["view-users", "query-users", "query-groups", "view-clients", "manage-clients"])
+
+  realm_id                = keycloak_realm.realm.id
+  service_account_user_id = keycloak_openid_client.app_client.service_account_user_id
+  client_id               = data.keycloak_openid_client.realm_management.id
+  role                    = each.key
+}
+
 # Map user roles to the app client
 resource "keycloak_openid_user_client_role_protocol_mapper" "app_user_client_role_mapper" {
   realm_id   = keycloak_realm.realm.id
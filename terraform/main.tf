@@ -10,6 +10,10 @@ terraform {
       source  = "mrparkers/keycloak"
       version = "4.4.0"
     }
+    random = {
+      source  = "hashicorp/random"
+      version = "3.6.3"
+    }
   }
 }
 
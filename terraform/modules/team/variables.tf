@@ -72,4 +72,15 @@ variable "client_id" {
   description = "OAuth client ID used for Vault authentication"
   type        = string
   default     = "rust-app"
-}
\ No newline at end of file
+}
+variable "database_backend" {
+  description = "Path of the Vault database secrets engine, empty to skip the team's database roles"
+  type        = string
+  default     = ""
+}
+
+variable "database_connection" {
+  description = "Name of the database connection in the secrets engine"
+  type        = string
+  default     = "aicldb"
+}
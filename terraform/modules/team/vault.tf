@@ -24,6 +24,11 @@ path "auth/token/create/team-${var.team_name}-member" {
 path "auth/token/create/team-${var.team_name}-member" {
   capabilities = ["create", "read", "update"]
 }
+
+# Allow requesting short-lived database credentials
+path "database/creds/team-${var.team_name}-member" {
+  capabilities = ["read"]
+}
 EOT
 }
 
@@ -51,6 +56,11 @@ path "auth/token/create/team-${var.team_name}-captain" {
 path "auth/token/create/team-${var.team_name}-captain" {
   capabilities = ["create", "read", "update"]
 }
+
+# Allow requesting short-lived database credentials
+path "database/creds/team-${var.team_name}-captain" {
+  capabilities = ["read"]
+}
 EOT
 }
 
@@ -135,4 +145,49 @@ resource "vault_kv_secret_v2" "team_secret" {
     description = var.team_description
     created_at  = timestamp()
   })
-}
\ No newline at end of file
+}
+# Database roles issuing Postgres users limited to the team's schema.
+# Members read and write data, captains also own the schema's tables.
+resource "vault_database_secret_backend_role" "team_member_database_role" {
+  count = var.database_backend != "" ? 1 : 0
+
+  backend     = var.database_backend
+  name        = "team-${var.team_name}-member"
+  db_name     = var.database_connection
+  default_ttl = 3600  # 1 hour
+  max_ttl     = 86400 # 24 hours
+
+  creation_statements = [
+    "CREATE SCHEMA IF NOT EXISTS \"team_${lower(var.team_name)}\";",
+    "CREATE ROLE \"{{name}}\" WITH LOGIN PASSWORD '{{password}}' VALID UNTIL '{{expiration}}';",
+    "GRANT USAGE ON SCHEMA \"team_${lower(var.team_name)}\" TO \"{{name}}\";",
+    "GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA \"team_${lower(var.team_name)}\" TO \"{{name}}\";",
+  ]
+  revocation_statements = [
+    "REVOKE ALL ON ALL TABLES IN SCHEMA \"team_${lower(var.team_name)}\" FROM \"{{name}}\";",
+    "REVOKE ALL ON SCHEMA \"team_${lower(var.team_name)}\" FROM \"{{name}}\";",
+    "DROP ROLE IF EXISTS \"{{name}}\";",
+  ]
+}
+
+resource "vault_database_secret_backend_role" "team_captain_database_role" {
+  count = var.database_backend != "" ? 1 : 0
+
+  backend     = var.database_backend
+  name        = "team-${var.team_name}-captain"
+  db_name     = var.database_connection
+  default_ttl = 3600  # 1 hour
+  max_ttl     = 86400 # 24 hours
+
+  creation_statements = [
+    "CREATE SCHEMA IF NOT EXISTS \"team_${lower(var.team_name)}\";",
+    "CREATE ROLE \"{{name}}\" WITH LOGIN PASSWORD '{{password}}' VALID UNTIL '{{expiration}}';",
+    "GRANT ALL ON SCHEMA \"team_${lower(var.team_name)}\" TO \"{{name}}\";",
+    "GRANT ALL ON ALL TABLES IN SCHEMA \"team_${lower(var.team_name)}\" TO \"{{name}}\";",
+  ]
+  revocation_statements = [
+    "REASSIGN OWNED BY \"{{name}}\" TO CURRENT_USER;",
+    "DROP OWNED BY \"{{name}}\";",
+    "DROP ROLE IF EXISTS \"{{name}}\";",
+  ]
+}
@@ -0,0 +1,43 @@
+# PKI engine issuing client certificates to team agents that can't hold bearer tokens
+resource "vault_mount" "pki" {
+  path                  = "pki"
+  type                  = "pki"
+  max_lease_ttl_seconds = 315360000 # 10 years, for the CA
+}
+
+resource "vault_pki_secret_backend_root_cert" "agents_ca" {
+  backend     = vault_mount.pki.path
+  type        = "internal"
+  common_name = "AICL Agents CA"
+  ttl         = "87600h"
+}
+
+resource "vault_pki_secret_backend_config_urls" "agents" {
+  backend                 = vault_mount.pki.path
+  issuing_certificates    = ["http://vault:8200/v1/pki/ca"]
+  crl_distribution_points = ["http://vault:8200/v1/pki/crl"]
+}
+
+# Rebuild the CRL often, so revoked agents are refused by the TLS terminator too
+resource "vault_pki_secret_backend_crl_config" "agents" {
+  backend      = vault_mount.pki.path
+  expiry       = "72h"
+  auto_rebuild = true
+  enable_delta = true
+}
+
+# Client-only certificates, the team and user are in the URI SANs
+resource "vault_pki_secret_backend_role" "team_agent" {
+  backend           = vault_mount.pki.path
+  name              = "team-agent"
+  ttl               = 86400  # 24 hours
+  max_ttl           = 259200 # 3 days
+  allow_any_name    = true
+  enforce_hostnames = false
+  allowed_uri_sans  = ["urn:aicl:team:*", "urn:aicl:user:*"]
+  server_flag       = false
+  client_flag       = true
+  ou                = ["aicl-agents"]
+  key_type          = "ec"
+  key_bits          = 256
+}
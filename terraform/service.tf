@@ -0,0 +1,132 @@
+# AppRole login for the application itself, so it doesn't need the root token
+resource "vault_auth_backend" "approle" {
+  type = "approle"
+}
+
+# What the application needs from Vault, nothing more
+resource "vault_policy" "app_service_policy" {
+  name = "app-service"
+
+  policy = <<EOT
+# Read the IdP configuration, session keys and token profiles
+path "secret/data/idp/*" {
+  capabilities = ["read"]
+}
+
+# Watch the IdP configuration and store rotated client secrets
+path "secret/data/idp/app-config" {
+  capabilities = ["create", "read", "update"]
+}
+
+path "secret/metadata/idp/app-config" {
+  capabilities = ["read"]
+}
+
+# Manage the API tokens issued to users
+path "auth/token/lookup-accessor" {
+  capabilities = ["update"]
+}
+
+path "auth/token/revoke-accessor" {
+  capabilities = ["update"]
+}
+
+path "auth/token/revoke" {
+  capabilities = ["update"]
+}
+
+# Encrypt sensitive database columns
+path "transit/encrypt/aicl-data" {
+  capabilities = ["update"]
+}
+
+path "transit/decrypt/aicl-data" {
+  capabilities = ["update"]
+}
+
+path "transit/rewrap/aicl-data" {
+  capabilities = ["update"]
+}
+
+# Revoke team database credentials on logout and team removal
+path "sys/leases/revoke" {
+  capabilities = ["update"]
+}
+
+# Issue, check and revoke team agent certificates
+path "pki/issue/team-agent" {
+  capabilities = ["update"]
+}
+
+path "pki/cert/*" {
+  capabilities = ["read"]
+}
+
+path "pki/revoke" {
+  capabilities = ["update"]
+}
+
+# Reconcile the team and institution policies and roles, see `reconcile_vault_roles`
+path "sys/policies/acl" {
+  capabilities = ["list"]
+}
+
+path "sys/policies/acl/team-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+path "sys/policies/acl/institution-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+path "auth/jwt/role" {
+  capabilities = ["list"]
+}
+
+path "auth/jwt/role/team-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+path "auth/jwt/role/institution-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+path "auth/token/roles" {
+  capabilities = ["list"]
+}
+
+path "auth/token/roles/team-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+path "auth/token/roles/institution-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+# Manage the teams' database roles when the database secrets engine is mounted
+path "database/config/aicldb" {
+  capabilities = ["read"]
+}
+
+path "database/roles" {
+  capabilities = ["list"]
+}
+
+path "database/roles/team-*" {
+  capabilities = ["create", "read", "update", "delete"]
+}
+
+# Wrap tokens handed to someone else, the recipient unwraps with the wrapping token itself
+path "sys/wrapping/wrap" {
+  capabilities = ["update"]
+}
+EOT
+}
+
+resource "vault_approle_auth_backend_role" "app_service" {
+  backend        = vault_auth_backend.approle.path
+  role_name      = "app-service"
+  token_policies = [vault_policy.app_service_policy.name]
+  token_ttl      = 3600  # 1 hour, renewed by the application
+  token_max_ttl  = 86400 # 24 hours, then the application logs in again
+}
@@ -0,0 +1,49 @@
+# Vault access for students who are not in a team yet
+
+# Vault policy for team-less students, only public secrets
+resource "vault_policy" "global_student_policy" {
+  name = "global-student"
+
+  policy = <<EOT
+# Allow reading public secrets
+path "secret/data/public/*" {
+  capabilities = ["read", "list"]
+}
+
+# Allow creating API tokens
+path "auth/token/create/global-student" {
+  capabilities = ["create", "read", "update"]
+}
+EOT
+}
+
+# Create JWT/OIDC role for team-less students
+resource "vault_jwt_auth_backend_role" "global_student_role" {
+  backend        = vault_jwt_auth_backend.keycloak.path
+  role_name      = "global-student"
+  role_type      = "jwt"
+  token_ttl      = 3600  # 1 hour
+  token_max_ttl  = 86400 # 24 hours
+  token_policies = [vault_policy.global_student_policy.name]
+
+  bound_audiences = [keycloak_openid_client.app_client.client_id]
+  user_claim      = "sub"
+  bound_claims    = {
+    "roles" = "student"
+  }
+
+  claim_mappings = {
+    preferred_username = "username"
+    email              = "email"
+  }
+}
+
+resource "vault_token_auth_backend_role" "global_student_role" {
+  role_name        = "global-student"
+  allowed_policies = [vault_policy.global_student_policy.name]
+  orphan           = true
+  renewable        = true
+  token_period     = 86400  # 24 hours
+  token_explicit_max_ttl = 604800  # 7 days
+  path_suffix      = "global-student"
+}
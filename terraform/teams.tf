@@ -21,6 +21,7 @@ module "team1" {
   create_team_kv_store    = true
   vault_auth_backend_path = vault_jwt_auth_backend.keycloak.path
   client_id               = keycloak_openid_client.app_client.client_id
+  database_backend        = vault_database_secrets_mount.database.path
   
   users = [
     {
@@ -66,6 +67,7 @@ module "team2" {
   create_team_kv_store    = true
   vault_auth_backend_path = vault_jwt_auth_backend.keycloak.path
   client_id               = keycloak_openid_client.app_client.client_id
+  database_backend        = vault_database_secrets_mount.database.path
   
   users = [
     {
@@ -111,6 +113,7 @@ module "team3" {
   create_team_kv_store    = true
   vault_auth_backend_path = vault_jwt_auth_backend.keycloak.path
   client_id               = keycloak_openid_client.app_client.client_id
+  database_backend        = vault_database_secrets_mount.database.path
   
   users = [
     {
@@ -0,0 +1,13 @@
+# Transit engine for encrypting sensitive database columns, keys never leave Vault
+resource "vault_mount" "transit" {
+  path = "transit"
+  type = "transit"
+}
+
+# Derived key, every team and institution encrypts under its own context
+resource "vault_transit_secret_backend_key" "data" {
+  backend          = vault_mount.transit.path
+  name             = "aicl-data"
+  derived          = true
+  deletion_allowed = false
+}
@@ -27,8 +27,26 @@ resource "vault_kv_secret_v2" "app_idp_config" {
     issuer        = format("%s/realms/%s", local.keycloak_url, keycloak_realm.realm.id)
     client_id     = keycloak_openid_client.app_client.client_id
     client_secret = keycloak_openid_client.app_client.client_secret
-    admin_username = local.keycloak_user,
-    admin_password = local.keycloak_password,
+    # No admin username and password, the application logs in with the client's service account
+  })
+}
+
+# Key ring for the sealed cookie session mode, the first key seals new cookies
+resource "random_bytes" "session_key" {
+  length = 32
+}
+
+resource "vault_kv_secret_v2" "session_keys" {
+  mount               = "secret"
+  name                = "idp/session-keys"
+  delete_all_versions = true
+  data_json = jsonencode({
+    keys = [
+      {
+        id  = "k1"
+        key = random_bytes.session_key.base64
+      }
+    ]
   })
 }
 
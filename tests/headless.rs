@@ -1,19 +1,14 @@
-use aicl_oidc::{errors::JsonErrorHandler, oidc::keycloak::{KeyCloakToken, TOKEN_KEY}, vault::ApiToken, AiclIdentifier, AiclIdentity, AppErrorHandler, OptionalIdentity};
-use axum::{response::IntoResponse, routing::get, Json, Router};
+use aicl_oidc::{axum::handlers, errors::JsonErrorHandler, oidc::session::SessionConfig, vault::ApiToken, AiclIdentifier, AiclIdentity, AiclRouterExt, AppErrorHandler, OptionalIdentity};
+use axum::{response::IntoResponse, routing::{get, post}, Router};
 use dotenvy::dotenv;
 use headless_chrome::Browser;
-use reqwest::{header::AUTHORIZATION, Client, StatusCode};
+use reqwest::{header::AUTHORIZATION, Client};
 use sqlx::postgres::PgPoolOptions;
 use tokio::{net::TcpListener, task::JoinHandle};
-use tower_sessions::{cookie::{time::Duration, SameSite}, Expiry, MemoryStore, Session, SessionManagerLayer};
 pub const APP_URL: &str = "http://localhost:4040";
 
 pub async fn run(identifier: AiclIdentifier) {
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false)
-        .with_same_site(SameSite::Lax)
-        .with_expiry(Expiry::OnInactivity(Duration::hours(1)));
+    let session_layer = SessionConfig::insecure().memory_layer();
     let error_handler = AppErrorHandler::new(JsonErrorHandler::default());
 
     let app = Router::new()
@@ -21,20 +16,17 @@ pub async fn run(identifier: AiclIdentifier) {
             Router::new()
                 .route("/foo", get(authenticated))
                 .route_service("/logout", identifier.logout_service())
-                .route("/token", get(create_token))
+                .route("/token", post(handlers::create_token))
                 .layer(identifier.login_layer())
                 .route("/bar", get(maybe_authenticated))
-                .layer(identifier.authenticate_layer())
-                .layer(session_layer)
-                .layer(identifier.identifier_layer())
-                .layer(error_handler.layer()),
+                .merge(identifier.auth_router())
+                .with_aicl_auth(&identifier, &error_handler, session_layer),
         )
         .nest(
             "/api",
             Router::new()
                 .route("/protected", get(token_authenticated))
-                .layer(identifier.api_token_layer())
-                .layer(error_handler.layer()),
+                .with_aicl_api_token_auth(&identifier, &error_handler),
         );
 
     let listener = TcpListener::bind("0.0.0.0:4040").await.unwrap();
@@ -55,46 +47,11 @@ async fn maybe_authenticated(OptionalIdentity(maybe_user): OptionalIdentity) ->
             format!("Hello {}! You are already logged in.", identity.username)
         }
         None => {
-            format!("Hello anon!")
+            "Hello anon!".to_string()
         }
     }
 }
 
-// Endpoint for creating API tokens
-async fn create_token(
-    identity: AiclIdentity,
-    session: Session,
-    identifier: AiclIdentifier,
-) -> Result<Json<ApiToken>, (StatusCode, String)> {
-    // Get the vault service from the identifier
-
-    // Get the OIDC token from the session
-    let oidc_token = session
-        .get::<KeyCloakToken>(TOKEN_KEY)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Session error: {}", e),
-            )
-        })?
-        .ok_or((StatusCode::UNAUTHORIZED, "No OIDC token found".to_string()))?;
-    tracing::info!("Creating API token for user {}", identity.username);
-    // Create a token
-    let token = identifier
-        .vault
-        .create_api_token_with_oidc(&identity, &oidc_token)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to create token: {}", e),
-            )
-        })?;
-    tracing::info!("Created API token for user {}", identity.username);
-    Ok(Json(token))
-}
-
 // Endpoint that requires token authentication
 async fn token_authenticated(identity: AiclIdentity) -> impl IntoResponse {
     format!("API access granted for {}!", identity.username)
@@ -205,9 +162,9 @@ async fn test_end_to_end_access() {
 
     tracing::info!("✅ OIDC session authentication test passed");
 
-    // Navigate to the authenticated endpoint
-    tab.navigate_to(&format!("{}/token", APP_URL))
-        .expect("Failed to navigate to /token");
+    // Log in again through the authenticated endpoint
+    tab.navigate_to(&format!("{}/foo", APP_URL))
+        .expect("Failed to navigate to /foo");
 
     // Wait for redirect to Keycloak login page
     tab.wait_for_element("#kc-form-login")
@@ -232,12 +189,19 @@ async fn test_end_to_end_access() {
     // Wait for redirect back to application
     tab.wait_until_navigated()
         .expect("Failed to navigate back to application");
+    tracing::info!("Successfully logged in with username: captain1");
+
+    // Tokens are only created by POST, with the session cookie
     let body = tab
-        .wait_for_element("body")
-        .expect("Element not found")
-        .get_inner_text()
-        .expect("Failed to get inner text");
-    tracing::info!(body, "Successfully logged in with username: captain1");
+        .evaluate(
+            "fetch('/token', { method: 'POST', headers: { 'Content-Type': 'application/json' }, body: '{}' })\
+                .then(response => response.text())",
+            true,
+        )
+        .expect("Failed to create a token")
+        .value
+        .and_then(|body| body.as_str().map(str::to_string))
+        .expect("Token response has no body");
     let token =
         serde_json::from_str::<ApiToken>(&body).expect("Failed to parse claims from response");
     // Parse the token from the response